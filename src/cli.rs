@@ -0,0 +1,333 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use crate::config::RateLimit;
+use crate::extractor::VolumeOrder;
+
+#[derive(Parser, Debug, Clone)]
+#[command(version, about = "爬取轻小说并生成EPUB")]
+pub struct Cli {
+    /// 网站配置名称, 未指定时交互式输入
+    #[arg(long)]
+    pub site: Option<String>,
+
+    /// 小说id, 与 --site 一起指定时只爬取一次后退出
+    #[arg(long)]
+    pub id: Option<String>,
+
+    /// 在终端界面中选择网站、输入小说id, 代替逐行输入; 需要以 tui 功能编译
+    #[cfg(feature = "tui")]
+    #[arg(long)]
+    pub tui: bool,
+
+    #[command(flatten)]
+    pub options: Options,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// 用一本已知的小说检查网站配置: 解析小说信息和第一章, 列出每个字段是否提取到内容, 不生成EPUB
+    TestSite {
+        /// 网站配置名称
+        site: String,
+        /// 小说id
+        id: String,
+    },
+}
+
+impl Cli {
+    pub fn is_interactive(&self) -> bool {
+        self.site.is_none() || self.id.is_none()
+    }
+}
+
+/// 未指定 --confirm-over 时, 章节数超过该值才需要确认
+pub const DEFAULT_CONFIRM_CHAPTERS: usize = 500;
+
+/// --disclaimer 未指定文本时使用的声明
+pub const DEFAULT_DISCLAIMER: &str = "本书《{title}》整理自 {source}
+版权归原作者及译者所有, 仅供个人学习交流使用, 请勿传播或用于商业用途
+生成日期: {date}";
+
+/// 影响单次运行的选项
+#[derive(Args, Debug, Clone, Default)]
+pub struct Options {
+    /// 只解析目录, 以JSON输出章节列表 (index, title, url, volume) 后退出, 不下载任何章节
+    #[arg(long)]
+    pub list_chapters_json: bool,
+
+    /// 为章节分配跨卷的全书连续编号: toc 只用于目录, heading 同时用于章节标题
+    #[arg(long, value_enum)]
+    pub global_numbering: Option<GlobalNumbering>,
+
+    /// 把小说信息页和章节页缓存到磁盘(默认 cache/ 目录), 再次请求时发送条件请求, 未修改则使用缓存
+    #[arg(long, value_name = "DIR", num_args = 0..=1, default_missing_value = "cache")]
+    pub cache: Option<PathBuf>,
+
+    /// 把抓取到的每个小说信息页和章节页原样保存为 <DIR>/<url哈希>.html, 用于排查解析问题
+    #[arg(long, value_name = "DIR")]
+    pub dump_html: Option<PathBuf>,
+
+    /// 为生成的EPUB追加标签(<dc:subject>), 可以重复指定, 与网站提取的标签合并
+    #[arg(long, visible_alias = "add-tag", value_name = "TAG")]
+    pub tag: Vec<String>,
+
+    /// 只下载上次运行之后新增的章节, 生成只包含新章节的EPUB;
+    /// 每次成功生成EPUB后都会在 state/ 目录记录已下载到的章节位置
+    #[arg(long)]
+    pub since_last: bool,
+
+    /// 出错或中断时保留临时文件夹, 在其中的 progress.json 记录目录和已完成的章节;
+    /// 再次使用 --resume 运行时只下载未完成的章节; 顺序抓取(next_url)的网站从上次停下的页面接着抓取
+    #[arg(long)]
+    pub resume: bool,
+
+    /// 从指定的章节开始下载: 数字表示按阅读顺序的位置(从1开始), 否则匹配标题包含该文本或url相同的章节
+    #[arg(long, value_name = "CHAPTER", conflicts_with = "since_last")]
+    pub from: Option<String>,
+
+    /// 下载到指定的章节(包含), 格式与 --from 相同
+    #[arg(long, value_name = "CHAPTER", conflicts_with = "since_last")]
+    pub to: Option<String>,
+
+    /// 卷的排列顺序: page 页面中的顺序, reverse 反转页面中的顺序, number 按卷标题中的数字排序;
+    /// 覆盖网站配置中的 volumes.order
+    #[arg(long, value_enum, value_name = "ORDER")]
+    pub sort_volumes: Option<VolumeOrder>,
+
+    /// 从卷和章节标题中去掉匹配该正则的部分, 例如 '^第\d+章\s*' 去掉每个章节标题的前缀;
+    /// 覆盖网站配置中的 book.strip_title
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    pub strip_chapter_title_prefix: Option<Regex>,
+
+    /// 输出格式: epub 打包为EPUB文件, dir 只保留生成的文件夹(OEBPS等), 不打包
+    #[arg(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+
+    /// 不下载任何图片(包括封面), 章节中的<img>会被去掉
+    #[arg(long)]
+    pub skip_images: bool,
+
+    /// 去掉番外、后记等额外章节(由网站配置的 special_selector/special_text 标记)
+    #[arg(long, conflicts_with = "group_extras")]
+    pub no_extras: bool,
+
+    /// 在目录末尾把额外章节单独归为"番外"一组
+    #[arg(long)]
+    pub group_extras: bool,
+
+    /// 只有一卷时去掉卷, 目录中直接列出章节, 也不生成卷封面页
+    #[arg(long)]
+    pub flatten_single_volume: bool,
+
+    /// 下载完成后比较章节正文, 去掉与前面章节内容相同的重复章节(转载、镜像章节等)
+    #[arg(long)]
+    pub dedup_chapters_by_content: bool,
+
+    /// 顺序抓取(网站配置了 next_url)时每个章节写入文件后只保留导航信息(序号、标题、文件名、插图),
+    /// 不再保留目录中的章节, 元数据只从导航列表生成; 用于章节很多的书
+    #[arg(long, conflicts_with = "dedup_chapters_by_content")]
+    pub stream_chapters: bool,
+
+    /// 章节插图按章节分别保存到 OEBPS/Images/<章节>/ 下, 默认全部放在 OEBPS/Images/;
+    /// 此时相同的图片只在同一章节内去重
+    #[arg(long)]
+    pub image_subdirs: bool,
+
+    /// 把下载的所有图片(包括封面)转换为同一种格式, 避免部分阅读器无法显示webp等格式;
+    /// 已经是该格式或无法解码的图片保持原样
+    #[arg(long, value_name = "FORMAT")]
+    pub normalize_images: Option<ImageFormat>,
+
+    /// 章节插图的预算(字节): 已写入的章节和图片超出后不再下载章节插图, 用占位文字代替;
+    /// 只省略插图, 章节正文仍然全部写入, 生成的EPUB可能超过这个大小
+    #[arg(long, value_name = "BYTES")]
+    pub image_budget_bytes: Option<u64>,
+
+    /// 没有封面(cover_from_first_image 也没有找到插图)时, 把网站图标放大后作为封面;
+    /// 图标很模糊, 只用于在书库中区分书籍
+    #[arg(long)]
+    pub favicon_cover: bool,
+
+    /// 生成 META-INF/com.apple.ibooks.display-options.xml, 让Apple Books使用书内指定的字体
+    #[arg(long)]
+    pub ibooks_specified_fonts: bool,
+
+    /// 在书末添加来源声明页, 可以指定文本, 其中的 {title}/{source}/{date} 替换为书名、小说页面url和生成日期,
+    /// 每行为一个段落; 只指定 --disclaimer 时使用默认文本
+    #[arg(long, value_name = "TEXT", num_args = 0..=1, default_missing_value = DEFAULT_DISCLAIMER)]
+    pub disclaimer: Option<String>,
+
+    /// 生成的EPUB版本: 2 兼容性最好; 3 额外生成 nav.xhtml(同时保留 toc.ncx), 章节使用HTML5的DOCTYPE
+    #[arg(long, value_enum, value_name = "VERSION", default_value_t)]
+    pub epub_version: EpubVersion,
+
+    /// 去掉生成的XHTML、OPF、NCX中用于缩进的空白, 减小EPUB的体积; 默认保留缩进便于查看
+    #[arg(long)]
+    pub minify: bool,
+
+    /// 重新缩进生成的XHTML、OPF、NCX, 每个块级元素各占一行, 便于查看和比较
+    #[arg(long, conflicts_with = "minify")]
+    pub pretty: bool,
+
+    /// 生成固定版式的EPUB3, 适合以整页插图为主的小说: 有插图的章节每张图片一页, 页面大小与图片相同,
+    /// 章节中的文字不保留; 没有插图的章节仍按普通章节排版; 同时生成Apple Books的display-options.xml
+    #[arg(long)]
+    pub fixed_layout: bool,
+
+    /// 兼容模式, 用于很旧或功能很少的阅读器: 生成EPUB2和XHTML 1.1, 章节只保留标题、纯文本段落和插图,
+    /// 去掉样式、class等属性, OPF只包含最基本的元数据
+    #[arg(long, conflicts_with_all = ["epub_version", "fixed_layout", "ibooks_specified_fonts"])]
+    pub compat_plain: bool,
+
+    /// OPF的 dc:identifier 和NCX的 dtb:uid 的格式, 同一本小说每次生成的值都相同
+    #[arg(long, value_enum, default_value_t)]
+    pub identifier: IdentifierFormat,
+
+    /// 不显示下载进度条, 默认只输出警告和错误日志; 输出不是终端时也不显示进度条
+    #[arg(long, short)]
+    pub quiet: bool,
+
+    /// 不询问直接下载; 默认在终端中运行且要下载的章节数超过 --confirm-over 时, 先显示章节数并等待确认
+    #[arg(long, short)]
+    pub yes: bool,
+
+    /// 下载前需要确认的章节数, 默认为 500
+    #[arg(long, value_name = "N")]
+    pub confirm_over: Option<usize>,
+
+    /// EPUB文件(或 --format dir 的文件夹)的输出目录, 默认为当前目录
+    #[arg(long, value_name = "DIR")]
+    pub output_dir: Option<PathBuf>,
+
+    /// 每卷同时下载的章节数, 多卷仍会同时下载; 默认与 --concurrency 或网站配置的 concurrency_limit 相同
+    #[arg(long, value_name = "N")]
+    pub workers_per_volume: Option<usize>,
+
+    /// 最大并行请求数, 覆盖网站配置中的 concurrency_limit
+    #[arg(long, value_name = "N")]
+    pub concurrency: Option<usize>,
+
+    /// 请求频率, 格式为 N/SECS, 如 5/1s 表示每秒最多5次请求; 覆盖网站配置中的 rate_limit
+    #[arg(long, value_name = "N/SECS")]
+    pub rate: Option<RateLimit>,
+
+    /// 最大重试次数, 覆盖网站配置中的 retry.max
+    #[arg(long, value_name = "N")]
+    pub max_retries: Option<u32>,
+
+    /// 单个请求的超时秒数, 默认不限制
+    #[arg(long, value_name = "SECS")]
+    pub timeout: Option<u64>,
+
+    /// 使用的代理, 可以指定多次; 指定后代替网站配置中的 proxies
+    #[arg(long, value_name = "URL")]
+    pub proxy: Vec<String>,
+
+    /// 本次运行额外发送的cookie, 格式为 name=value, 可以指定多次; 与 config.toml 中的同名cookie冲突时覆盖
+    #[arg(long, value_name = "NAME=VALUE")]
+    pub cookie: Vec<String>,
+
+    /// 本次运行额外发送的请求头, 格式为 "Name: Value", 可以指定多次; 覆盖同名的默认请求头
+    #[arg(long, value_name = "NAME: VALUE")]
+    pub header: Vec<String>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    /// 打包为EPUB文件并删除临时文件夹
+    #[default]
+    Epub,
+    /// 保留完整的文件夹, 不打包
+    Dir,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    /// 体积较小, 透明的部分填充为白色
+    Jpeg,
+    /// 无损, 保留透明
+    Png,
+}
+
+impl ImageFormat {
+    /// 转换后图片文件的扩展名
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum GlobalNumbering {
+    /// 只在目录(toc.ncx)中显示编号
+    Toc,
+    /// 目录和章节的<h1>都显示编号
+    Heading,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum EpubVersion {
+    /// EPUB 2.0.1, 目录只有 toc.ncx
+    #[default]
+    #[value(name = "2")]
+    V2,
+    /// EPUB 3, 目录为 nav.xhtml, 同时保留 toc.ncx 供旧阅读器使用
+    #[value(name = "3")]
+    V3,
+}
+
+impl EpubVersion {
+    /// 写入content.opf的 package version
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EpubVersion::V2 => "2.0",
+            EpubVersion::V3 => "3.0",
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum IdentifierFormat {
+    /// 直接使用小说id, 如 docln_12345
+    #[default]
+    Raw,
+    /// 由小说id的哈希生成的UUID, 如 urn:uuid:xxxxxxxx-xxxx-8xxx-xxxx-xxxxxxxxxxxx
+    Uuid,
+    /// urn:docln:<小说id>
+    Urn,
+}
+
+impl IdentifierFormat {
+    pub fn identifier(self, id: &str) -> String {
+        match self {
+            IdentifierFormat::Raw => id.to_string(),
+            IdentifierFormat::Uuid => {
+                // 取哈希的前16字节, 按RFC 9562设置为version 8(自定义)的UUID
+                let hash = Sha256::digest(id.as_bytes());
+                let mut bytes = [0u8; 16];
+                bytes.copy_from_slice(&hash[..16]);
+                bytes[6] = (bytes[6] & 0x0f) | 0x80;
+                bytes[8] = (bytes[8] & 0x3f) | 0x80;
+                let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                format!(
+                    "urn:uuid:{}-{}-{}-{}-{}",
+                    &hex[..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..]
+                )
+            }
+            IdentifierFormat::Urn => format!("urn:docln:{}", id),
+        }
+    }
+}
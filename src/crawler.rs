@@ -1,61 +1,227 @@
+pub mod builder;
+pub mod cache;
+pub mod check;
 pub mod downloader;
 pub mod parser;
 pub mod processor;
+pub mod proxy;
+pub mod resume;
+pub mod retry;
+pub mod state;
 pub mod task;
 
+use std::collections::VecDeque;
+use std::io::IsTerminal;
 use std::mem::take;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::Result;
+use serde::Serialize;
 use tokio::fs;
-use tracing::{error, info, instrument};
+use tokio::sync::Semaphore;
+use tracing::{error, info, instrument, warn};
 
 use crate::{
-    config::get_site_config,
-    epub::{self, Chapter, Epub, VolOrChap, Volume},
+    cli::{DEFAULT_CONFIRM_CHAPTERS, Options, OutputFormat},
+    config::RateLimit,
+    epub::{self, Chapter, ChapterRange, Epub, NavEntry, NavList, NavVolume, VolOrChap, Volume},
+    get_user_input,
 };
+pub use builder::DoclnCrawlerBuilder;
 use downloader::Downloader;
-use parser::Parser;
+use parser::{Parser, SoftError};
+use processor::OMITTED_IMAGE_SRC;
+use resume::ResumeState;
 pub use task::TaskManager;
 
 type Processor = Arc<processor::Processor>;
 type ChapterTaskManager = TaskManager<Chapter>;
-type VolumeTaskManager = TaskManager<(Volume, ChapterTaskManager)>;
-
-// static MAX_RETRIES: u32 = 3;
+type VolumeTaskManager = TaskManager<Volume>;
 
 pub struct DoclnCrawler {
+    site: String,
+    id: String,
     parser: Parser,
     downloader: Downloader,
+    options: Arc<Options>,
+}
+
+/// 目录中的一个章节, 用于输出JSON供外部工具使用
+#[derive(Serialize)]
+struct TocEntry<'a> {
+    index: usize,
+    title: &'a str,
+    url: &'a str,
+    volume: Option<usize>,
+    volume_title: Option<&'a str>,
 }
 
 impl DoclnCrawler {
-    pub fn new(url: String, site_name: &str) -> Self {
-        Self {
-            parser: Parser::new(site_name),
-            downloader: Downloader::new(site_name, url),
+    pub fn new(url: String, id: String, site_name: &str, options: Arc<Options>) -> Result<Self> {
+        Self::builder()
+            .site(site_name)
+            .id(id)
+            .url(url)
+            .options((*options).clone())
+            .build()
+    }
+
+    pub fn builder() -> DoclnCrawlerBuilder {
+        DoclnCrawlerBuilder::new()
+    }
+
+    /// 网站名称和小说id组成的id, 用于EPUB的文件名和下载记录
+    fn novel_id(&self) -> String {
+        format!("{}_{}", self.site, self.id)
+    }
+
+    /// 只解析目录, 返回章节列表的JSON
+    #[instrument(skip_all, fields(site = %self.site, id = %self.id))]
+    pub async fn list_chapters_json(&self) -> Result<String> {
+        let id = self.novel_id();
+        let mut downloader = self.downloader.clone();
+        let novel_html = downloader.novel_info().await?;
+        let chapter_list_html = downloader.chapter_list().await?;
+        let epub = self
+            .parser
+            .novel_info(&novel_html, chapter_list_html.as_deref(), id)?;
+
+        let mut entries = Vec::new();
+        match &epub.children {
+            VolOrChap::Volumes(volumes) => {
+                for volume in volumes {
+                    for chapter in &volume.chapters {
+                        entries.push(TocEntry {
+                            index: chapter.index,
+                            title: &chapter.title,
+                            url: &chapter.url,
+                            volume: Some(volume.index),
+                            volume_title: Some(&volume.title),
+                        });
+                    }
+                }
+            }
+            VolOrChap::Chapters(chapters) => {
+                for chapter in chapters {
+                    entries.push(TocEntry {
+                        index: chapter.index,
+                        title: &chapter.title,
+                        url: &chapter.url,
+                        volume: None,
+                        volume_title: None,
+                    });
+                }
+            }
         }
+
+        Ok(serde_json::to_string_pretty(&entries)?)
     }
 
-    pub async fn crawl(&self, id: String, site_name: String) -> Result<()> {
-        let id = format!("{}_{}", site_name, id);
+    pub async fn crawl(&self) -> Result<()> {
+        let id = self.novel_id();
 
-        let content_extractor = &get_site_config(site_name.as_str())?
+        let site_config = self.downloader.config();
+        let content_extractor = &site_config
             .get_chapter_config()
             .expect("没有章节配置")
             .content;
 
-        if let Some(_) = &content_extractor.next_url {
-            let epub =
-                Self::epub_sequential(id, self.downloader.clone(), self.parser.clone()).await?;
-            let _ = epub.generate().await?;
+        let since = if self.options.since_last {
+            let mark = state::load_mark(&id).await;
+            if mark.is_none() {
+                warn!("没有找到 {} 的下载记录, 将下载全部章节", id);
+            }
+            mark
+        } else {
+            None
+        };
+
+        let mut epub = if let Some(_) = &content_extractor.next_url {
+            Self::epub_sequential(
+                id.clone(),
+                self.downloader.clone(),
+                self.parser.clone(),
+                self.options.clone(),
+                since,
+            )
+            .await?
         } else {
-            let (mut epub, children_tasks) =
-                Self::epub_task(id, self.downloader.clone(), self.parser.clone()).await?;
+            if self.options.stream_chapters {
+                warn!("--stream-chapters 只用于顺序抓取, 并行下载时忽略");
+            }
+            let (mut epub, children_tasks) = Self::epub_task(
+                id.clone(),
+                self.downloader.clone(),
+                self.parser.clone(),
+                self.options.clone(),
+                since,
+            )
+            .await?;
 
             Self::set_epub_children(&mut epub, children_tasks).await?;
-            let _ = epub.generate().await?;
+            epub
+        };
+
+        let new_chapters = epub.chapter_count();
+        // 下载记录按网站目录中的章节计数, 包括去掉的额外章节和重复章节;
+        // 只下载部分章节时不更新
+        let update_mark = self.options.from.is_none() && self.options.to.is_none();
+        if self.options.dedup_chapters_by_content {
+            let duplicates = epub.dedup_by_content().await?;
+            info!("合并 {} 个内容重复的章节", duplicates.len());
+        }
+
+        if site_config.detect_lang {
+            epub.detect_lang().await?;
+        }
+
+        if epub.cover.is_none() && site_config.cover_from_first_image && !self.options.skip_images {
+            epub.cover_from_first_image().await?;
+        }
+
+        if epub.cover.is_none()
+            && let Some(favicon) = take(&mut epub.favicon)
+            && !self.options.skip_images
+        {
+            let mut downloader = self.downloader.clone();
+            match downloader.image(&favicon).await {
+                // 没有图标时服务器可能返回空白或HTML的错误页
+                Ok((icon, extension))
+                    if !icon.is_empty() && (extension == "svg" || !icon.starts_with(b"<")) =>
+                {
+                    epub.cover_from_favicon(&icon, &extension).await?
+                }
+                Ok(_) => warn!("网站图标不是图片, 不设置封面: {}", favicon),
+                Err(e) => warn!("网站图标下载失败, 不设置封面: {:#}", e),
+            }
+        }
+
+        if since.is_some() && new_chapters == 0 {
+            info!("{} 没有新章节", id);
+            // 不生成EPUB, 也不需要保留继续下载的文件夹
+            epub.keep_dir = false;
+            if update_mark {
+                state::store_mark(&id, epub.toc_chapters).await;
+            }
+            return Ok(());
+        }
+        match self.options.format {
+            OutputFormat::Epub => {
+                let _ = epub.generate().await?;
+            }
+            OutputFormat::Dir => {
+                let dir = epub.generate_dir().await?;
+                println!("{}", dir.display());
+            }
+        }
+        // 生成成功后才删除继续下载的进度, 之前的步骤出错时仍然可以使用 --resume 继续
+        if self.options.resume {
+            ResumeState::remove(&epub.epub_dir).await;
+            epub.keep_dir = self.options.format == OutputFormat::Dir;
+        }
+        if update_mark {
+            state::store_mark(&id, epub.toc_chapters).await;
         }
 
         Ok(())
@@ -82,13 +248,7 @@ impl DoclnCrawler {
         let mut volumes = Vec::new();
         info!("正在整合小说的卷信息");
         let results = volume_tasks.wait().await?;
-        for (mut volume, chapter_tasks) in results {
-            info!("正在整合第 {} 卷", volume.index);
-            let chapters = Self::sort_chapters(chapter_tasks).await?;
-            volume.chapters = chapters;
-            info!("完成整合第 {} 卷", volume.index);
-            volumes.push(volume);
-        }
+        volumes.extend(results);
         info!("正在排序小说的卷信息");
         volumes.sort_by_key(|v| v.index);
         info!("完成整合小说的卷信息");
@@ -106,13 +266,29 @@ impl DoclnCrawler {
         processor: &Processor,
         downloader: &Downloader,
         parser: &Parser,
+        workers_per_volume: usize,
     ) -> VolumeTaskManager {
         let mut task_manager = TaskManager::new();
+        // 卷任务在所有章节完成后才释放许可, 限制同时下载的卷数
+        let semaphore = Arc::new(Semaphore::new(
+            downloader.config().volume_concurrency.max(1),
+        ));
         for volume in volumes {
             let processor = processor.clone();
             let downloader = downloader.clone();
+            let parser = parser.clone();
+            let semaphore = semaphore.clone();
 
-            let volume_future = Self::volume_task(volume, processor, downloader, *parser);
+            let volume_future = async move {
+                let _permit = semaphore.acquire_owned().await?;
+                let (mut volume, chapter_tasks) =
+                    Self::volume_task(volume, processor, downloader, parser, workers_per_volume)
+                        .await?;
+                info!("正在整合第 {} 卷", volume.index);
+                volume.chapters = Self::sort_chapters(chapter_tasks).await?;
+                info!("完成整合第 {} 卷", volume.index);
+                Ok(volume)
+            };
             task_manager.spawn(volume_future);
         }
         task_manager
@@ -120,245 +296,670 @@ impl DoclnCrawler {
 
     fn chapter_tasks(
         chapters: Vec<Chapter>,
+        mut task_manager: ChapterTaskManager,
         processor: &Processor,
         downloader: &Downloader,
         parser: &Parser,
     ) -> ChapterTaskManager {
-        let mut task_manager = TaskManager::new();
         for chapter in chapters {
             let downloader = downloader.clone();
             let processor = processor.clone();
-            let chapter_future = Self::chapter_task(chapter, processor, downloader, *parser);
+            let chapter_future = Self::chapter_task(chapter, processor, downloader, parser.clone());
             task_manager.spawn(chapter_future);
         }
         task_manager
     }
 
-    #[instrument(skip_all)]
-    pub async fn epub_task(
+    /// 增量下载或只下载部分章节时在文件名中标明章节范围, 避免覆盖完整的EPUB
+    fn epub_name(novel_id: &str, since: Option<usize>, range: Option<&ChapterRange>) -> String {
+        match (since, range) {
+            (Some(since), _) => format!("{}_from_{}", novel_id, since + 1),
+            (None, Some(range)) => format!("{}_{}-{}", novel_id, range.start, range.end),
+            (None, None) => novel_id.to_string(),
+        }
+    }
+
+    /// 未指定输出目录时在当前目录生成
+    fn epub_dir(options: &Options, epub_name: &str) -> PathBuf {
+        match &options.output_dir {
+            Some(output_dir) => output_dir.join(epub_name),
+            None => PathBuf::from(epub_name),
+        }
+    }
+
+    /// --format dir 生成的文件夹会保留, 重新下载同一本小说时先删除上次生成的文件夹;
+    /// 不是EPUB文件夹(没有mimetype文件)时不删除, 直接报错
+    async fn remove_previous_dir(options: &Options, epub_dir: &Path) -> Result<()> {
+        if options.format != OutputFormat::Dir || !fs::try_exists(epub_dir).await? {
+            return Ok(());
+        }
+        if !fs::try_exists(epub_dir.join("mimetype")).await? {
+            anyhow::bail!("输出文件夹已存在且不是EPUB文件夹: {}", epub_dir.display());
+        }
+        info!("删除上次生成的EPUB文件夹: {}", epub_dir.display());
+        fs::remove_dir_all(epub_dir).await?;
+        Ok(())
+    }
+
+    /// 章节数很多时显示章节数和预计的最短时间, 在终端中等待用户确认, 防止输错id下载巨大的小说;
+    /// 指定 --yes 或不在终端中运行时直接继续
+    fn confirm_download(epub: &Epub, options: &Options, rate_limit: &RateLimit) -> Result<()> {
+        let chapters = epub.chapter_count();
+        let threshold = options.confirm_over.unwrap_or(DEFAULT_CONFIRM_CHAPTERS);
+        if options.yes || chapters <= threshold || !std::io::stdin().is_terminal() {
+            return Ok(());
+        }
+
+        // 每个章节至少一个请求, 按网站的限速估算
+        let secs = chapters as u64 * rate_limit.secs / rate_limit.num.max(1);
+        let estimate = match secs {
+            0..60 => format!("{} 秒", secs),
+            60..3600 => format!("{} 分钟", secs.div_ceil(60)),
+            _ => format!("{:.1} 小时", secs as f64 / 3600.0),
+        };
+        let volumes = match &epub.children {
+            VolOrChap::Volumes(volumes) => format!("{} 卷, ", volumes.len()),
+            VolOrChap::Chapters(_) => String::new(),
+        };
+        println!(
+            "《{}》共 {}{} 个章节, 按网站限速至少需要 {}",
+            epub.title, volumes, chapters, estimate
+        );
+        let choice = get_user_input("是否开始下载? (y/n)")?;
+        if !choice.eq_ignore_ascii_case("y") {
+            anyhow::bail!("已取消下载 {}", epub.id);
+        }
+        Ok(())
+    }
+
+    /// 按 --since-last、--no-extras、--flatten-single-volume、--global-numbering、--from/--to 的顺序
+    /// 筛选章节并编号; keep_extras 为true时保留额外章节(顺序抓取需要经过它们的页面), 只是不编号
+    fn select_chapters(
+        epub: &mut Epub,
+        options: &Options,
+        since: Option<usize>,
+        range: Option<&ChapterRange>,
+        keep_extras: bool,
+    ) {
+        // 下载记录按网站目录中的位置计数, 在去掉额外章节之前跳过
+        let skipped = match since {
+            Some(since) => {
+                let skipped = epub.skip_chapters(since);
+                info!(
+                    "跳过已下载的 {} 个章节, 新章节 {} 个",
+                    since,
+                    epub.chapter_count()
+                );
+                skipped
+            }
+            None => Vec::new(),
+        };
+        if options.no_extras && !keep_extras {
+            let extras = epub.remove_extras();
+            info!("去掉 {} 个额外章节", extras.len());
+        }
+        if options.flatten_single_volume && epub.flatten_single_volume() {
+            info!("只有一卷, 章节直接作为书的章节");
+        }
+        if options.global_numbering.is_some() {
+            // 按网站目录编号, 跳过的章节也占用编号
+            let start = skipped
+                .iter()
+                .filter(|chapter| !(options.no_extras && chapter.special))
+                .count();
+            epub.number_chapters(start, options.no_extras);
+        }
+        if let Some(range) = range {
+            epub.retain_range(range);
+            info!(
+                "只下载第 {} 到第 {} 个章节, 共 {} 个",
+                range.start,
+                range.end,
+                epub.chapter_count()
+            );
+        }
+    }
+
+    /// 解析小说信息并创建EPUB文件夹, 并行和顺序抓取共用; 继续下载时恢复上次的进度,
+    /// 否则筛选章节、等待确认并下载封面. sequential 为true时保留额外章节, 并检查目录是否缺少前面的章节
+    async fn prepare_epub(
         novel_id: String,
-        mut downloader: Downloader,
-        parser: Parser,
-    ) -> Result<(Epub, VolOrChapTasks)> {
+        downloader: &mut Downloader,
+        parser: &Parser,
+        options: &Arc<Options>,
+        since: Option<usize>,
+        sequential: bool,
+    ) -> Result<(Epub, Processor)> {
         info!("正在爬取 ID为 {} 的小说...", novel_id);
-        let epub_name = format!("{}", novel_id);
-        let epub_dir = PathBuf::from(&epub_name);
+        let novel_html = downloader.novel_info().await?;
+        let chapter_list_html = downloader.chapter_list().await?;
+        let mut epub = parser.novel_info(&novel_html, chapter_list_html.as_deref(), novel_id)?;
+        let range = epub.chapter_range(options.from.as_deref(), options.to.as_deref())?;
+
+        let epub_name = Self::epub_name(&epub.id, since, range.as_ref());
+        let epub_dir = Self::epub_dir(options, &epub_name);
         let meta_dir = epub_dir.join("META-INF");
         let oebps_dir = epub_dir.join("OEBPS");
         let image_dir = oebps_dir.join("Images");
         let text_dir = oebps_dir.join("Text");
 
-        fs::create_dir(&epub_dir).await?;
-        fs::create_dir(&meta_dir).await?;
-        fs::create_dir(&oebps_dir).await?;
-        fs::create_dir(&image_dir).await?;
-        fs::create_dir(&text_dir).await?;
+        let resume = match options.resume {
+            true => Some(Arc::new(ResumeState::open(&epub_dir).await)),
+            false => None,
+        };
+        let resumed = match &resume {
+            Some(resume) => resume.is_resumed().await,
+            None => false,
+        };
 
-        let processor = Arc::new(processor::Processor::new(
-            image_dir.clone(),
-            text_dir.clone(),
-        ));
-        let novel_html = downloader.novel_info().await?;
-        let mut epub = parser.novel_info(&novel_html, novel_id)?;
-        if let Some(cover_url) = take(&mut epub.cover) {
-            let (cover_bytes, extension) = downloader.image(&cover_url).await?;
-            let cover_name = processor.write_image(cover_bytes, extension).await?;
-            epub.cover = Some(cover_name);
-        }
-
-        let children_tasks =
-            match take(&mut epub.children) {
-                epub::VolOrChap::Volumes(volumes) => VolOrChapTasks::Volume(Self::volume_tasks(
-                    volumes,
-                    &processor,
-                    &downloader,
-                    &parser,
-                )),
-                epub::VolOrChap::Chapters(chapters) => VolOrChapTasks::Chapter(
-                    Self::chapter_tasks(chapters, &processor, &downloader, &parser),
-                ),
-            };
+        // 解析成功后再创建文件夹, 之后出错时由Epub的drop清理
+        if let Some(output_dir) = &options.output_dir {
+            fs::create_dir_all(output_dir).await?;
+        }
+        if resumed {
+            // 继续下载时文件夹已经存在
+            for dir in [&meta_dir, &oebps_dir, &image_dir, &text_dir] {
+                fs::create_dir_all(dir).await?;
+            }
+            ResumeState::remove_temp_files(&image_dir).await;
+        } else {
+            Self::remove_previous_dir(options, &epub_dir).await?;
+            fs::create_dir(&epub_dir).await?;
+            fs::create_dir(&meta_dir).await?;
+            fs::create_dir(&oebps_dir).await?;
+            fs::create_dir(&image_dir).await?;
+            fs::create_dir(&text_dir).await?;
+        }
 
         epub.epub_dir = epub_dir;
         epub.meta_dir = meta_dir;
         epub.oebps_dir = oebps_dir;
         epub.image_dir = image_dir;
         epub.text_dir = text_dir;
+        epub.fixed_layout = options.fixed_layout;
+        epub.compat_plain = options.compat_plain;
+        epub.set_version(options.epub_version);
+
+        let processor = Arc::new(
+            processor::Processor::new(
+                epub.image_dir.clone(),
+                epub.text_dir.clone(),
+                options.clone(),
+                epub.doctype,
+            )
+            .with_resume(resume.clone()),
+        );
+        // 出错时保留文件夹, 下次使用 --resume 继续下载
+        epub.keep_dir = options.resume;
+        epub.add_tags(&options.tag);
+        epub.group_extras = options.group_extras;
+        epub.ibooks_specified_fonts = options.ibooks_specified_fonts;
+        epub.minify = options.minify;
+        epub.pretty = options.pretty;
+        epub.source = downloader.url.to_string();
+        epub.identifier = options.identifier.identifier(&epub.id);
+        epub.disclaimer = options.disclaimer.clone();
+        if options.favicon_cover {
+            epub.favicon = Some(
+                parser
+                    .icon_url(&novel_html)
+                    .unwrap_or_else(|| "/favicon.ico".to_string()),
+            );
+        }
+        if let Some(resume) = &resume
+            && resumed
+        {
+            let done = resume.restore(&mut epub).await;
+            info!(
+                "从上次中断的位置继续下载, 已完成 {} / {} 个章节",
+                done,
+                epub.chapter_count()
+            );
+        } else {
+            Self::select_chapters(&mut epub, options, since, range.as_ref(), sequential);
+            if let Err(e) = Self::confirm_download(&epub, options, &downloader.rate_limit()) {
+                // 取消时还没有下载任何内容, 不需要保留文件夹
+                epub.keep_dir = false;
+                return Err(e);
+            }
+            Self::download_covers(&mut epub, downloader, &processor).await?;
+            if let Some(resume) = &resume {
+                resume.start(&epub).await?;
+            }
+
+            // 顺序抓取时检查目录是否缺少前面的章节; 增量下载本来就从中间开始, 不需要检查
+            if sequential && since.is_none() {
+                Self::check_prev_page(&epub, downloader).await?;
+            }
+        }
+        processor.start_progress(&epub.id, epub.chapter_count());
+
+        Ok((epub, processor))
+    }
+
+    #[instrument(skip_all, fields(novel_id = %novel_id))]
+    pub async fn epub_task(
+        novel_id: String,
+        mut downloader: Downloader,
+        parser: Parser,
+        options: Arc<Options>,
+        since: Option<usize>,
+    ) -> Result<(Epub, VolOrChapTasks)> {
+        let (mut epub, processor) =
+            Self::prepare_epub(novel_id, &mut downloader, &parser, &options, since, false).await?;
+
+        // 未指定时与全局的并行请求数相同, 即不单独限制每卷
+        let workers_per_volume = options
+            .workers_per_volume
+            .unwrap_or(downloader.concurrency_limit());
+        let children_tasks = match take(&mut epub.children) {
+            epub::VolOrChap::Volumes(volumes) => VolOrChapTasks::Volume(Self::volume_tasks(
+                volumes,
+                &processor,
+                &downloader,
+                &parser,
+                workers_per_volume,
+            )),
+            epub::VolOrChap::Chapters(chapters) => VolOrChapTasks::Chapter(Self::chapter_tasks(
+                chapters,
+                TaskManager::new(),
+                &processor,
+                &downloader,
+                &parser,
+            )),
+        };
 
         info!("完成爬取 ID为 {} 的小说", epub.id);
         Ok((epub, children_tasks))
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(volume = volume.index))]
     async fn volume_task(
         mut volume: Volume,
         processor: Processor,
         mut downloader: Downloader,
         parser: Parser,
+        workers_per_volume: usize,
     ) -> Result<(Volume, ChapterTaskManager)> {
         info!("正在处理第 {} 卷", volume.index);
+        if processor.skip_images() {
+            volume.cover = None;
+        }
         if let Some(volume_cover_url) = &volume.cover {
             let (cover_bytes, extension) = downloader.image(volume_cover_url).await?;
             let cover_name = processor.write_image(cover_bytes, extension).await?;
             volume.cover = Some(cover_name);
         }
 
-        let cover_html = volume.cover_html();
+        let cover_html = volume.cover_html(processor.doctype());
         processor
             .write_html(cover_html, &volume.cover_chapter)
             .await?;
-        let chapter_tasks =
-            Self::chapter_tasks(take(&mut volume.chapters), &processor, &downloader, &parser);
+        let chapter_tasks = Self::chapter_tasks(
+            take(&mut volume.chapters),
+            TaskManager::with_limit(workers_per_volume),
+            &processor,
+            &downloader,
+            &parser,
+        );
         info!("完成处理第 {} 卷", volume.index);
         Ok((volume, chapter_tasks))
     }
 
-    #[instrument(skip_all)]
+    #[instrument(
+        skip_all,
+        fields(chapter = chapter.index, file = %chapter.filename, url = %chapter.url)
+    )]
     async fn chapter_task(
         mut chapter: Chapter,
         processor: Processor,
         mut downloader: Downloader,
         parser: Parser,
     ) -> Result<Chapter> {
+        if let Some(images) = processor.completed(&chapter).await {
+            info!(
+                "第 {} 章已在上次运行中完成, 跳过: {}",
+                chapter.index, chapter.title
+            );
+            chapter.images = images;
+            return Ok(chapter);
+        }
         info!("正在处理第 {} 章: {}", chapter.index, chapter.title);
-        let chapter_html = downloader.chapter(&chapter.url).await?;
-        let mut content = parser.chapter_content(chapter_html)?;
-        let srcs = parser.chapter_srcs(&content);
+        let retry = downloader.retry();
+        let mut retries = 0;
+        let mut content = loop {
+            let chapter_html = downloader.chapter(&chapter.url).await?;
+            match parser.chapter_content(chapter_html, &chapter.title) {
+                Err(e) if e.is::<SoftError>() && retries < retry.max => {
+                    let delay = retry.delay(retries);
+                    warn!("{}, {:?} 后重试: {}", e, delay, chapter.url);
+                    tokio::time::sleep(delay).await;
+                    retries += 1;
+                }
+                result => break result?,
+            }
+        };
+        let srcs = if processor.skip_images() {
+            Vec::new()
+        } else {
+            parser.chapter_srcs(&content)
+        };
         for src in srcs {
-            let Ok((image_bytes, extension)) = downloader.image(&src).await else {
-                error!("图片下载失败: {}", src);
-                continue;
-            };
-
-            let Ok(image_name) = processor.write_image(image_bytes, extension).await else {
-                error!("图片保存失败: {}", src);
-                continue;
-            };
-
-            content = content.replace(&src, &format!("../Images/{}", image_name));
-            chapter.images.push(image_name);
+            Self::chapter_image(
+                &mut content,
+                &mut chapter,
+                &src,
+                &processor,
+                &mut downloader,
+            )
+            .await;
         }
         processor.write_chapter(content, &chapter).await?;
+        processor.complete(&chapter).await;
         info!("完成处理第 {} 章: {}", chapter.index, chapter.title);
         Ok(chapter)
     }
 }
 
 impl DoclnCrawler {
-    async fn volume_sequential(
+    /// 下载并保存章节插图, 替换正文中的src; 同一url的图片本次运行中只下载一次,
+    /// 同时下载同一url的任务会等待第一个任务的结果
+    async fn chapter_image(
+        content: &mut String,
+        chapter: &mut Chapter,
+        src: &str,
+        processor: &Processor,
+        downloader: &mut Downloader,
+    ) {
+        let cell = processor.image_cell(src, chapter);
+        let image_name = cell
+            .get_or_try_init(|| async {
+                if processor.exceeds_image_budget(0) {
+                    warn!("已用完插图预算, 省略图片: {}", src);
+                    return Ok(None);
+                }
+                let Ok((image_bytes, extension)) = downloader.image(src).await else {
+                    error!("图片下载失败: {}", src);
+                    return Err(());
+                };
+                processor
+                    .write_chapter_image(image_bytes, extension, chapter)
+                    .await
+                    .map_err(|_| error!("图片保存失败: {}", src))
+            })
+            .await;
+
+        match image_name {
+            Ok(Some(image_name)) => {
+                *content = content.replace(src, &format!("../Images/{}", image_name));
+                chapter.images.push(image_name.clone());
+            }
+            Ok(None) => *content = content.replace(src, OMITTED_IMAGE_SRC),
+            Err(()) => {}
+        }
+    }
+
+    /// 顺序抓取所有卷的章节, 返回每一卷和转换后的章节
+    async fn volume_sequential<T: From<Chapter>>(
         mut volumes: Vec<Volume>,
         processor: &Processor,
         downloader: &mut Downloader,
         parser: &Parser,
-    ) -> Result<Vec<Volume>> {
-        let mut next_url = volumes.first().unwrap().chapters.first().map(|c| c.url.clone()).unwrap();
+    ) -> Result<Vec<(Volume, Vec<T>)>> {
+        // --since-last 过滤后可能没有任何章节
+        if volumes.first().and_then(|v| v.chapters.first()).is_none() {
+            return Ok(volumes
+                .into_iter()
+                .map(|mut volume| {
+                    let chapters: Vec<T> = take(&mut volume.chapters)
+                        .into_iter()
+                        .map(T::from)
+                        .collect();
+                    (volume, chapters)
+                })
+                .collect());
+        }
         for volume in volumes.iter_mut() {
             info!("正在处理第 {} 卷", volume.index);
+            if processor.skip_images() {
+                volume.cover = None;
+            }
             if let Some(volume_cover_url) = &volume.cover {
                 let (cover_bytes, extension) = downloader.image(volume_cover_url).await?;
                 let cover_name = processor.write_image(cover_bytes, extension).await?;
                 volume.cover = Some(cover_name);
             }
 
-            let cover_html = volume.cover_html();
+            let cover_html = volume.cover_html(processor.doctype());
             processor
                 .write_html(cover_html, &volume.cover_chapter)
                 .await?;
-            let chapters = Self::chapters_sequential(
-                take(&mut volume.chapters),
-                processor,
-                downloader,
-                parser,
-                &mut next_url,
-            )
-            .await?;
-            volume.chapters = chapters;
+        }
+
+        // 所有卷的章节作为一个整体顺序抓取, 这样卷的最后一章也能知道下一章节的标题
+        let counts: Vec<usize> = volumes.iter().map(|v| v.chapters.len()).collect();
+        let chapters = volumes
+            .iter_mut()
+            .flat_map(|v| take(&mut v.chapters))
+            .collect();
+        let chapters: Vec<T> =
+            Self::chapters_sequential(chapters, processor, downloader, parser).await?;
+
+        let mut chapters = chapters.into_iter();
+        let mut result = Vec::with_capacity(volumes.len());
+        for (volume, count) in volumes.into_iter().zip(counts) {
             info!("完成处理第 {} 卷", volume.index);
+            result.push((volume, chapters.by_ref().take(count).collect()));
         }
-        Ok(volumes)
+        Ok(result)
     }
 
-    // todo: 为什么要返回chapter
-    async fn chapters_sequential(
-        mut chapters: Vec<Chapter>,
+    /// 从第一个章节开始顺序抓取, 每个章节写入文件后立即从目录中取出并转换为 T:
+    /// 完整的 Chapter, 或 --stream-chapters 时只有导航信息的 NavEntry
+    async fn chapters_sequential<T: From<Chapter>>(
+        chapters: Vec<Chapter>,
         processor: &Processor,
         downloader: &Downloader,
         parser: &Parser,
-        next_url: &mut String,
-    ) -> Result<Vec<Chapter>> {
+    ) -> Result<Vec<T>> {
         let mut downloader = downloader.clone();
-        let chapter_contents = downloader.chapters_sequential(&chapters, next_url).await?;
-        for (chapter, mut content) in chapters.iter_mut().zip(chapter_contents) {
-            let srcs = parser.chapter_srcs(&content);
-            for src in srcs {
-                let Ok((image_bytes, extension)) = downloader.image(&src).await else {
-                    error!("图片下载失败: {}", src);
-                    continue;
+        let mut image_downloader = downloader.clone();
+        let total = chapters.len();
+        let mut next_url = chapters.first().map(|c| c.url.clone()).unwrap_or_default();
+        let mut pending = VecDeque::from(chapters);
+        let mut written = Vec::with_capacity(total);
+
+        // --resume 时跳过上次已经完成的章节, 从上次停下的页面接着抓取
+        if let Some(resume_url) = processor.resume_url().await {
+            while let Some(chapter) = pending.front_mut() {
+                let Some(images) = processor.completed(chapter).await else {
+                    break;
                 };
+                chapter.images = images;
+                written.extend(pending.pop_front().map(T::from));
+            }
+            if !written.is_empty() {
+                info!(
+                    "跳过上次已完成的 {} 个章节, 从 {} 继续抓取",
+                    written.len(),
+                    resume_url
+                );
+                next_url = resume_url;
+            }
+        }
 
-                let Ok(image_name) = processor.write_image(image_bytes, extension).await else {
-                    error!("图片保存失败: {}", src);
-                    continue;
+        // 章节之间只能通过"下一页"链接前进, 额外章节的页面也要经过, 标题用于判断章节的边界;
+        // --no-extras 时额外章节不下载插图, 整本抓取完成后再去掉
+        let titles: Vec<String> = pending.iter().map(|c| c.title.clone()).collect();
+        // 每个章节获取完整后立即下载插图并写入文件, 不在内存中保留所有章节的内容;
+        // 页面按顺序归入章节, 回调的总是下一个未写入的章节
+        downloader
+            .chapters_sequential(&titles, &mut next_url, async |_, mut content, next| {
+                let mut chapter = pending.pop_front().expect("章节数与标题数相同");
+                let srcs = if processor.skip_images() || (processor.no_extras() && chapter.special)
+                {
+                    Vec::new()
+                } else {
+                    parser.chapter_srcs(&content)
                 };
+                for src in srcs {
+                    Self::chapter_image(
+                        &mut content,
+                        &mut chapter,
+                        &src,
+                        processor,
+                        &mut image_downloader,
+                    )
+                    .await;
+                }
+                processor.write_chapter(content, &chapter).await?;
+                processor.complete_at(&chapter, next).await;
+                written.push(T::from(chapter));
+                Ok(())
+            })
+            .await?;
+        // 下一页链接提前中断(章节被删除等)时, 之后的章节没有内容, 不能留在目录中
+        if let Some(missing) = pending.front() {
+            warn!(
+                "只获取到 {} / {} 个章节, 去掉未获取到的章节: 从 '{}' 开始",
+                written.len(),
+                total,
+                missing.title
+            );
+        }
+        Ok(written)
+    }
+
+    /// 下载封面、封底和彩页插图, 把 epub 中的url替换为保存的文件名; --skip-images 时全部去掉;
+    /// 封面下载失败时报错, 封底和彩页插图下载失败时跳过
+    async fn download_covers(
+        epub: &mut Epub,
+        downloader: &mut Downloader,
+        processor: &Processor,
+    ) -> Result<()> {
+        let cover_url = take(&mut epub.cover);
+        let back_cover_url = take(&mut epub.back_cover);
+        let gallery_urls = take(&mut epub.gallery);
+        if processor.skip_images() {
+            return Ok(());
+        }
 
-                content = content.replace(&src, &format!("../Images/{}", image_name));
-                chapter.images.push(image_name);
+        if let Some(cover_url) = cover_url {
+            let (cover_bytes, extension) = downloader.image(&cover_url).await?;
+            epub.cover = Some(processor.write_image(cover_bytes, extension).await?);
+        }
+        // 封底和彩页不是必需的, 下载失败时跳过
+        if let Some(back_cover_url) = back_cover_url {
+            match downloader.image(&back_cover_url).await {
+                Ok((cover_bytes, extension)) => {
+                    epub.back_cover = Some(processor.write_image(cover_bytes, extension).await?);
+                }
+                Err(e) => warn!("封底下载失败, 跳过: {}: {:#}", back_cover_url, e),
             }
-            processor.write_chapter(content, chapter).await.expect("");
         }
-        Ok(chapters)
+        for image_url in gallery_urls {
+            let (image_bytes, extension) = match downloader.image(&image_url).await {
+                Ok(image) => image,
+                Err(e) => {
+                    warn!("彩页插图下载失败, 跳过: {}: {:#}", image_url, e);
+                    continue;
+                }
+            };
+            let image_name = processor.write_image(image_bytes, extension).await?;
+            // 与封面相同或重复的图片只保留一次
+            if epub.cover.as_ref() != Some(&image_name) && !epub.gallery.contains(&image_name) {
+                epub.gallery.push(image_name);
+            }
+        }
+        if !epub.gallery.is_empty() {
+            info!("彩页共 {} 张插图", epub.gallery.len());
+        }
+        Ok(())
     }
 
-    #[instrument(skip_all)]
+    /// 目录的第一章还有"上一页"时, 说明目录不是从真正的第一章开始, 生成的EPUB会缺少前面的章节
+    async fn check_prev_page(epub: &Epub, downloader: &mut Downloader) -> Result<()> {
+        let has_prev_url = downloader
+            .config()
+            .get_chapter_config()
+            .is_some_and(|chapter| chapter.content.prev_url.is_some());
+        if !has_prev_url {
+            return Ok(());
+        }
+
+        let first_chapter = match &epub.children {
+            VolOrChap::Volumes(volumes) => volumes.iter().find_map(|v| v.chapters.first()),
+            VolOrChap::Chapters(chapters) => chapters.first(),
+        };
+        let Some(first_chapter) = first_chapter else {
+            return Ok(());
+        };
+
+        if let Some(prev_url) = downloader.prev_url(&first_chapter.url).await? {
+            warn!(
+                "第一章 '{}' 之前还有页面, 目录可能不完整, 缺少前面的章节: {}",
+                first_chapter.title, prev_url
+            );
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(novel_id = %novel_id))]
     pub async fn epub_sequential(
         novel_id: String,
         mut downloader: Downloader,
         parser: Parser,
+        options: Arc<Options>,
+        since: Option<usize>,
     ) -> Result<Epub> {
-        info!("正在爬取 ID为 {} 的小说...", novel_id);
-        let epub_name = format!("{}", novel_id);
-        let epub_dir = PathBuf::from(&epub_name);
-        let meta_dir = epub_dir.join("META-INF");
-        let oebps_dir = epub_dir.join("OEBPS");
-        let image_dir = oebps_dir.join("Images");
-        let text_dir = oebps_dir.join("Text");
+        let (mut epub, processor) =
+            Self::prepare_epub(novel_id, &mut downloader, &parser, &options, since, true).await?;
 
-        fs::create_dir(&epub_dir).await?;
-        fs::create_dir(&meta_dir).await?;
-        fs::create_dir(&oebps_dir).await?;
-        fs::create_dir(&image_dir).await?;
-        fs::create_dir(&text_dir).await?;
-
-        let processor = Arc::new(processor::Processor::new(
-            image_dir.clone(),
-            text_dir.clone(),
-        ));
-        let novel_html = downloader.novel_info().await?;
-        let mut epub = parser.novel_info(&novel_html, novel_id)?;
-        if let Some(cover_url) = take(&mut epub.cover) {
-            let (cover_bytes, extension) = downloader.image(&cover_url).await?;
-            let cover_name = processor.write_image(cover_bytes, extension).await?;
-            epub.cover = Some(cover_name);
+        let children = take(&mut epub.children);
+        if options.stream_chapters {
+            let nav = match children {
+                VolOrChap::Volumes(volumes) => NavList::Volumes(
+                    Self::volume_sequential(volumes, &processor, &mut downloader, &parser)
+                        .await?
+                        .into_iter()
+                        .map(|(volume, entries)| NavVolume::new(&volume, entries))
+                        .collect(),
+                ),
+                VolOrChap::Chapters(chapters) => NavList::Chapters(
+                    Self::chapters_sequential(chapters, &processor, &downloader, &parser).await?,
+                ),
+            };
+            epub.nav = Some(nav);
+        } else {
+            epub.children = match children {
+                VolOrChap::Volumes(volumes) => VolOrChap::Volumes(
+                    Self::volume_sequential(volumes, &processor, &mut downloader, &parser)
+                        .await?
+                        .into_iter()
+                        .map(|(volume, chapters)| Volume { chapters, ..volume })
+                        .collect(),
+                ),
+                VolOrChap::Chapters(chapters) => VolOrChap::Chapters(
+                    Self::chapters_sequential(chapters, &processor, &downloader, &parser).await?,
+                ),
+            };
         }
-
-        let children = match take(&mut epub.children) {
-            epub::VolOrChap::Volumes(volumes) => VolOrChap::Volumes(
-                Self::volume_sequential(volumes, &processor, &mut downloader, &parser).await?,
-            ),
-            epub::VolOrChap::Chapters(chapters) => {
-                let mut next_url = chapters.first().map(|c| c.url.clone()).unwrap();
-                VolOrChap::Chapters(
-                Self::chapters_sequential(chapters, &processor, &downloader, &parser, &mut next_url).await?
-            )
+        // 顺序抓取时额外章节的页面也要经过, 下载完成后再去掉; 写入文件是为了 --resume 能接着抓取.
+        // 额外章节在写入之前就没有编号, 去掉后其他章节的编号与已写入的标题一致
+        if options.no_extras {
+            let extras = match &mut epub.nav {
+                Some(nav) => nav.remove_extras(),
+                None => epub
+                    .remove_extras()
+                    .into_iter()
+                    .map(NavEntry::from)
+                    .collect(),
+            };
+            info!("去掉 {} 个额外章节", extras.len());
+            for extra in extras {
+                fs::remove_file(epub.text_dir.join(&extra.filename)).await?;
             }
-        };
-
-        epub.children = children;
-        epub.epub_dir = epub_dir;
-        epub.meta_dir = meta_dir;
-        epub.oebps_dir = oebps_dir;
-        epub.image_dir = image_dir;
-        epub.text_dir = text_dir;
+        }
 
         info!("完成爬取 ID为 {} 的小说", epub.id);
         Ok(epub)
@@ -369,3 +970,132 @@ pub enum VolOrChapTasks {
     Volume(VolumeTaskManager),
     Chapter(ChapterTaskManager),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::GlobalNumbering;
+    use crate::epub::Doctype;
+    use crate::epub::test_util::{self, BOOK, TempDir, site_config};
+
+    const PNG: &str = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg==";
+    const GIF: &str = "data:image/gif;base64,R0lGODlhAQABAAAAACw=";
+    const BROKEN: &str = "data:image/png,not-base64";
+
+    #[tokio::test]
+    async fn download_covers_skips_failed_extras() {
+        let options = Arc::new(Options::default());
+        let mut downloader =
+            Downloader::new(site_config(BOOK), "https://example.com/novel/12/", &options).unwrap();
+        let image_dir = TempDir::new("covers");
+        let processor = Arc::new(processor::Processor::new(
+            image_dir.path().to_path_buf(),
+            image_dir.path().to_path_buf(),
+            options,
+            Doctype::default(),
+        ));
+
+        let mut epub = epub::test_util::epub(VolOrChap::Chapters(Vec::new()));
+        epub.cover = Some(PNG.to_string());
+        epub.back_cover = Some(BROKEN.to_string());
+        epub.gallery = vec![BROKEN.to_string(), PNG.to_string(), GIF.to_string()];
+        let result = DoclnCrawler::download_covers(&mut epub, &mut downloader, &processor).await;
+
+        // 封底和下载失败的彩页被跳过, 与封面相同的彩页只保留封面
+        result.unwrap();
+        assert!(epub.cover.as_deref().unwrap().ends_with(".png"));
+        assert!(epub.back_cover.is_none());
+        assert_eq!(epub.gallery.len(), 1);
+        assert!(epub.gallery[0].ends_with(".gif"));
+
+        // 封面下载失败时报错
+        let mut epub = epub::test_util::epub(VolOrChap::Chapters(Vec::new()));
+        epub.cover = Some(BROKEN.to_string());
+        let result = DoclnCrawler::download_covers(&mut epub, &mut downloader, &processor).await;
+        assert!(result.is_err());
+    }
+
+    /// 两卷共五个章节, 第一卷的第二章和第二卷的最后一章是额外章节
+    fn numbered_epub() -> Epub {
+        use test_util::{chapter, volume};
+        let extra = |index, title| Chapter {
+            special: true,
+            ..chapter(index, title)
+        };
+        test_util::epub(VolOrChap::Volumes(vec![
+            volume(0, "第一卷", vec![chapter(1, "第一章"), extra(2, "番外一")]),
+            volume(
+                1,
+                "第二卷",
+                vec![chapter(3, "第二章"), chapter(4, "第三章"), extra(5, "后记")],
+            ),
+        ]))
+    }
+
+    /// (标题, 编号), 按阅读顺序
+    fn numbers(epub: &Epub) -> Vec<(String, Option<usize>)> {
+        let VolOrChap::Volumes(volumes) = &epub.children else {
+            panic!("应该保留卷");
+        };
+        volumes
+            .iter()
+            .flat_map(|v| &v.chapters)
+            .map(|c| (c.title.clone(), c.number))
+            .collect()
+    }
+
+    fn numbered(chapters: &[(&str, Option<usize>)]) -> Vec<(String, Option<usize>)> {
+        chapters
+            .iter()
+            .map(|(title, number)| (title.to_string(), *number))
+            .collect()
+    }
+
+    #[test]
+    fn select_chapters_numbers_against_full_toc() {
+        let options = Options {
+            global_numbering: Some(GlobalNumbering::Heading),
+            ..Options::default()
+        };
+
+        // --since-last 跳过的章节也占用编号
+        let mut epub = numbered_epub();
+        DoclnCrawler::select_chapters(&mut epub, &options, Some(3), None, false);
+        assert_eq!(
+            numbers(&epub),
+            numbered(&[("第三章", Some(4)), ("后记", Some(5))])
+        );
+
+        // 并行抓取与顺序抓取的编号相同
+        let mut sequential = numbered_epub();
+        DoclnCrawler::select_chapters(&mut sequential, &options, Some(3), None, true);
+        assert_eq!(numbers(&sequential), numbers(&epub));
+    }
+
+    #[test]
+    fn select_chapters_no_extras_with_global_numbering() {
+        let options = Options {
+            global_numbering: Some(GlobalNumbering::Heading),
+            no_extras: true,
+            ..Options::default()
+        };
+
+        // 并行抓取时直接去掉额外章节, 跳过的额外章节不占用编号
+        let mut epub = numbered_epub();
+        DoclnCrawler::select_chapters(&mut epub, &options, Some(2), None, false);
+        assert_eq!(
+            numbers(&epub),
+            numbered(&[("第二章", Some(2)), ("第三章", Some(3))])
+        );
+
+        // 顺序抓取时保留额外章节到下载完成, 写入之前就没有编号; 去掉后与并行抓取相同
+        let mut sequential = numbered_epub();
+        DoclnCrawler::select_chapters(&mut sequential, &options, Some(2), None, true);
+        assert_eq!(
+            numbers(&sequential),
+            numbered(&[("第二章", Some(2)), ("第三章", Some(3)), ("后记", None)])
+        );
+        sequential.remove_extras();
+        assert_eq!(numbers(&sequential), numbers(&epub));
+    }
+}
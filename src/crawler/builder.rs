@@ -0,0 +1,147 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+
+use crate::cli::{Options, OutputFormat};
+use crate::config::{RateLimit, SiteConfig, get_site_config};
+use crate::crawler::DoclnCrawler;
+use crate::crawler::downloader::Downloader;
+use crate::crawler::parser::Parser;
+
+/// 构建 DoclnCrawler, 作为库使用时可以不依赖命令行参数
+///
+/// 网站配置可以通过 site 从 config/ 目录中读取, 也可以通过 site_config 直接传入
+#[derive(Default)]
+pub struct DoclnCrawlerBuilder {
+    site: Option<String>,
+    site_config: Option<Arc<SiteConfig>>,
+    url: Option<String>,
+    id: Option<String>,
+    options: Options,
+}
+
+impl DoclnCrawlerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 使用 config/ 目录中名称为 site 的网站配置
+    pub fn site(mut self, site: impl Into<String>) -> Self {
+        self.site = Some(site.into());
+        self
+    }
+
+    /// 直接使用给定的网站配置, 传入 Arc 时多个爬虫可以共用同一份配置
+    pub fn site_config(mut self, config: impl Into<Arc<SiteConfig>>) -> Self {
+        self.site_config = Some(config.into());
+        self
+    }
+
+    /// 小说页面的url
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    /// 小说id, 未设置url时按网站配置的 base_url 生成; 未设置时从url中提取
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// 一次设置全部选项, 之后的方法会覆盖其中对应的字段
+    pub fn options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn output_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.output_dir = Some(dir.into());
+        self
+    }
+
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.options.format = format;
+        self
+    }
+
+    pub fn max_retries(mut self, max: u32) -> Self {
+        self.options.max_retries = Some(max);
+        self
+    }
+
+    /// 最大并行请求数, 覆盖网站配置中的 concurrency_limit
+    pub fn concurrency(mut self, limit: usize) -> Self {
+        self.options.concurrency = Some(limit);
+        self
+    }
+
+    /// per_secs 秒内最多 num 次请求, 覆盖网站配置中的 rate_limit; 与 --rate 相同, 都不能为0
+    pub fn rate_limit(mut self, num: u64, per_secs: u64) -> Self {
+        self.options.rate = Some(RateLimit {
+            num,
+            secs: per_secs,
+        });
+        self
+    }
+
+    /// 单个请求的超时秒数, 不能为0
+    pub fn timeout(mut self, secs: u64) -> Self {
+        self.options.timeout = Some(secs);
+        self
+    }
+
+    /// 添加一个代理, 添加后不再使用网站配置中的代理
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.options.proxy.push(proxy.into());
+        self
+    }
+
+    pub fn build(self) -> Result<DoclnCrawler> {
+        if let Some(rate) = &self.options.rate
+            && (rate.num == 0 || rate.secs == 0)
+        {
+            bail!("请求频率不能为0: {}/{}s", rate.num, rate.secs);
+        }
+        if self.options.timeout == Some(0) {
+            bail!("超时时间不能为0秒");
+        }
+        let config = match (self.site_config, &self.site) {
+            (Some(config), _) => config,
+            (None, Some(site)) => get_site_config(site)?,
+            (None, None) => bail!("没有指定网站配置"),
+        };
+        if config.get_chapter_config().is_none() {
+            bail!("网站配置 {} 没有章节配置(volumes 或 chapters)", config.name);
+        }
+
+        let url = match (self.url, &self.id) {
+            (Some(url), _) => url,
+            (None, Some(id)) => config.build_url_with_id(id),
+            (None, None) => bail!("没有指定小说的url或id"),
+        };
+        // id 和网站名称组成EPUB的文件名和下载记录的键
+        let id = match self.id {
+            Some(id) => id,
+            None => match config
+                .id_from_url(&url)
+                .and_then(|mut values| values.remove("id"))
+            {
+                Some(id) => id,
+                None => bail!("没有指定小说id, 也无法从url中提取: {}", url),
+            },
+        };
+        let site = self.site.unwrap_or_else(|| config.name.clone());
+
+        Ok(DoclnCrawler {
+            site,
+            id,
+            parser: Parser::new(config.clone())
+                .with_volume_order(self.options.sort_volumes)
+                .with_strip_title(self.options.strip_chapter_title_prefix.clone()),
+            downloader: Downloader::new(config, &url, &self.options)?,
+            options: Arc::new(self.options),
+        })
+    }
+}
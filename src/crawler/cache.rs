@@ -0,0 +1,73 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tracing::{debug, error};
+
+/// 页面响应的磁盘缓存, 以url为键, 保存响应体和ETag/Last-Modified/Content-Type
+pub struct ResponseCache {
+    dir: PathBuf,
+}
+
+pub struct CacheEntry {
+    pub body: Vec<u8>,
+    pub meta: CacheMeta,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct CacheMeta {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// 响应的 Content-Type, 使用缓存时按其中的charset解码; 旧的缓存中没有该字段
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
+
+impl ResponseCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// url对应的文件名(不含扩展名)
+    pub fn key(url: &str) -> String {
+        format!("{:x}", Sha256::digest(url.as_bytes()))
+    }
+
+    pub async fn load(&self, url: &str) -> Option<CacheEntry> {
+        let key = Self::key(url);
+        let meta = fs::read(self.dir.join(format!("{}.json", key)))
+            .await
+            .ok()?;
+        let meta: CacheMeta = serde_json::from_slice(&meta).ok()?;
+        let body = fs::read(self.dir.join(format!("{}.html", key)))
+            .await
+            .ok()?;
+        debug!("找到缓存: {}", url);
+        Some(CacheEntry { body, meta })
+    }
+
+    pub async fn store(&self, body: &[u8], meta: &CacheMeta) {
+        if meta.etag.is_none() && meta.last_modified.is_none() {
+            // 没有校验信息的响应无法发送条件请求, 不缓存
+            return;
+        }
+        if let Err(e) = self.try_store(body, meta).await {
+            error!("写入缓存失败: {}: {}", meta.url, e);
+        }
+    }
+
+    async fn try_store(&self, body: &[u8], meta: &CacheMeta) -> Result<()> {
+        let key = Self::key(&meta.url);
+        fs::create_dir_all(&self.dir).await?;
+        fs::write(self.dir.join(format!("{}.html", key)), body).await?;
+        fs::write(
+            self.dir.join(format!("{}.json", key)),
+            serde_json::to_vec(meta)?,
+        )
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::Result;
+use scraper::Html;
+use tracing::{instrument, warn};
+
+use crate::crawler::DoclnCrawler;
+use crate::epub::VolOrChap;
+use crate::extractor::Value;
+
+impl DoclnCrawler {
+    /// 用一本已知的小说检查网站配置: 解析小说信息和第一章, 输出每个字段是否提取到内容, 不生成EPUB;
+    /// 标题、作者、章节列表和第一章正文都提取成功时返回true
+    #[instrument(skip_all, fields(site = %self.site, id = %self.id))]
+    pub async fn test_site(&self) -> Result<bool> {
+        let id = self.novel_id();
+        let mut downloader = self.downloader.clone();
+        let book_extractor = self.downloader.config().get_book_config();
+
+        println!("检查网站配置 {}: {}", self.site, downloader.url);
+        let novel_html = downloader.novel_info().await?;
+        let document = Html::parse_document(&novel_html);
+        let Some(book_elem) = book_extractor.this(document.root_element()) else {
+            report("book", false, "book选择器未匹配");
+            return Ok(false);
+        };
+
+        let fields = [
+            ("title", true, Some(book_extractor.extract_title(book_elem))),
+            (
+                "author",
+                true,
+                Some(book_extractor.extract_author(book_elem)),
+            ),
+            (
+                "subtitle",
+                false,
+                book_extractor
+                    .subtitle
+                    .as_ref()
+                    .map(|_| book_extractor.extract_subtitle(book_elem)),
+            ),
+            (
+                "illustrator",
+                false,
+                book_extractor
+                    .illustrator
+                    .as_ref()
+                    .map(|_| book_extractor.extract_illustrator(book_elem)),
+            ),
+            (
+                "tags",
+                false,
+                book_extractor
+                    .tags
+                    .as_ref()
+                    .map(|_| book_extractor.extract_tags(book_elem)),
+            ),
+            (
+                "summary",
+                false,
+                book_extractor
+                    .summary
+                    .as_ref()
+                    .map(|_| book_extractor.extract_summary(book_elem)),
+            ),
+            (
+                "cover_url",
+                false,
+                book_extractor
+                    .cover_url
+                    .as_ref()
+                    .map(|_| book_extractor.extract_cover_url(book_elem)),
+            ),
+            (
+                "back_cover_url",
+                false,
+                book_extractor
+                    .back_cover_url
+                    .as_ref()
+                    .map(|_| book_extractor.extract_back_cover_url(book_elem)),
+            ),
+            (
+                "gallery",
+                false,
+                book_extractor
+                    .gallery
+                    .as_ref()
+                    .map(|_| book_extractor.extract_gallery(book_elem)),
+            ),
+        ];
+
+        let mut passed = true;
+        for (name, required, value) in fields {
+            match value.map(Value::trim) {
+                None => println!("  [-] {}: 未配置", name),
+                Some(Value::Empty) => {
+                    report(name, false, "没有提取到内容");
+                    passed &= !required;
+                }
+                Some(Value::Single(value)) => report(name, true, &preview(&value)),
+                Some(Value::Multiple(values)) => report(
+                    name,
+                    true,
+                    &format!("{} 项: {}", values.len(), values.join(", ")),
+                ),
+            }
+        }
+        if !passed {
+            return Ok(false);
+        }
+
+        let chapter_list_html = match downloader.chapter_list().await {
+            Ok(chapter_list_html) => chapter_list_html,
+            Err(e) => {
+                report("chapter_list_url", false, &format!("{:#}", e));
+                return Ok(false);
+            }
+        };
+        let epub = match self
+            .parser
+            .novel_info(&novel_html, chapter_list_html.as_deref(), id)
+        {
+            Ok(epub) => epub,
+            Err(e) => {
+                report("chapters", false, &format!("{:#}", e));
+                return Ok(false);
+            }
+        };
+        let first_chapter = match &epub.children {
+            VolOrChap::Volumes(volumes) => {
+                report(
+                    "volumes",
+                    !volumes.is_empty(),
+                    &format!("{} 卷", volumes.len()),
+                );
+                volumes.iter().find_map(|v| v.chapters.first())
+            }
+            VolOrChap::Chapters(chapters) => chapters.first(),
+        };
+        let chapter_count = epub.chapter_count();
+        report(
+            "chapters",
+            chapter_count > 0,
+            &format!("{} 个章节", chapter_count),
+        );
+        let Some(first_chapter) = first_chapter else {
+            return Ok(false);
+        };
+
+        let content = match downloader.chapter(&first_chapter.url).await {
+            Ok(chapter_html) => self
+                .parser
+                .chapter_content(chapter_html, &first_chapter.title),
+            Err(e) => Err(e),
+        };
+        match content {
+            Ok(content) if !content.trim().is_empty() => {
+                let text_len = Html::parse_fragment(&content)
+                    .root_element()
+                    .text()
+                    .map(|text| text.trim().chars().count())
+                    .sum::<usize>();
+                report(
+                    "content",
+                    true,
+                    &format!("第一章 '{}' 正文 {} 字", first_chapter.title, text_len),
+                );
+                let images = self.parser.chapter_srcs(&content).len();
+                if images == 0 {
+                    println!("  [-] images: 第一章没有插图");
+                } else {
+                    report("images", true, &format!("第一章有 {} 张插图", images));
+                }
+                Ok(true)
+            }
+            Ok(_) => {
+                report("content", false, "第一章正文为空");
+                Ok(false)
+            }
+            Err(e) => {
+                report("content", false, &format!("{:#}", e));
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// 输出一项检查结果, 失败的项同时记录警告
+fn report(name: &str, ok: bool, detail: &str) {
+    if ok {
+        println!("  [✓] {}: {}", name, detail);
+    } else {
+        println!("  [✗] {}: {}", name, detail);
+        warn!("{} 检查失败: {}", name, detail);
+    }
+}
+
+/// 过长的值只显示开头
+fn preview(value: &str) -> String {
+    const MAX_CHARS: usize = 60;
+    let value = value.replace('\n', " ");
+    if value.chars().count() > MAX_CHARS {
+        format!("{}...", value.chars().take(MAX_CHARS).collect::<String>())
+    } else {
+        value
+    }
+}
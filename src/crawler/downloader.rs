@@ -1,169 +1,682 @@
-use std::path::Path;
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::future::Future;
+use std::io::{IsTerminal, Read};
+use std::mem::take;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock};
+use std::task::{Context as TaskContext, Poll};
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use base64::prelude::*;
 use bytes::Bytes;
+use encoding_rs::{Encoding, UTF_8};
+use flate2::read::GzDecoder;
+use http::header::{
+    CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LOCATION,
+};
 use http::{Request, Response};
+use http_body_util::BodyExt;
+use indicatif::{ProgressBar, ProgressStyle};
+use regex::Regex;
 use reqwest::Body;
 use reqwest::StatusCode;
-use tower::{ServiceBuilder, ServiceExt as _};
+use sha2::{Digest, Sha256};
+use tower::{BoxError, Service, ServiceBuilder, ServiceExt as _};
 use tower_http_client::{ResponseExt, ServiceExt as _};
 use tower_reqwest::HttpClientLayer;
-use tracing::{error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
 use url::Url;
 
-use crate::Chapter;
-use crate::config::SiteConfig;
-use crate::config::{AuthType, JAR, get_auth, get_site_config};
-use crate::extractor::Value;
+use crate::cli::Options;
+use crate::config::{AuthType, JAR, get_auth};
+use crate::config::{RateLimit, Referer, Retry, SiteConfig, UserAgent};
+use crate::crawler::cache::{CacheMeta, ResponseCache};
+use crate::crawler::parser::SoftError;
+use crate::crawler::proxy::{ProxyClient, ProxyPool};
+use crate::crawler::retry::{RequestError, RetryBudget, RetryPolicy};
+use crate::extractor::{PostRequest, Value, strip_title};
+use crate::logger;
+
+/// 获取小说页面时在请求本身的重试之外最多额外重试的次数, 不超过 retry.max
+const NOVEL_INFO_RETRIES: u32 = 2;
+
+/// 顺序抓取时平均每个章节最多的页数, 超过后认为"下一页"链接陷入了循环
+const MAX_PAGES_PER_CHAPTER: usize = 100;
 
 type HttpClient = tower::util::BoxCloneService<Request<Body>, Response<Body>, anyhow::Error>;
 
 #[derive(Clone)]
 pub struct Downloader {
-    config: &'static SiteConfig,
+    config: Arc<SiteConfig>,
     client: HttpClient,
+    image_client: HttpClient, // 下载图片使用, 没有单独配置图片的限制时与 client 相同
+    cache: Option<Arc<ResponseCache>>,
+    dump_dir: Option<Arc<PathBuf>>,
+    retry: Retry,
+    budget: Arc<RetryBudget>, // 与重试层共用, 获取小说页面时额外的重试也从中取出
+    rate_limit: RateLimit,
+    concurrency_limit: usize,
+    strip_title: Option<Regex>, // 与目录中的章节标题一样处理页面标题, 顺序抓取时才能匹配
     pub url: Arc<Url>,
+    image_base: Arc<Url>, // 拼接图片相对链接的基础url, 没有配置 image_base_url 时与 url 相同
+    image_progress: bool, // 下载较大的图片时是否显示进度条
 }
 
 impl Downloader {
-    pub async fn chapters_sequential(&mut self, chapters: &[Chapter], next_url: &mut String) -> Result<Vec<String>> {
-        let mut results = Vec::new();
-
-        // let mut next_url = self.url.join(
-        //     &chapters
-        //         .first()
-        //         .ok_or_else(|| anyhow::anyhow!("章节列表为空"))?
-        //         .url,
-        // )?;
+    /// 从 next_url 开始沿"下一页"链接依次抓取, 按页面标题把页面归入 titles 中对应的章节
+    ///
+    /// 每个章节的内容完整后立即交给 on_chapter(章节在 titles 中的位置, 内容, 下一章节开始的页面),
+    /// 不在内存中累积, 没有下一页时页面为空;
+    /// 返回获取到的章节数(可能少于 titles); 当前章节列表结束时,
+    /// next_url 指向下一个尚未处理的页面, 没有下一页时被清空
+    pub async fn chapters_sequential(
+        &mut self,
+        titles: &[String],
+        next_url: &mut String,
+        mut on_chapter: impl AsyncFnMut(usize, String, &str) -> Result<()>,
+    ) -> Result<usize> {
+        let mut done = 0;
+        if titles.is_empty() {
+            return Ok(done);
+        }
+        if next_url.is_empty() {
+            warn!("已经没有下一页, 跳过 {} 个章节", titles.len());
+            return Ok(done);
+        }
 
         *next_url = self.url.join(next_url)?.to_string();
 
-        let mut chapter_content = String::new();
-
-        for chapter in chapters {
-            let response = self.client.get(next_url.as_str()).send().await?;
-            let chapter_html = response.body_reader().utf8().await?;
+        let config = self.config.clone();
+        let content_extract = &config.get_chapter_config().expect("没有章节配置").content;
 
-            let content_extract = &self
-                .config
-                .get_chapter_config()
-                .expect("没有章节配置")
-                .content;
+        let mut chapter_content = String::new();
+        // 上一个页面正文的哈希和已访问的页面, 防止服务器出错或 next_url 指向自身时重复添加内容
+        let mut prev_hash = None;
+        let mut visited = HashSet::new();
+        // 每次的链接都不同的循环(例如链接中带有随机参数)无法通过已访问的页面发现, 按章节数限制总页数
+        let max_pages = titles.len().saturating_mul(MAX_PAGES_PER_CHAPTER);
+        // 当前页面匹配 retry_if_content_matches 后已经重试的次数
+        let mut retries = 0;
 
+        loop {
+            visited.insert(next_url.clone());
+            let response = self.get_page(next_url).await?;
+            let chapter_html = read_html(response).await?;
             let chapter_html = scraper::Html::parse_document(&chapter_html);
 
-            let content = chapter_html
-                .select(&content_extract.this)
-                .next()
+            let content = content_extract
+                .this(chapter_html.root_element())
                 .ok_or_else(|| anyhow::anyhow!("无法找到章节内容"))?;
 
-            let paragraphs = match content_extract.extract_paragraphs(content) {
+            let paragraphs = content_extract.extract_content(chapter_html.root_element(), content);
+            let paragraphs = match paragraphs {
                 Value::Single(text) => text,
                 _ => {
-                    println!("content: {}", content.html());
-                    return Err(anyhow::anyhow!("章节内容提取失败"))
-                },
+                    debug!("章节内容: {}", content.html());
+                    return Err(anyhow::anyhow!("章节内容提取失败"));
+                }
             };
+            // 与并行下载相同, 错误页等待后重新获取同一页面
+            if let Some(pattern) = content_extract.soft_error(&paragraphs) {
+                let error = SoftError(pattern.as_str().to_string());
+                if retries >= self.retry.max {
+                    return Err(error.into());
+                }
+                let delay = self.retry.delay(retries);
+                warn!("{}, {:?} 后重试: {}", error, delay, next_url);
+                tokio::time::sleep(delay).await;
+                retries += 1;
+                continue;
+            }
+            retries = 0;
 
-            let title = match content_extract.extract_title(content) {
-                Value::Single(text) => text.trim().to_string(),
-                _ => chapter.title.clone(),
-            };
+            let hash = Sha256::digest(paragraphs.as_bytes());
+            let duplicate = prev_hash == Some(hash);
+            prev_hash = Some(hash);
 
-            if content_extract.matches_title(&chapter.title, &title) {
-                chapter_content.push_str(&paragraphs);
-            } else {
-                results.push(chapter_content);
-                chapter_content = String::new();
+            let expected = &titles[done];
+            if duplicate {
+                warn!(
+                    "页面内容与上一页相同, 跳过该页面: {} ({})",
+                    next_url, expected
+                );
+            } else if let Value::Single(title) = content_extract.extract_title(content)
+                && let title = strip_title(self.strip_title.as_ref(), &title)
+                && !content_extract.matches_title(expected, &title)
+            {
+                match titles.get(done + 1) {
+                    Some(next) if content_extract.matches_title(next, &title) => {
+                        info!("开始下一章节: {}", next);
+                        on_chapter(done, take(&mut chapter_content), next_url).await?;
+                        done += 1;
+                    }
+                    Some(next) => {
+                        warn!(
+                            "页面标题 '{}' 与当前章节 '{}' 和下一章节 '{}' 都不匹配, 视为当前章节的一部分, 请检查title配置: {}",
+                            title.trim(),
+                            expected,
+                            next,
+                            next_url
+                        );
+                    }
+                    None => {
+                        // 最后一个章节已经结束, 该页面属于后续的章节, 留给下一次调用处理
+                        info!("章节列表已处理完, 下一页面标题: {}", title.trim());
+                        on_chapter(done, chapter_content, next_url).await?;
+                        return Ok(done + 1);
+                    }
+                }
+            }
+            if !duplicate {
                 chapter_content.push_str(&paragraphs);
             }
 
-            *next_url = match content_extract.extract_next_url(content) {
+            *next_url = match content_extract.extract_next_url(content).trim() {
                 Value::Single(url) => self.url.join(&url)?.to_string(),
                 _ => {
-                    tracing::error!("无法提取下一章节URL，结束下载");
-                    return Ok(results);
-                },
+                    info!("没有下一页, 结束下载");
+                    next_url.clear();
+                    on_chapter(done, chapter_content, next_url).await?;
+                    return Ok(done + 1);
+                }
             };
+            if visited.contains(next_url.as_str()) {
+                warn!("下一页链接指向已访问的页面, 结束下载: {}", next_url);
+                next_url.clear();
+                on_chapter(done, chapter_content, next_url).await?;
+                return Ok(done + 1);
+            }
+            if visited.len() >= max_pages {
+                warn!(
+                    "已抓取 {} 个页面, 超过 {} 个章节的页数上限, 结束下载: {}",
+                    visited.len(),
+                    titles.len(),
+                    next_url
+                );
+                next_url.clear();
+                on_chapter(done, chapter_content, next_url).await?;
+                return Ok(done + 1);
+            }
 
-            // 后续添加retry中间件
             let sleep_time = rand::random::<u64>() % 2000 + 1000;
             tokio::time::sleep(Duration::from_millis(sleep_time)).await;
         }
-
-        Ok(results)
     }
 
-    pub fn new(site_name: &str, url: String) -> Self {
-        let config = get_site_config(site_name).expect("无法获取网站配置");
+    /// 返回页面的"上一页"链接, 用于发现目录中缺少的前面章节
+    pub async fn prev_url(&mut self, url: &str) -> Result<Option<String>> {
+        let config = self.config.clone();
+        let content_extract = &config.get_chapter_config().expect("没有章节配置").content;
+
+        let url = self.url.join(url)?;
+        let response = self.get_page(url.as_str()).await?;
+        let html = read_html(response).await?;
+        let html = scraper::Html::parse_document(&html);
+        let Some(content) = content_extract.this(html.root_element()) else {
+            return Ok(None);
+        };
+
+        match content_extract.extract_prev_url(content).trim() {
+            Value::Single(prev) => Ok(Some(url.join(&prev)?.to_string())),
+            _ => Ok(None),
+        }
+    }
 
-        let url = Url::parse(&url).expect("url解析错误");
+    pub fn new(config: Arc<SiteConfig>, url: &str, options: &Options) -> Result<Self> {
+        let url = Url::parse(url).with_context(|| format!("url解析错误: {}", url))?;
 
+        Self::add_cookies(&url, &options.cookie)?;
         let url = Arc::new(url);
+        let image_base = match &config.image_base_url {
+            Some(base) => Arc::new(
+                Url::parse(base).with_context(|| format!("image_base_url解析错误: {}", base))?,
+            ),
+            None => url.clone(),
+        };
 
-        let ua = ua_generator::ua::spoof_ua();
+        let ua = match &config.user_agent {
+            UserAgent::Random => ua_generator::ua::spoof_ua(),
+            UserAgent::Session => *SESSION_USER_AGENT,
+            UserAgent::Custom(ua) => ua.as_str(),
+        };
 
-        let mut client_builder = reqwest::Client::builder()
-            .user_agent(ua)
-            .referer(true)
-            .cookie_provider(JAR.clone());
+        // 命令行指定的代理代替网站配置中的代理
+        let proxies = if options.proxy.is_empty() {
+            &config.proxies
+        } else {
+            &options.proxy
+        };
+        let clients = if proxies.is_empty() {
+            vec![(
+                "直连".to_string(),
+                Self::proxy_client(&config, options, ua, None)?,
+            )]
+        } else {
+            proxies
+                .iter()
+                .map(|proxy| {
+                    let client = Self::proxy_client(&config, options, ua, Some(proxy))?;
+                    Ok((proxy.clone(), client))
+                })
+                .collect::<Result<_>>()?
+        };
 
-        if let Some(auth_config) = get_auth().get(site_name) {
-            match auth_config {
-                AuthType::Token(token) => {
-                    client_builder = client_builder.default_headers({
-                        let mut headers = reqwest::header::HeaderMap::new();
-                        headers.insert(
-                            reqwest::header::AUTHORIZATION,
-                            format!("Bearer {}", token)
-                                .parse()
-                                .expect("无法解析Authorization头"),
-                        );
-                        headers
-                    });
-                }
-                _ => {}
-            }
+        let mut retry = config.retry;
+        if let Some(max) = options.max_retries {
+            retry.max = max;
         }
-        let client = client_builder.build().expect("无法构建HTTP客户端");
 
-        let client = ServiceBuilder::new()
-            .buffer(64)
-            .rate_limit(
-                config.rate_limit.num,
-                Duration::from_secs(config.rate_limit.secs),
+        // 命令行参数优先于网站配置, 网站配置中没有设置时使用默认值
+        let rate_limit = options.rate.unwrap_or(config.rate_limit);
+        let concurrency_limit = options.concurrency.unwrap_or(config.concurrency_limit);
+
+        let budget = Arc::new(RetryBudget::new(retry.budget));
+        let pool = ProxyPool::new(clients);
+        let client = Self::http_client(
+            pool.clone(),
+            retry,
+            budget.clone(),
+            rate_limit,
+            concurrency_limit,
+        );
+        // 配置了图片的频率或并行数时, 图片使用单独的限制, 不与页面请求共用
+        let separate_images =
+            config.image_rate_limit.is_some() || config.image_concurrency.is_some();
+        let image_client = if separate_images {
+            Self::http_client(
+                pool,
+                retry,
+                budget.clone(),
+                config.image_rate_limit.unwrap_or(rate_limit),
+                config.image_concurrency.unwrap_or(concurrency_limit),
             )
-            .concurrency_limit(config.concurrency_limit)
-            .layer(HttpClientLayer) 
-            .service(client)
-            .map_err(|e| {
-                error!("HTTP请求失败: {}", e);
-                anyhow::anyhow!("HTTP请求失败: {}", e)
-            })
-            .boxed_clone();
+        } else {
+            client.clone()
+        };
+
+        let cache = options
+            .cache
+            .clone()
+            .map(|dir| Arc::new(ResponseCache::new(dir)));
+
+        let dump_dir = options.dump_html.clone().map(Arc::new);
 
-        Self {
+        let strip_title = options
+            .strip_chapter_title_prefix
+            .clone()
+            .or_else(|| config.get_book_config().strip_title.clone());
+
+        Ok(Self {
             client,
+            image_client,
+            cache,
+            dump_dir,
             url,
+            image_base,
             config,
+            retry,
+            budget,
+            rate_limit,
+            concurrency_limit,
+            strip_title,
+            image_progress: !options.quiet && std::io::stderr().is_terminal(),
+        })
+    }
+
+    /// 在代理池外加上重试、频率和并行数限制
+    fn http_client(
+        pool: ProxyPool,
+        retry: Retry,
+        budget: Arc<RetryBudget>,
+        rate_limit: RateLimit,
+        concurrency_limit: usize,
+    ) -> HttpClient {
+        ServiceBuilder::new()
+            .retry(RetryPolicy::new(retry, budget.clone()))
+            .buffer(64)
+            .rate_limit(rate_limit.num, Duration::from_secs(rate_limit.secs))
+            .concurrency_limit(concurrency_limit)
+            // 在重试层之内读取完整的响应体, 读取中途失败时也会重试整个请求
+            .layer_fn(|inner| ReadBody { inner })
+            .service(pool)
+            .map_err(move |e| {
+                let mut e = anyhow::Error::new(RequestError::new(e));
+                if budget.is_exhausted() {
+                    e = e.context("网站似乎无法访问(已达到 retry.budget 的重试次数上限)");
+                }
+                error!("{:#}", e);
+                e
+            })
+            .boxed_clone()
+    }
+
+    /// 把命令行指定的cookie加入cookie jar; 与 config.toml 中的cookie一样设置在网站根路径上,
+    /// 同名的cookie会被覆盖
+    fn add_cookies(url: &Url, cookies: &[String]) -> Result<()> {
+        if cookies.is_empty() {
+            return Ok(());
+        }
+        let origin = url.join("/")?;
+        for cookie in cookies {
+            if !cookie.contains('=') {
+                anyhow::bail!("cookie格式错误, 应为 name=value: {}", cookie);
+            }
+            JAR.add_cookie_str(cookie, &origin);
+        }
+        info!("已添加 {} 个命令行指定的cookie", cookies.len());
+        Ok(())
+    }
+
+    /// 构建通过指定代理(或直连)发送请求的客户端
+    fn proxy_client(
+        config: &SiteConfig,
+        options: &Options,
+        ua: &str,
+        proxy: Option<&str>,
+    ) -> Result<ProxyClient> {
+        let mut client_builder = reqwest::Client::builder()
+            .user_agent(ua)
+            .referer(true)
+            .cookie_provider(JAR.clone());
+
+        if let Some(proxy) = proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .with_context(|| format!("代理地址解析错误: {}", proxy))?;
+            client_builder = client_builder.proxy(proxy);
+        }
+
+        if let Some(max_idle) = config.pool_max_idle_per_host {
+            client_builder = client_builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(idle_timeout) = config.pool_idle_timeout {
+            client_builder = client_builder.pool_idle_timeout(Duration::from_secs(idle_timeout));
+        }
+        if let Some(timeout) = options.timeout {
+            client_builder = client_builder.timeout(Duration::from_secs(timeout));
+        }
+        client_builder =
+            client_builder.redirect(reqwest::redirect::Policy::custom(redirect_policy));
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(AuthType::Token(token)) = get_auth().get(&config.name) {
+            headers.insert(
+                reqwest::header::AUTHORIZATION,
+                format!("Bearer {}", token)
+                    .parse()
+                    .context("无法解析Authorization头")?,
+            );
+        }
+        // 命令行指定的请求头最后加入, 覆盖同名的请求头(包括User-Agent)
+        for header in &options.header {
+            let (name, value) = header
+                .split_once(':')
+                .with_context(|| format!("请求头格式错误, 应为 \"Name: Value\": {}", header))?;
+            headers.insert(
+                reqwest::header::HeaderName::from_bytes(name.trim().as_bytes())
+                    .with_context(|| format!("无法解析请求头名称: {}", name))?,
+                value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("无法解析请求头的值: {}", header))?,
+            );
+        }
+        if !headers.is_empty() {
+            client_builder = client_builder.default_headers(headers);
         }
+        let client = client_builder.build().context("无法构建HTTP客户端")?;
+
+        Ok(ProxyClient::new(
+            ServiceBuilder::new()
+                .layer(HttpClientLayer)
+                .service(client)
+                .map_err(BoxError::from),
+        ))
+    }
+
+    pub fn config(&self) -> &SiteConfig {
+        &self.config
+    }
+
+    /// 网站配置中的重试设置, 已应用 --max-retries
+    pub fn retry(&self) -> Retry {
+        self.retry
+    }
+
+    /// 实际使用的请求频率, 指定 --rate 时与网站配置不同
+    pub fn rate_limit(&self) -> RateLimit {
+        self.rate_limit
+    }
+
+    /// 实际使用的最大并行请求数, 指定 --concurrency 时与网站配置不同
+    pub fn concurrency_limit(&self) -> usize {
+        self.concurrency_limit
     }
 
-    #[instrument(skip_all)]
+    /// 获取小说页面; 作为整个抓取的入口, 页面中没有小说元素(验证页面等)时额外重试几次,
+    /// 这些重试也计入 retry.budget; 仍然没有小说元素时返回页面, 由解析时给出具体的错误.
+    /// 请求失败时重试层已经重试过, 直接返回错误
+    #[instrument(skip_all, fields(url = %self.url))]
     pub async fn novel_info(&mut self) -> Result<String> {
         info!("正在获取: {}", self.url);
 
-        let response = self.client.get(self.url.as_str()).send().await?;
-        let html_content = response.body_reader().utf8().await?;
+        let url = self.url.clone();
+        let config = self.config.clone();
+        let book_extractor = config.get_book_config();
+        let max_retries = self.retry.max.min(NOVEL_INFO_RETRIES);
+        let mut retries = 0;
+        loop {
+            let response = self
+                .get_page(url.as_str())
+                .await
+                .map_err(|e| Self::novel_info_error(e, &url))?;
+            if response.status() == StatusCode::NOT_FOUND {
+                anyhow::bail!("小说页面不存在(HTTP 404), 请检查id或url: {}", url);
+            }
+            let html = read_html(response)
+                .await
+                .map_err(|e| Self::novel_info_error(e, &url))?;
+            let has_book = book_extractor
+                .this(scraper::Html::parse_document(&html).root_element())
+                .is_some();
+            if has_book || retries >= max_retries || !self.budget.withdraw() {
+                return Ok(html);
+            }
 
-        Ok(html_content)
+            let delay = self.retry.delay(retries);
+            retries += 1;
+            warn!(
+                "页面中没有找到小说元素, {} 毫秒后第 {} 次重试: {}",
+                delay.as_millis(),
+                retries,
+                url
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// 获取小说页面失败时的错误; DNS解析失败、证书错误等重试也不会成功, 给出具体的原因
+    fn novel_info_error(error: anyhow::Error, url: &Url) -> anyhow::Error {
+        let kind = error
+            .chain()
+            .find_map(|e| e.downcast_ref::<RequestError>())
+            .map(|e| e.kind);
+        match kind {
+            Some(kind) if !kind.is_retryable() => {
+                error.context(format!("{}, 请检查网络连接、代理或url: {}", kind, url))
+            }
+            _ => error.context(format!("无法访问网站, 请检查网络连接、代理或url: {}", url)),
+        }
+    }
+
+    /// 网站配置了 chapter_list_url 时获取章节列表页
+    #[instrument(skip_all, fields(url = %self.url))]
+    pub async fn chapter_list(&mut self) -> Result<Option<String>> {
+        let book_extractor = self.config.get_book_config();
+        let Some(url) = book_extractor.build_chapter_list_url(&self.url) else {
+            return Ok(None);
+        };
+        let url = url.context("无法生成章节列表页的url")?;
+        info!("正在获取章节列表: {}", url);
+
+        let response = self.get_page(url.as_str()).await?;
+        let html_content = read_html(response).await?;
+
+        Ok(Some(html_content))
+    }
+
+    async fn get_page(&mut self, url: &str) -> Result<Response<Body>> {
+        let response = self.fetch_page(url).await?;
+        self.dump_page(url, response).await
+    }
+
+    /// 用POST请求获取页面, 不使用缓存
+    async fn post_page(
+        &mut self,
+        url: &str,
+        post: &PostRequest,
+        body: String,
+    ) -> Result<Response<Body>> {
+        let mut request = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, post.content_type.as_str());
+        if let Some(referer) = Self::page_referer(&self.config, &self.url) {
+            request = request.header("Referer", referer);
+        }
+        let response = request.body(body)?.send().await?;
+        self.dump_page(url, response).await
+    }
+
+    /// 设置了 --dump-html 时保存页面
+    async fn dump_page(&mut self, url: &str, response: Response<Body>) -> Result<Response<Body>> {
+        let Some(dump_dir) = &self.dump_dir else {
+            return Ok(response);
+        };
+
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await?.to_bytes();
+        let path = dump_dir.join(format!("{}.html", ResponseCache::key(url)));
+        let dumped = async {
+            tokio::fs::create_dir_all(dump_dir.as_path()).await?;
+            tokio::fs::write(&path, &body).await
+        };
+        match dumped.await {
+            Ok(()) => debug!("已保存页面: {} -> {}", url, path.display()),
+            Err(e) => error!("保存页面失败: {}: {}", url, e),
+        }
+        Ok(Response::from_parts(parts, Body::from(body)))
     }
 
-    #[instrument(skip_all)]
+    async fn fetch_page(&mut self, url: &str) -> Result<Response<Body>> {
+        let cached = match &self.cache {
+            Some(cache) => cache.load(url).await,
+            None => None,
+        };
+
+        let mut request = self.client.get(url);
+        if let Some(referer) = Self::page_referer(&self.config, &self.url) {
+            request = request.header("Referer", referer);
+        }
+        if let Some(cached) = &cached {
+            if let Some(etag) = &cached.meta.etag {
+                request = request.header(IF_NONE_MATCH, etag.as_str());
+            }
+            if let Some(last_modified) = &cached.meta.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified.as_str());
+            }
+        }
+        let response = request.send().await?;
+
+        let Some(cache) = &self.cache else {
+            return Ok(response);
+        };
+
+        if let (StatusCode::NOT_MODIFIED, Some(cached)) = (response.status(), cached) {
+            info!("页面未修改, 使用缓存: {}", url);
+            let mut response = Response::builder();
+            if let Some(content_type) = &cached.meta.content_type {
+                response = response.header(CONTENT_TYPE, content_type.as_str());
+            }
+            return Ok(response.body(Body::from(cached.body))?);
+        }
+
+        if response.status() != StatusCode::OK {
+            return Ok(response);
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        let meta = CacheMeta {
+            url: url.to_owned(),
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            content_type: header(CONTENT_TYPE),
+        };
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await?.to_bytes();
+        cache.store(&body, &meta).await;
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+
+    /// 章节页是跳转用的中间页时返回阅读页的链接,
+    /// 优先使用配置的 reader_url, 其次是 <meta http-equiv="refresh">
+    fn reader_url(&self, html: &str) -> Option<String> {
+        let document = scraper::Html::parse_document(html);
+        let content_extract = &self.config.get_chapter_config()?.content;
+        if let Value::Single(url) = content_extract
+            .extract_reader_url(document.root_element())
+            .trim()
+        {
+            return Some(url);
+        }
+        // 页面中已经有章节内容时不跟随刷新跳转
+        let has_content = content_extract
+            .this(document.root_element())
+            .is_some_and(|content| content_extract.extract_paragraphs(content) != Value::Empty);
+        if has_content {
+            return None;
+        }
+        meta_refresh_url(&document)
+    }
+
+    /// 解析页面中的链接: 绝对链接直接解析, 协议相对链接(//host/...)沿用 base 的协议,
+    /// 只有相对链接才基于 base 拼接
+    fn resolve_url(base: &Url, url: &str) -> Result<Url> {
+        let url = url.trim();
+        if let Some(rest) = url.strip_prefix("//") {
+            return Ok(Url::parse(&format!("{}://{}", base.scheme(), rest))?);
+        }
+        match Url::parse(url) {
+            Ok(url) => Ok(url),
+            Err(url::ParseError::RelativeUrlWithoutBase) => Ok(base.join(url)?),
+            Err(e) => Err(anyhow::anyhow!("无法解析链接 {}: {}", url, e)),
+        }
+    }
+
+    fn page_referer<'a>(config: &'a SiteConfig, url: &'a Url) -> Option<&'a str> {
+        match &config.page_referer {
+            Referer::None => None,
+            Referer::Url => Some(url.as_str()),
+            Referer::Host => Some(config.host.as_deref().unwrap_or(url.as_str())),
+            Referer::Custom(referer) => Some(referer),
+        }
+    }
+
+    #[instrument(skip_all, fields(url = %image_url))]
     pub async fn image(&mut self, image_url: &str) -> Result<(Bytes, String)> {
-        let image_url = self.url.join(image_url)?;
+        // 内嵌在页面中的图片直接解码, 不需要请求
+        if image_url.starts_with("data:") {
+            return decode_data_uri(image_url);
+        }
+
+        let image_url = Self::resolve_url(&self.image_base, image_url)?;
         info!("下载图片: {}", image_url);
         // 从URL中提取文件扩展名
         let extension = Path::new(image_url.path())
@@ -178,31 +691,129 @@ impl Downloader {
             self.url.as_str()
         };
 
-        // 下载图片
-        let response = self.client
-            .get(image_url.as_str())
-            .header("Referer", referer)
-            .send().await?;
-
-        let image_bytes = response.body_reader().bytes().await?;
+        // 下载图片, 响应体在重试层之外按块读取, 读取中途失败时重新请求
+        let mut retries = 0;
+        let image_bytes = loop {
+            let mut request = Request::get(image_url.as_str())
+                .header("Referer", referer)
+                .body(Body::default())?;
+            request.extensions_mut().insert(StreamBody);
+            let response = self.image_client.clone().oneshot(request).await?;
+            // 重定向到登录页或首页时 redirect_policy 停止跟随, 返回的重定向响应没有图片
+            if response.status().is_redirection() {
+                anyhow::bail!(
+                    "图片被重定向到 {}, 可能需要登录: {}",
+                    redirect_location(&response, &image_url),
+                    image_url
+                );
+            }
+            // 重试用完后返回的错误页不是图片, 交给调用方按下载失败处理
+            if !response.status().is_success() {
+                anyhow::bail!("图片请求失败, HTTP {}: {}", response.status(), image_url);
+            }
+            match Self::read_image(response, &image_url, self.image_progress).await {
+                Ok(image_bytes) => break image_bytes,
+                Err(e) if retries < self.retry.max => {
+                    let delay = self.retry.delay(retries);
+                    warn!("图片下载中断, {:?} 后重试: {}: {:#}", delay, image_url, e);
+                    tokio::time::sleep(delay).await;
+                    retries += 1;
+                }
+                Err(e) => return Err(e.context(format!("图片下载失败: {}", image_url))),
+            }
+        };
 
         info!("图片下载成功: {} KB", image_bytes.len() / 1024);
 
         Ok((image_bytes, extension.to_owned()))
     }
 
-    #[instrument(skip_all)]
+    /// 按块读取图片的响应体; show_progress 为true且 Content-Length 达到 IMAGE_PROGRESS_BYTES 时显示下载进度
+    async fn read_image(
+        response: Response<Body>,
+        image_url: &Url,
+        show_progress: bool,
+    ) -> Result<Bytes> {
+        let total = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+        let progress = match total {
+            Some(total) if show_progress && total >= IMAGE_PROGRESS_BYTES => {
+                let name = image_url.path_segments().and_then(|mut s| s.next_back());
+                Some(logger::progress().add(image_progress_bar(
+                    total,
+                    name.unwrap_or(image_url.as_str()),
+                )))
+            }
+            _ => None,
+        };
+
+        let mut body = response.into_body();
+        let mut image_bytes = Vec::with_capacity(total.unwrap_or(0).min(IMAGE_CAPACITY) as usize);
+        while let Some(frame) = body.frame().await {
+            if let Ok(data) = frame?.into_data() {
+                image_bytes.extend_from_slice(&data);
+                if let Some(progress) = &progress {
+                    progress.inc(data.len() as u64);
+                }
+            }
+        }
+        if let Some(progress) = progress {
+            progress.finish_and_clear();
+        }
+        Ok(Bytes::from(image_bytes))
+    }
+
+    #[instrument(skip_all, fields(url = %chapter_url))]
     pub async fn chapter(&mut self, chapter_url: &str) -> Result<String> {
         let chapter_url = self.url.join(chapter_url)?;
 
         // 请求过多（429）会被限制访问，需要控制访问频率或者使用代理
         info!("正在获取章节内容: {}", chapter_url);
 
-        let response = self.client.get(chapter_url.as_str()).send().await?;
+        let config = self.config.clone();
+        let post = config
+            .get_chapter_config()
+            .and_then(|chapter| chapter.content.post.as_ref());
+        let mut chapter_url = chapter_url;
+        let mut response = match post {
+            Some(post) => {
+                let (url, body) = post.build(&chapter_url)?;
+                debug!("POST {}: {}", url, body);
+                self.post_page(url.as_str(), post, body).await?
+            }
+            None => self.get_page(chapter_url.as_str()).await?,
+        };
+        for _ in 0..MAX_READER_REDIRECTS {
+            if response.status() != StatusCode::OK {
+                break;
+            }
+            let html_content = read_html(response).await?;
+            let Some(reader_url) = self.reader_url(&html_content) else {
+                return Ok(html_content);
+            };
+            let reader_url = chapter_url.join(&reader_url)?;
+            // 阅读页本身也匹配 reader_url 时链接指向当前页面, 当前页面就是阅读页
+            if reader_url == chapter_url {
+                return Ok(html_content);
+            }
+            chapter_url = reader_url;
+            info!("跳转到阅读页: {}", chapter_url);
+            response = self.get_page(chapter_url.as_str()).await?;
+        }
+
         match response.status() {
             StatusCode::OK => {
                 info!("章节内容获取成功");
             }
+            status if status.is_redirection() => {
+                return Err(anyhow::anyhow!(
+                    "章节页面被重定向到 {}, 可能需要登录或章节已移动: {}",
+                    redirect_location(&response, &chapter_url),
+                    chapter_url
+                ));
+            }
             StatusCode::TOO_MANY_REQUESTS => {
                 let Some(retry_after) = response.headers().get("Retry-After") else {
                     return Err(anyhow::anyhow!("无法获取重试时间"));
@@ -218,8 +829,572 @@ impl Downloader {
                 return Err(anyhow::anyhow!("HTTP错误 {}", status));
             }
         }
-        let html_content = response.body_reader().utf8().await?;
+        let html_content = read_html(response).await?;
 
         Ok(html_content)
     }
 }
+
+/// 读取响应中的HTML并转换为UTF-8, 去掉开头的BOM
+async fn read_html(response: Response<Body>) -> Result<String> {
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let mut bytes = response.body_reader().bytes().await?;
+    // 部分服务器返回gzip压缩的内容却没有 Content-Encoding, 只在无法按UTF-8解码时检查
+    if bytes.starts_with(GZIP_MAGIC) && std::str::from_utf8(&bytes).is_err() {
+        let mut decompressed = Vec::new();
+        match GzDecoder::new(&bytes[..]).read_to_end(&mut decompressed) {
+            Ok(_) => {
+                warn!("响应体是没有声明 Content-Encoding 的gzip数据, 已解压");
+                bytes = Bytes::from(decompressed);
+            }
+            Err(e) => debug!("响应体以gzip标识开头, 但解压失败: {}", e),
+        }
+    }
+    Ok(decode_html(&bytes, content_type.as_deref()))
+}
+
+/// gzip数据开头的两个字节
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// 按 BOM、Content-Type 中的 charset、页面开头的 <meta charset> 的顺序确定编码, 都没有时按UTF-8解码
+fn decode_html(bytes: &[u8], content_type: Option<&str>) -> String {
+    let encoding = Encoding::for_bom(bytes)
+        .map(|(encoding, _)| encoding)
+        .or_else(|| content_type.and_then(charset_encoding))
+        .or_else(|| meta_charset(bytes))
+        .unwrap_or(UTF_8);
+    if encoding != UTF_8 {
+        debug!("页面编码: {}", encoding.name());
+    }
+    let (html, _, had_errors) = encoding.decode(bytes);
+    if had_errors {
+        warn!("页面中有无法按 {} 解码的字符", encoding.name());
+    }
+    html.into_owned()
+}
+
+/// 从 "text/html; charset=gbk" 这样的值中取出编码
+fn charset_encoding(value: &str) -> Option<&'static Encoding> {
+    let lower = value.to_ascii_lowercase();
+    let (_, charset) = lower.split_once("charset=")?;
+    let charset = charset
+        .trim_start_matches(['"', '\''])
+        .split(|c: char| c == ';' || c == '"' || c == '\'' || c.is_whitespace() || c == '>')
+        .next()?;
+    Encoding::for_label(charset.as_bytes())
+}
+
+/// 在页面开头查找 <meta charset="..."> 或 <meta http-equiv="Content-Type" content="...; charset=...">
+fn meta_charset(bytes: &[u8]) -> Option<&'static Encoding> {
+    const META_SCAN_BYTES: usize = 1024;
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(META_SCAN_BYTES)]);
+    head.split('<')
+        .filter(|tag| {
+            tag.get(..4)
+                .is_some_and(|name| name.eq_ignore_ascii_case("meta"))
+        })
+        .find_map(charset_encoding)
+}
+
+/// user_agent = "session" 时本次运行共用的User-Agent
+static SESSION_USER_AGENT: LazyLock<&'static str> = LazyLock::new(ua_generator::ua::spoof_ua);
+
+/// 从中间页跳转到阅读页的最大次数
+const MAX_READER_REDIRECTS: usize = 3;
+
+/// 一个请求最多跟随的HTTP重定向次数, 与reqwest的默认策略相同
+const MAX_HTTP_REDIRECTS: usize = 10;
+
+/// 重定向目标的路径或查询参数中包含这些词时, 认为跳转到了登录页
+const LOGIN_KEYWORDS: &[&str] = &["login", "signin", "sign_in", "sign-in", "register"];
+
+/// 跟随HTTP重定向, 但目标像是登录页或网站首页时停止跟随, 返回重定向响应,
+/// 避免把登录页、"章节已移动"的提示页当作正文保存; 图片请求也使用该策略, 停止跟随时下载失败
+fn redirect_policy(attempt: reqwest::redirect::Attempt) -> reqwest::redirect::Action {
+    if attempt.previous().len() >= MAX_HTTP_REDIRECTS {
+        return attempt.error("重定向次数过多");
+    }
+    let Some(from) = attempt.previous().first() else {
+        return attempt.follow();
+    };
+    let target = attempt.url();
+    let path = format!("{}?{}", target.path(), target.query().unwrap_or_default()).to_lowercase();
+    let to_login = LOGIN_KEYWORDS.iter().any(|keyword| path.contains(keyword));
+    let to_home = target.path() == "/" && from.path() != "/";
+    if to_login || to_home {
+        warn!("{} 被重定向到 {}, 可能需要登录或页面已移动", from, target);
+        return attempt.stop();
+    }
+    attempt.follow()
+}
+
+/// 重定向响应的目标url, 没有 Location 头时返回"未知"
+fn redirect_location(response: &Response<Body>, url: &Url) -> String {
+    response
+        .headers()
+        .get(LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .and_then(|location| url.join(location).ok())
+        .map_or("未知".to_string(), |url| url.to_string())
+}
+
+/// 解析 <meta http-equiv="refresh" content="0; url=..."> 中的链接
+fn meta_refresh_url(document: &scraper::Html) -> Option<String> {
+    let selector =
+        scraper::Selector::parse("meta[http-equiv][content]").expect("无法创建meta选择器");
+    let content = document
+        .select(&selector)
+        .find(|meta| {
+            meta.value()
+                .attr("http-equiv")
+                .is_some_and(|v| v.eq_ignore_ascii_case("refresh"))
+        })?
+        .value()
+        .attr("content")?;
+
+    let (_, url) = content.split_once(';')?;
+    let url = url.trim();
+    let (key, url) = url.split_once('=')?;
+    if !key.trim().eq_ignore_ascii_case("url") {
+        return None;
+    }
+    let url = url.trim().trim_matches(|c| c == '\'' || c == '"');
+    (!url.is_empty()).then(|| url.to_owned())
+}
+
+/// 解码 data:image/png;base64,... 形式的图片, 扩展名由MIME类型得到
+fn decode_data_uri(uri: &str) -> Result<(Bytes, String)> {
+    let (header, data) = uri
+        .strip_prefix("data:")
+        .and_then(|uri| uri.split_once(','))
+        .ok_or_else(|| anyhow::anyhow!("data URI格式错误"))?;
+
+    let Some(mime) = header.strip_suffix(";base64") else {
+        anyhow::bail!("不支持非base64编码的data URI: {}", header);
+    };
+    let mime = mime.split(';').next().unwrap_or_default();
+    let extension = match mime {
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/svg+xml" => "svg",
+        _ => mime.strip_prefix("image/").unwrap_or("jpg"),
+    };
+
+    let image_bytes = BASE64_STANDARD
+        .decode(data.trim())
+        .context("data URI的base64解码失败")?;
+    info!("解码内嵌图片: {} 字节", image_bytes.len());
+
+    Ok((Bytes::from(image_bytes), extension.to_owned()))
+}
+
+/// 读取完整的响应体, 读取中途失败时返回错误, 由外面的重试层重试整个请求
+async fn read_body(response: Response<Body>) -> Result<Response<Body>, BoxError> {
+    let (parts, body) = response.into_parts();
+    let bytes = body.collect().await?.to_bytes();
+    Ok(Response::from_parts(parts, Body::from(bytes)))
+}
+
+/// 请求扩展: 响应体不在重试层之内读取, 由调用方按块读取(例如显示图片的下载进度)
+#[derive(Clone, Copy)]
+struct StreamBody;
+
+/// 在重试层之内用 read_body 读取完整的响应体, 带有 StreamBody 扩展的请求除外
+#[derive(Clone)]
+struct ReadBody<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for ReadBody<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let stream = req.extensions().get::<StreamBody>().is_some();
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let response = response.await?;
+            if stream {
+                return Ok(response);
+            }
+            read_body(response).await
+        })
+    }
+}
+
+/// Content-Length 达到该大小的图片在下载时显示进度条
+const IMAGE_PROGRESS_BYTES: u64 = 1024 * 1024;
+
+/// 按 Content-Length 预先分配的最大容量, 避免错误的响应头导致分配过多内存
+const IMAGE_CAPACITY: u64 = 64 * 1024 * 1024;
+
+fn image_progress_bar(total: u64, name: &str) -> ProgressBar {
+    let style = ProgressStyle::with_template("{msg} [{bar:30}] {bytes}/{total_bytes}")
+        .expect("进度条模板错误")
+        .progress_chars("=> ");
+    ProgressBar::new(total)
+        .with_style(style)
+        .with_message(format!("图片 {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+
+    use http_body::Frame;
+    use tower::{Service, service_fn};
+
+    use super::*;
+    use crate::epub::test_util::{BOOK, TempDir, site_config};
+
+    /// 发送一部分内容后连接被重置的响应体
+    #[derive(Default)]
+    struct TruncatedBody {
+        sent: bool,
+    }
+
+    impl http_body::Body for TruncatedBody {
+        type Data = Bytes;
+        type Error = std::io::Error;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, std::io::Error>>> {
+            if self.sent {
+                return Poll::Ready(Some(Err(std::io::ErrorKind::ConnectionReset.into())));
+            }
+            self.sent = true;
+            Poll::Ready(Some(Ok(Frame::data(Bytes::from_static(b"<html>")))))
+        }
+    }
+
+    /// 分成多块发送的响应体, 从后往前依次发送 Vec 中的各块
+    struct ChunkedBody(Vec<Bytes>);
+
+    impl http_body::Body for ChunkedBody {
+        type Data = Bytes;
+        type Error = std::io::Error;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, std::io::Error>>> {
+            Poll::Ready(self.0.pop().map(|chunk| Ok(Frame::data(chunk))))
+        }
+    }
+
+    /// 用只包含必要字段的网站配置创建Downloader, site_options 加入网站配置的顶层
+    fn downloader(site_options: &str) -> Downloader {
+        let config = site_config(&format!("{}\n{}", site_options, BOOK));
+        Downloader::new(config, "https://example.com/novel/12/", &Options::default()).unwrap()
+    }
+
+    #[test]
+    fn resolve_image_urls() {
+        let base = Url::parse("https://example.com/novel/12/").unwrap();
+        let resolve = |url: &str| Downloader::resolve_url(&base, url).unwrap().to_string();
+
+        assert_eq!(
+            resolve("https://cdn.example.net/a.jpg"),
+            "https://cdn.example.net/a.jpg"
+        );
+        assert_eq!(
+            resolve("//cdn.example.net/a.jpg"),
+            "https://cdn.example.net/a.jpg"
+        );
+        assert_eq!(resolve("/img/a.jpg"), "https://example.com/img/a.jpg");
+        assert_eq!(
+            resolve("img/a.jpg"),
+            "https://example.com/novel/12/img/a.jpg"
+        );
+        // 链接两侧的空白不影响解析
+        assert_eq!(resolve(" ../a.jpg\n"), "https://example.com/novel/a.jpg");
+    }
+
+    #[tokio::test]
+    async fn cover_resolved_against_image_base_url() {
+        let downloader = downloader(r#"image_base_url = "https://img.example.net/covers/""#);
+        let resolve = |url: &str| {
+            Downloader::resolve_url(&downloader.image_base, url)
+                .unwrap()
+                .to_string()
+        };
+
+        // 相对链接基于 image_base_url 而不是小说页面
+        assert_eq!(resolve("12.jpg"), "https://img.example.net/covers/12.jpg");
+        assert_eq!(resolve("/12.jpg"), "https://img.example.net/12.jpg");
+        assert_eq!(
+            resolve("https://example.com/12.jpg"),
+            "https://example.com/12.jpg"
+        );
+    }
+
+    #[tokio::test]
+    async fn image_error_status_rejected() {
+        let mut downloader = downloader("");
+        downloader.image_client = service_fn(|request: Request<Body>| async move {
+            if request.uri().path() == "/login.jpg" {
+                let response = Response::builder()
+                    .status(StatusCode::FOUND)
+                    .header(LOCATION, "/login?next=/login.jpg")
+                    .body(Body::default())
+                    .unwrap();
+                return Ok(response);
+            }
+            let (status, body) = match request.uri().path() {
+                "/404.jpg" => (StatusCode::NOT_FOUND, "<html>错误页</html>".as_bytes()),
+                "/503.jpg" => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "<html>错误页</html>".as_bytes(),
+                ),
+                _ => (StatusCode::OK, b"\x89PNG".as_slice()),
+            };
+            let response = Response::builder()
+                .status(status)
+                .body(Body::from(body))
+                .unwrap();
+            Ok::<_, anyhow::Error>(response)
+        })
+        .boxed_clone();
+
+        // 错误页和停在登录页的重定向不能当作图片保存
+        let error = downloader.image("/login.jpg").await.unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("https://example.com/login?next=/login.jpg")
+        );
+        assert!(downloader.image("/404.jpg").await.is_err());
+        assert!(downloader.image("/503.jpg").await.is_err());
+        let (bytes, extension) = downloader.image("/a.png").await.unwrap();
+        assert_eq!(bytes.as_ref(), b"\x89PNG");
+        assert_eq!(extension, "png");
+    }
+
+    #[test]
+    fn meta_refresh_landing_page() {
+        let landing = scraper::Html::parse_document(
+            r#"<html><head><meta http-equiv="Refresh" content="0; URL='/read/12/34.html'"></head>
+            <body>正在跳转到阅读页...</body></html>"#,
+        );
+        assert_eq!(
+            meta_refresh_url(&landing).as_deref(),
+            Some("/read/12/34.html")
+        );
+
+        // 只有刷新时间没有链接的meta只是定时刷新, 不是跳转
+        let reload = scraper::Html::parse_document(r#"<meta http-equiv="refresh" content="30">"#);
+        assert_eq!(meta_refresh_url(&reload), None);
+    }
+
+    #[tokio::test]
+    async fn retries_when_body_is_truncated() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let server = service_fn(move |_request: Request<Body>| {
+            let attempt = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let body = match attempt {
+                    0 => Body::wrap(TruncatedBody::default()),
+                    _ => Body::from("<html>完整的页面</html>"),
+                };
+                Ok::<_, BoxError>(Response::new(body))
+            }
+        });
+        let retry = Retry {
+            max: 2,
+            delay_ms: 0,
+            budget: None,
+        };
+        let mut client = ServiceBuilder::new()
+            .retry(RetryPolicy::new(retry, Arc::new(RetryBudget::new(None))))
+            .layer_fn(|inner| ReadBody { inner })
+            .service(server);
+
+        let response = client
+            .ready()
+            .await
+            .unwrap()
+            .call(Request::new(Body::from("")))
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "<html>完整的页面</html>");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    /// 返回验证页面(没有小说元素)或DNS解析失败的服务, 记录请求次数
+    fn novel_server(attempts: Arc<AtomicUsize>, dns_error: bool) -> HttpClient {
+        service_fn(move |_request: Request<Body>| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if dns_error {
+                    let source = std::io::Error::other("dns error: failed to lookup address");
+                    return Err(anyhow::Error::new(RequestError::new(source.into())));
+                }
+                Ok(Response::new(Body::from("<html>验证页面</html>")))
+            }
+        })
+        .boxed_clone()
+    }
+
+    #[tokio::test]
+    async fn novel_info_retries_only_missing_book() {
+        // 没有小说元素时额外重试, 不超过 NOVEL_INFO_RETRIES
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut novel = downloader("retry = { max = 3, delay_ms = 0 }");
+        novel.client = novel_server(attempts.clone(), false);
+        let html = novel.novel_info().await.unwrap();
+        assert!(html.contains("验证页面"));
+        assert_eq!(
+            attempts.load(Ordering::SeqCst),
+            1 + NOVEL_INFO_RETRIES as usize
+        );
+
+        // 额外的重试从 retry.budget 中取出
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut novel = downloader("retry = { max = 3, delay_ms = 0, budget = 1 }");
+        novel.client = novel_server(attempts.clone(), false);
+        novel.novel_info().await.unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // DNS解析失败时不再重试
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut novel = downloader("retry = { max = 3, delay_ms = 0 }");
+        novel.client = novel_server(attempts.clone(), true);
+        let error = novel.novel_info().await.unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("DNS解析失败"),
+            "{:#}",
+            error
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn stream_body_read_in_chunks() {
+        let server = service_fn(|request: Request<Body>| async move {
+            let body = match request.uri().path() {
+                "/truncated.jpg" => Body::wrap(TruncatedBody::default()),
+                _ => Body::wrap(ChunkedBody(vec![
+                    Bytes::from_static(b"\xff\xd9"),
+                    Bytes::from_static(b"\xff\xd8"),
+                ])),
+            };
+            let response = Response::builder()
+                .header(CONTENT_LENGTH, "4")
+                .body(body)
+                .unwrap();
+            Ok::<_, BoxError>(response)
+        });
+        let mut client = ReadBody { inner: server };
+        let request = |path: &str, stream: bool| {
+            let mut request = Request::get(path).body(Body::default()).unwrap();
+            if stream {
+                request.extensions_mut().insert(StreamBody);
+            }
+            request
+        };
+
+        // 带有 StreamBody 的请求由调用方按块读取, 读取中断时返回错误而不是在服务中重试
+        let response = client.call(request("/a.jpg", true)).await.unwrap();
+        let url = Url::parse("https://example.com/a.jpg").unwrap();
+        let bytes = Downloader::read_image(response, &url, true).await.unwrap();
+        assert_eq!(bytes.as_ref(), b"\xff\xd8\xff\xd9");
+
+        let response = client.call(request("/truncated.jpg", true)).await.unwrap();
+        assert!(Downloader::read_image(response, &url, false).await.is_err());
+
+        // 其他请求在服务中读取完整的响应体
+        assert!(client.call(request("/truncated.jpg", false)).await.is_err());
+    }
+
+    #[test]
+    fn decode_png_data_uri() {
+        let (bytes, extension) = decode_data_uri(
+            "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg==",
+        )
+        .unwrap();
+        assert_eq!(extension, "png");
+        assert!(bytes.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(crate::utils::image_size(&bytes), Some((1, 1)));
+
+        assert!(decode_data_uri("data:image/png,not-base64").is_err());
+    }
+
+    #[test]
+    fn decode_gbk_page() {
+        let body = "<p>第一章 开始</p>";
+        let (gbk, _, _) = encoding_rs::GBK.encode(body);
+
+        // Content-Type 中的 charset
+        assert_eq!(decode_html(&gbk, Some("text/html; charset=GBK")), body);
+
+        // 页面开头的 <meta charset>
+        let mut page = br#"<html><head><meta http-equiv="Content-Type" content="text/html; charset=gb2312"></head><body>"#.to_vec();
+        page.extend_from_slice(&gbk);
+        let html = decode_html(&page, Some("text/html"));
+        assert!(html.ends_with(body), "{}", html);
+    }
+
+    #[tokio::test]
+    async fn cached_page_keeps_charset() {
+        let cache_dir = TempDir::new("cache");
+        let mut downloader = downloader("");
+        downloader.cache = Some(Arc::new(ResponseCache::new(cache_dir.path().to_path_buf())));
+        downloader.client = service_fn(|request: Request<Body>| async move {
+            // 第一次返回GBK编码的页面, 之后的条件请求返回 304
+            let response = if request.headers().contains_key(IF_NONE_MATCH) {
+                Response::builder()
+                    .status(StatusCode::NOT_MODIFIED)
+                    .body(Body::default())
+            } else {
+                let (gbk, _, _) = encoding_rs::GBK.encode("<p>第一章</p>");
+                Response::builder()
+                    .header(CONTENT_TYPE, "text/html; charset=gbk")
+                    .header(ETAG, "\"1\"")
+                    .body(Body::from(gbk.into_owned()))
+            };
+            Ok::<_, anyhow::Error>(response.unwrap())
+        })
+        .boxed_clone();
+
+        let url = "https://example.com/novel/12/1.html";
+        let first = read_html(downloader.fetch_page(url).await.unwrap()).await;
+        let cached = read_html(downloader.fetch_page(url).await.unwrap()).await;
+
+        assert_eq!(first.unwrap(), "<p>第一章</p>");
+        assert_eq!(cached.unwrap(), "<p>第一章</p>");
+    }
+
+    #[test]
+    fn decode_bom_prefixed_page() {
+        // BOM优先于 Content-Type, 解码后去掉BOM
+        let page = b"\xef\xbb\xbf<p>\xe4\xbd\xa0\xe5\xa5\xbd</p>";
+        assert_eq!(
+            decode_html(page, Some("text/html; charset=gbk")),
+            "<p>你好</p>"
+        );
+
+        let page: Vec<u8> = [0xff, 0xfe]
+            .into_iter()
+            .chain("<p>你好</p>".encode_utf16().flat_map(u16::to_le_bytes))
+            .collect();
+        assert_eq!(decode_html(&page, None), "<p>你好</p>");
+    }
+}
@@ -1,30 +1,83 @@
-use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use regex::Regex;
 use scraper::element_ref::Select;
 use scraper::{ElementRef, Html, Selector};
-use tracing::{error, info, instrument};
+use tracing::{debug, error, info, instrument};
 
-use crate::config::{SiteConfig, get_site_config};
+use crate::config::{BookField, SiteConfig};
 use crate::epub;
 use crate::epub::chapter::Chapter;
-use crate::extractor::{ChapterExtractor, Value, VolumeExtractor};
+use crate::extractor::{
+    ChapterExtractor, ChapterSort, Value, VolumeExtractor, VolumeOrder, strip_title,
+};
+use crate::utils::normalize_url;
 use crate::{Volume, epub::Epub};
 
-#[derive(Clone, Copy)]
+/// 章节内容匹配 retry_if_content_matches, 页面是服务器返回的错误页, 可以重试
+#[derive(Debug)]
+pub struct SoftError(pub String);
+
+impl std::fmt::Display for SoftError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "章节内容匹配错误页规则 '{}'", self.0)
+    }
+}
+
+impl std::error::Error for SoftError {}
+
+/// 目录中解析出的章节, 排序后再生成 Chapter
+#[derive(Clone)]
+struct ChapterEntry {
+    title: String,
+    url: String,
+    special: bool,
+    published_at: Option<i64>,
+}
+
+#[derive(Clone)]
 pub struct Parser {
-    config: &'static SiteConfig,
+    config: Arc<SiteConfig>,
+    volume_order: Option<VolumeOrder>,
+    strip_title: Option<Regex>,
 }
 
 impl Parser {
-    pub fn new(site_name: &str) -> Self {
+    pub fn new(config: Arc<SiteConfig>) -> Self {
         Self {
-            config: get_site_config(site_name).unwrap(),
+            config,
+            volume_order: None,
+            strip_title: None,
         }
     }
+
+    /// 覆盖网站配置中卷的排列顺序
+    pub fn with_volume_order(mut self, order: Option<VolumeOrder>) -> Self {
+        self.volume_order = order;
+        self
+    }
+
+    /// 覆盖网站配置中的 strip_title
+    pub fn with_strip_title(mut self, pattern: Option<Regex>) -> Self {
+        self.strip_title = pattern;
+        self
+    }
+
+    /// 去掉卷和章节标题中 strip_title 匹配的部分
+    fn strip_title(&self, title: &str) -> String {
+        let pattern =
+            self.strip_title
+                .as_ref()
+                .or(self.config.get_book_config().strip_title.as_ref());
+        strip_title(pattern, title)
+    }
 }
 
 impl Parser {
     #[instrument(skip_all)]
-    pub fn chapter_content(&self, chapter: String) -> Result<String> {
+    pub fn chapter_content(&self, chapter: String, title: &str) -> Result<String> {
         let document = Html::parse_document(&chapter);
 
         let content_extractor = &self
@@ -33,16 +86,20 @@ impl Parser {
             .ok_or_else(|| anyhow::anyhow!("未配置章节提取器"))?
             .content;
 
-        let content_elem = document
-            .select(&content_extractor.this)
-            .next()
+        let content_elem = content_extractor
+            .this(document.root_element())
             .ok_or_else(|| anyhow::anyhow!("无法找到章节内容"))?;
 
-        let content = content_extractor
-            .extract_paragraphs(content_elem);
+        let content = content_extractor.extract_content(document.root_element(), content_elem);
 
         if let Value::Single(content) = content {
+            if let Some(pattern) = content_extractor.soft_error(&content) {
+                return Err(SoftError(pattern.as_str().to_string()).into());
+            }
             info!("章节内容提取完成");
+            if content_extractor.dedup_paragraphs {
+                return Ok(Self::dedup_paragraphs(&content, title));
+            }
             Ok(content)
         } else {
             error!("章节内容提取失败");
@@ -50,6 +107,68 @@ impl Parser {
         }
     }
 
+    /// 去掉与章节标题相同的第一段, 并把紧邻的相同段落合并为一段
+    ///
+    /// 段落按行分隔, 比较时只看段落的文本
+    fn dedup_paragraphs(content: &str, title: &str) -> String {
+        let text = |paragraph: &str| {
+            Html::parse_fragment(paragraph)
+                .root_element()
+                .text()
+                .flat_map(str::split_whitespace)
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        let mut paragraphs: Vec<&str> = Vec::new();
+        let mut last_text: Option<String> = None;
+        for paragraph in content.lines() {
+            let paragraph_text = text(paragraph);
+            if paragraph_text.is_empty() {
+                paragraphs.push(paragraph);
+                continue;
+            }
+            if last_text.is_none() && paragraph_text == title {
+                debug!("去掉与标题相同的段落: {}", title);
+                continue;
+            }
+            if last_text.as_ref() == Some(&paragraph_text) {
+                debug!("去掉重复的段落: {}", paragraph_text);
+                continue;
+            }
+            paragraphs.push(paragraph);
+            last_text = Some(paragraph_text);
+        }
+        paragraphs.join("\n")
+    }
+
+    /// 判断页面是否是错误页、验证页或几乎没有内容的空页面
+    fn looks_blocked(html: &str, document: &Html) -> bool {
+        const MIN_TEXT_LEN: usize = 200;
+        const ERROR_MARKERS: &[&str] = &[
+            "Just a moment",
+            "cf-challenge",
+            "challenge-platform",
+            "Attention Required",
+            "Access denied",
+            "403 Forbidden",
+            "404 Not Found",
+            "Too Many Requests",
+        ];
+
+        if ERROR_MARKERS.iter().any(|marker| html.contains(marker)) {
+            return true;
+        }
+
+        let text_len: usize = document
+            .root_element()
+            .text()
+            .map(|text| text.trim().chars().count())
+            .sum();
+        text_len < MIN_TEXT_LEN
+    }
+
     pub fn chapter_srcs(&self, chapter_content: &str) -> Vec<String> {
         let mut srcs = Vec::new();
         let chapter_document = Html::parse_fragment(chapter_content);
@@ -67,63 +186,167 @@ impl Parser {
         srcs
     }
 
+    /// 页面中声明的网站图标, 优先使用尺寸较大的 apple-touch-icon
+    pub fn icon_url(&self, novel_html: &str) -> Option<String> {
+        let document = Html::parse_document(novel_html);
+        ["link[rel~=\"apple-touch-icon\"]", "link[rel~=\"icon\"]"]
+            .iter()
+            .filter_map(|selector| Selector::parse(selector).ok())
+            .find_map(|selector| {
+                document
+                    .select(&selector)
+                    .find_map(|link| link.value().attr("href"))
+                    .filter(|href| !href.is_empty())
+                    .map(str::to_owned)
+            })
+    }
+
+    /// 解析小说信息, chapter_list_html 为单独的章节列表页时, 卷和章节从该页面解析
     #[instrument(skip_all)]
-    pub fn novel_info(&self, novel_html: &str, novel_id: String) -> Result<Epub> {
+    pub fn novel_info(
+        &self,
+        novel_html: &str,
+        chapter_list_html: Option<&str>,
+        novel_id: String,
+    ) -> Result<Epub> {
         info!("正在解析小说信息");
         let document = Html::parse_document(novel_html);
 
         let book_extractor = self.config.get_book_config();
 
         let Some(book_elem) = book_extractor.this(document.root_element()) else {
-            anyhow::bail!("无法获取小说元素")
+            if Self::looks_blocked(novel_html, &document) {
+                anyhow::bail!("已访问网站, 但无法获取小说元素: 页面可能为空或被拦截")
+            }
+            anyhow::bail!("已访问网站, 但无法获取小说元素: book选择器未匹配，请检查配置")
         };
 
-        let Value::Single(title) = book_extractor.extract_title(book_elem) else {
+        let Value::Single(title) = book_extractor.extract_title(book_elem).trim() else {
             anyhow::bail!("无法提取小说标题");
         };
 
-        let Value::Single(author) = book_extractor.extract_author(book_elem) else {
+        let subtitle = match book_extractor.extract_subtitle(book_elem).trim() {
+            Value::Single(subtitle) => {
+                Some(subtitle).filter(|subtitle| !subtitle.is_empty() && *subtitle != title)
+            }
+            _ => None,
+        };
+
+        let Value::Single(author) = book_extractor.extract_author(book_elem).trim() else {
             anyhow::bail!("无法提取作者信息");
         };
 
-        let tags = match book_extractor.extract_tags(book_elem) {
+        let tags = match book_extractor.extract_tags(book_elem).trim() {
             Value::Multiple(ts) => ts,
+            Value::Single(tag) if !tag.is_empty() => {
+                debug!("tags 提取到单个值, 作为只有一个标签的列表: {}", tag);
+                vec![tag]
+            }
             _ => Vec::new(),
         };
 
-        let illustrator = match book_extractor.extract_illustrator(book_elem) {
+        let illustrator = match book_extractor.extract_illustrator(book_elem).trim() {
             Value::Single(illust) => Some(illust),
+            Value::Multiple(illusts) if !illusts.is_empty() => {
+                debug!("illustrator 提取到多个值, 合并为一个: {:?}", illusts);
+                Some(illusts.join(", "))
+            }
             _ => None,
         };
 
-        let cover = match book_extractor.extract_cover_url(book_elem) {
+        let cover = match book_extractor.extract_cover_url(book_elem).trim() {
             Value::Single(cover_url) => Some(cover_url),
             _ => None,
         };
 
-        let summary = match book_extractor.extract_summary(book_elem) {
+        let back_cover = match book_extractor.extract_back_cover_url(book_elem).trim() {
+            Value::Single(back_cover_url) => Some(back_cover_url),
+            _ => None,
+        };
+
+        let gallery = match book_extractor.extract_gallery(book_elem).trim() {
+            Value::Multiple(urls) => urls,
+            Value::Single(url) if !url.is_empty() => vec![url],
+            _ => Vec::new(),
+        };
+
+        let summary = match book_extractor.extract_summary(book_elem).trim() {
             Value::Single(s) => s,
             _ => String::new(),
         };
 
-        let children = self.children(book_elem)?;
+        let missing: Vec<&str> = self
+            .config
+            .required_fields
+            .iter()
+            .filter(|field| match field {
+                BookField::Title => title.is_empty(),
+                BookField::Author => author.is_empty(),
+                BookField::Subtitle => subtitle.is_none(),
+                BookField::Illustrator => illustrator.is_none(),
+                BookField::Tags => tags.is_empty(),
+                BookField::Summary => summary.is_empty(),
+                // 开启 cover_from_first_image 时, 缺少的封面之后由第一张插图补上
+                BookField::Cover => cover.is_none() && !self.config.cover_from_first_image,
+            })
+            .map(BookField::as_str)
+            .collect();
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "以下必需的字段没有提取到内容: {} (网站配置的 required_fields)",
+                missing.join(", ")
+            );
+        }
+
+        let children = match chapter_list_html {
+            Some(chapter_list_html) => {
+                let document = Html::parse_document(chapter_list_html);
+                // 章节列表页可能没有与小说页面相同的book元素, 此时从整个页面中查找
+                let list_elem = book_extractor
+                    .this(document.root_element())
+                    .unwrap_or(document.root_element());
+                self.children(list_elem)
+                    .context("无法从章节列表页解析卷和章节")?
+            }
+            None => self.children(book_elem)?,
+        };
 
-        let epub = Epub {
+        let mut epub = Epub {
+            identifier: novel_id.clone(),
             id: novel_id,
-            title: title.trim().to_string(),
+            title,
+            subtitle,
             lang: self.config.lang.clone(),
+            direction: self.config.direction,
+            doctype: self.config.doctype,
+            version: Default::default(),
             author,
             illustrator,
             summary,
             cover,
+            back_cover,
+            gallery,
+            toc_chapters: 0,
             children,
+            nav: None,
             tags,
             epub_dir: Default::default(),
             meta_dir: Default::default(),
             oebps_dir: Default::default(),
             image_dir: Default::default(),
             text_dir: Default::default(),
+            keep_dir: false,
+            group_extras: false,
+            ibooks_specified_fonts: false,
+            minify: false,
+            pretty: false,
+            fixed_layout: false,
+            compat_plain: false,
+            source: String::new(),
+            disclaimer: None,
+            favicon: None,
         };
+        epub.toc_chapters = epub.chapter_count();
 
         info!("小说信息解析完成");
         Ok(epub)
@@ -161,33 +384,75 @@ impl Parser {
     pub fn volumes(&self, iter: Select, extractor: &VolumeExtractor) -> Result<Vec<Volume>> {
         info!("正在解析卷和章节信息");
 
-        let mut volumes = Vec::new();
-
+        let mut entries = Vec::new();
         for (volume_index, volume_elem) in iter.enumerate() {
-            let Value::Single(title) = extractor.extract_title(volume_elem) else {
+            let Value::Single(title) = extractor.extract_title(volume_elem).trim() else {
                 anyhow::bail!("无法提取第 {} 卷标题", volume_index + 1);
             };
+            entries.push((volume_elem, title));
+        }
 
-            let cover_url = match extractor.extract_cover_url(volume_elem) {
-                Value::Single(url) => Some(url),
-                _ => None,
-            };
+        // 卷的编号按排序后的位置分配, 章节的文件名也随之改变
+        let order = self.volume_order.unwrap_or(extractor.order);
+        match order {
+            VolumeOrder::Page => {}
+            VolumeOrder::Reverse => entries.reverse(),
+            VolumeOrder::Number => {
+                Self::order_by_number(&mut entries, |(_, title)| extractor.title_number(title))
+            }
+        }
+
+        let mut entries = match &extractor.merge_pattern {
+            Some(pattern) => Self::merge_volumes(entries, pattern),
+            None => entries
+                .into_iter()
+                .map(|(volume_elem, title)| (vec![volume_elem], title))
+                .collect(),
+        };
+
+        // 章节按发布时间排序时, 卷按其中最早的章节排序, 没有发布时间的卷保持原来的位置;
+        // 配置或指定了卷的顺序时以卷的顺序为准
+        if extractor.chapters.sort_by == ChapterSort::PublishedAt && order == VolumeOrder::Page {
+            Self::order_by_number(&mut entries, |(volume_elems, _)| {
+                volume_elems
+                    .iter()
+                    .flat_map(|volume_elem| volume_elem.select(&extractor.chapters.this))
+                    .filter_map(|chapter_elem| extractor.chapters.published_at(chapter_elem))
+                    .min()
+            });
+        }
+
+        let mut volumes = Vec::new();
+        for (volume_index, (volume_elems, title)) in entries.into_iter().enumerate() {
+            let title = self.strip_title(&title);
+            let cover_url = volume_elems.iter().find_map(|volume_elem| {
+                match extractor.extract_cover_url(*volume_elem).trim() {
+                    Value::Single(url) => Some(url),
+                    _ => None,
+                }
+            });
 
             let cover_chapter = Chapter {
                 index: 0,
-                title: title.trim().to_string(),
+                title: title.clone(),
                 url: String::new(),
                 filename: format!("{}_cover.xhtml", volume_index + 1),
                 images: Vec::new(),
+                number: None,
+                special: false,
+                published_at: None,
             };
 
             let chapters = self.chapters(
-                volume_elem.select(&extractor.chapters.this),
+                volume_elems
+                    .iter()
+                    .flat_map(|volume_elem| volume_elem.select(&extractor.chapters.this)),
                 &extractor.chapters,
                 Some(volume_index),
             )?;
 
             volumes.push(Volume {
+                title,
                 index: volume_index + 1,
                 cover: cover_url,
                 chapters,
@@ -198,16 +463,62 @@ impl Parser {
         Ok(volumes)
     }
 
-    pub fn chapters(
+    /// 标题匹配 pattern 的卷以第一个捕获组(没有时为整个匹配)作为键, 键相同的卷合并到第一次出现的位置,
+    /// 合并后的标题为键; 不匹配的卷和没有其他部分的卷保持原样
+    fn merge_volumes<'a>(
+        entries: Vec<(ElementRef<'a>, String)>,
+        pattern: &Regex,
+    ) -> Vec<(Vec<ElementRef<'a>>, String)> {
+        let mut merged: Vec<(Vec<ElementRef<'a>>, String, String)> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for (volume_elem, title) in entries {
+            let key = pattern
+                .captures(&title)
+                .and_then(|captures| captures.get(1).or_else(|| captures.get(0)))
+                .map(|key| key.as_str().trim().to_string())
+                .filter(|key| !key.is_empty());
+            let Some(key) = key else {
+                merged.push((vec![volume_elem], title.clone(), title));
+                continue;
+            };
+            match positions.get(&key) {
+                Some(&position) => {
+                    info!("合并卷 '{}' 到 '{}'", title, key);
+                    merged[position].0.push(volume_elem);
+                }
+                None => {
+                    positions.insert(key.clone(), merged.len());
+                    merged.push((vec![volume_elem], key, title));
+                }
+            }
+        }
+
+        merged
+            .into_iter()
+            .map(|(volume_elems, key, title)| {
+                let title = if volume_elems.len() > 1 { key } else { title };
+                (volume_elems, title)
+            })
+            .collect()
+    }
+
+    pub fn chapters<'a>(
         &self,
-        iter: Select,
+        iter: impl IntoIterator<Item = ElementRef<'a>>,
         extractor: &ChapterExtractor,
         volume_index: Option<usize>,
     ) -> Result<Vec<Chapter>> {
-        let mut chapters = Vec::new();
-
-        for (chapter_index, chapter_elem) in iter.enumerate() {
-            let Value::Single(title) = extractor.extract_title(chapter_elem) else {
+        let mut entries = Vec::new();
+
+        for (chapter_index, chapter_elem) in iter.into_iter().enumerate() {
+            let title = match extractor.extract_title(chapter_elem).trim() {
+                Value::Single(title) => Some(title),
+                Value::Multiple(titles) => titles.into_iter().next().inspect(|title| {
+                    debug!("章节标题提取到多个值, 使用第一个: {}", title);
+                }),
+                Value::Empty => None,
+            };
+            let Some(title) = title else {
                 if let Some(vol_idx) = volume_index {
                     anyhow::bail!(
                         "无法提取第 {} 卷第 {} 章标题",
@@ -219,7 +530,18 @@ impl Parser {
                 }
             };
 
-            let Value::Single(url) = extractor.extract_content_url(chapter_elem) else {
+            let url = match extractor.extract_content_url(chapter_elem).trim() {
+                Value::Single(url) => Some(url),
+                Value::Multiple(urls) => {
+                    let url = extractor.pick_content_url(&urls);
+                    if let Some(url) = &url {
+                        debug!("章节 '{}' 有多个内容链接 {:?}, 使用: {}", title, urls, url);
+                    }
+                    url
+                }
+                Value::Empty => None,
+            };
+            let Some(url) = url else {
                 if let Some(vol_idx) = volume_index {
                     anyhow::bail!(
                         "无法提取第 {} 卷第 {} 章内容链接",
@@ -231,6 +553,26 @@ impl Parser {
                 }
             };
 
+            let special = extractor.is_special(chapter_elem, &title);
+            let published_at = extractor.published_at(chapter_elem);
+            entries.push(ChapterEntry {
+                title,
+                url: normalize_url(&url),
+                special,
+                published_at,
+            });
+        }
+
+        if extractor.order_by_title_number {
+            Self::order_by_title_number(&mut entries, extractor);
+        }
+        // 章节的编号和文件名按排序后的位置分配
+        if extractor.sort_by == ChapterSort::PublishedAt {
+            Self::order_by_number(&mut entries, |entry| entry.published_at);
+        }
+
+        let mut chapters = Vec::new();
+        for (chapter_index, entry) in entries.into_iter().enumerate() {
             let filename = if let Some(vol_idx) = volume_index {
                 format!("{}_{}.xhtml", vol_idx + 1, chapter_index + 1)
             } else {
@@ -239,12 +581,242 @@ impl Parser {
 
             chapters.push(Chapter {
                 index: chapter_index + 1,
-                title: title.trim().to_string(),
-                url,
+                title: self.strip_title(&entry.title),
+                url: entry.url,
                 filename,
                 images: Vec::new(),
+                number: None,
+                special: entry.special,
+                published_at: entry.published_at,
             });
         }
         Ok(chapters)
     }
+
+    /// 按标题中的数字重新排列章节, 标题中没有数字的章节保持原来的位置,
+    /// 数字相同的章节保持页面中的顺序
+    fn order_by_title_number(entries: &mut [ChapterEntry], extractor: &ChapterExtractor) {
+        Self::order_by_number(entries, |entry| extractor.title_number(&entry.title));
+    }
+
+    /// 按 number 返回的值重新排列, 没有值的项保持原来的位置, 值相同的项保持原来的顺序
+    fn order_by_number<T: Clone, K: Ord>(entries: &mut [T], number: impl Fn(&T) -> Option<K>) {
+        let mut positions = Vec::new();
+        let mut numbered = Vec::new();
+        for (position, entry) in entries.iter().enumerate() {
+            if let Some(number) = number(entry) {
+                positions.push(position);
+                numbered.push((number, entry.clone()));
+            }
+        }
+
+        numbered.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        for (position, (_, entry)) in positions.into_iter().zip(numbered) {
+            entries[position] = entry;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::test_util::{BOOK, site_config};
+
+    /// 用只包含 book 部分的网站配置创建Parser
+    fn parser(book: &str) -> Parser {
+        Parser::new(site_config(book))
+    }
+
+    /// 每卷是一个 section, 卷标题在 h2 中, 章节是其中的链接;
+    /// volume_options 和 chapter_options 分别加入卷和章节的配置
+    fn volumes_parser(volume_options: &str, chapter_options: &str) -> Parser {
+        parser(&format!(
+            r#"{}
+            [book.volumes]
+            this = "section"
+            title = {{ type = "Text", selector = "h2" }}
+            {}
+            [book.volumes.chapters]
+            this = "a"
+            title = {{ type = "Text" }}
+            content_url = {{ type = "Attr", name = "href" }}
+            content = {{ this = "body", paragraphs = {{ type = "Text", selector = "p" }} }}
+            {}
+            "#,
+            BOOK, volume_options, chapter_options
+        ))
+    }
+
+    fn volumes(epub: &Epub) -> Vec<(&str, Vec<&str>)> {
+        let epub::VolOrChap::Volumes(volumes) = &epub.children else {
+            panic!("应该解析出卷");
+        };
+        volumes
+            .iter()
+            .map(|volume| {
+                let titles = volume.chapters.iter().map(|c| c.title.as_str()).collect();
+                (volume.title.as_str(), titles)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn content_url_with_trailing_newline() {
+        let parser = parser(&format!(
+            r#"{}
+            [book.chapters]
+            this = "li > a"
+            title = {{ type = "Text" }}
+            content_url = {{ type = "Url", inner = {{ type = "Attr", name = "href" }} }}
+            content = {{ this = "body", paragraphs = {{ type = "Text", selector = "p" }} }}
+            "#,
+            BOOK
+        ));
+        let html = r#"<div class="book"><h1>书名</h1><span class="author">作者</span><ul>
+            <li><a href="/read/1.html
+            ">第一章</a></li>
+            <li><a href=" /read/2.html&#10;">第二章</a></li>
+        </ul></div>"#;
+
+        let epub = parser.novel_info(html, None, "1".to_string()).unwrap();
+        let epub::VolOrChap::Chapters(chapters) = &epub.children else {
+            panic!("没有卷时应该直接解析出章节");
+        };
+        let urls: Vec<_> = chapters.iter().map(|c| c.url.as_str()).collect();
+        assert_eq!(urls, ["/read/1.html", "/read/2.html"]);
+    }
+
+    #[test]
+    fn content_url_prefer_picks_matching_link() {
+        let book = |prefer: &str| {
+            parser(&format!(
+                r#"{}
+                [book.chapters]
+                this = "li"
+                title = {{ type = "Text", selector = "span" }}
+                content_url = {{ type = "List", selector = "div.links", item = {{ type = "Attr", selector = "a", name = "href" }} }}
+                content = {{ this = "body", paragraphs = {{ type = "Text", selector = "p" }} }}
+                {}
+                "#,
+                BOOK, prefer
+            ))
+        };
+        let html = r#"<div class="book"><h1>书名</h1><span class="author">作者</span><ul>
+            <li><span>第一章</span><div class="links">
+                <a href="/download/1.txt">下载</a><a href="/forum/1.html">阅读</a>
+            </div></li>
+        </ul></div>"#;
+        let chapter_url = |parser: Parser| {
+            let epub = parser.novel_info(html, None, "1".to_string()).unwrap();
+            let epub::VolOrChap::Chapters(chapters) = &epub.children else {
+                panic!("没有卷时应该直接解析出章节");
+            };
+            chapters[0].url.clone()
+        };
+
+        assert_eq!(
+            chapter_url(book("content_url_prefer = '/forum/'")),
+            "/forum/1.html"
+        );
+        // 未配置时使用第一个链接
+        assert_eq!(chapter_url(book("")), "/download/1.txt");
+    }
+
+    #[test]
+    fn merge_volume_parts() {
+        let parser = volumes_parser(r#"merge_pattern = '^(第.+卷)\s*[上下]$'"#, "");
+        let html = r#"<div class="book"><h1>书名</h1><span class="author">作者</span>
+            <section><h2>第一卷 上</h2><a href="/1">第一章</a><a href="/2">第二章</a></section>
+            <section><h2>第一卷 下</h2><a href="/3">第三章</a></section>
+            <section><h2>第二卷</h2><a href="/4">第四章</a></section>
+            <section><h2>第三卷 上</h2><a href="/5">第五章</a></section>
+        </div>"#;
+
+        let epub = parser.novel_info(html, None, "1".to_string()).unwrap();
+        assert_eq!(
+            volumes(&epub),
+            [
+                ("第一卷", vec!["第一章", "第二章", "第三章"]),
+                ("第二卷", vec!["第四章"]),
+                // 没有其他部分的卷保持原来的标题
+                ("第三卷 上", vec!["第五章"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn sort_by_published_at() {
+        let html = r#"<div class="book"><h1>书名</h1><span class="author">作者</span>
+            <section><h2>第二卷</h2>
+                <a href="/4" data-time="2024-03-02">第四章</a>
+                <a href="/3" data-time="2024-03-01">第三章</a>
+            </section>
+            <section><h2>第一卷</h2>
+                <a href="/2" data-time="2024-01-02 08:00">第二章</a>
+                <a href="/1" data-time="2024-01-01">第一章</a>
+            </section>
+            <section><h2>番外</h2><a href="/5">番外</a></section>
+        </div>"#;
+        let sorted = volumes_parser(
+            "",
+            r#"
+            published_at = { type = "Attr", name = "data-time" }
+            sort_by = "published_at"
+            "#,
+        );
+
+        let epub = sorted.novel_info(html, None, "1".to_string()).unwrap();
+        assert_eq!(
+            volumes(&epub),
+            [
+                ("第一卷", vec!["第一章", "第二章"]),
+                ("第二卷", vec!["第三章", "第四章"]),
+                ("番外", vec!["番外"]),
+            ]
+        );
+        let epub::VolOrChap::Volumes(sorted_volumes) = &epub.children else {
+            unreachable!();
+        };
+        assert_eq!(sorted_volumes[0].chapters[0].filename, "1_1.xhtml");
+        assert_eq!(sorted_volumes[0].chapters[0].url, "/1");
+
+        // 默认按页面中的顺序
+        let epub = volumes_parser("", "")
+            .novel_info(html, None, "1".to_string())
+            .unwrap();
+        assert_eq!(
+            volumes(&epub),
+            [
+                ("第二卷", vec!["第四章", "第三章"]),
+                ("第一卷", vec!["第二章", "第一章"]),
+                ("番外", vec!["番外"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn required_cover_with_cover_from_first_image() {
+        let html = r#"<div class="book"><h1>书名</h1><span class="author">作者</span>
+            <a href="/read/1.html">第一章</a></div>"#;
+        let required = |options: &str| {
+            parser(&format!(
+                r#"required_fields = ["cover"]
+                {}
+                {}
+                [book.chapters]
+                this = "a"
+                title = {{ type = "Text" }}
+                content_url = {{ type = "Attr", name = "href" }}
+                content = {{ this = "body", paragraphs = {{ type = "Text", selector = "p" }} }}
+                "#,
+                options, BOOK
+            ))
+            .novel_info(html, None, "1".to_string())
+        };
+
+        let err = required("").expect_err("没有封面时应该报错");
+        assert!(err.to_string().contains("cover"));
+        assert!(required("cover_from_first_image = true").is_ok());
+    }
 }
@@ -1,16 +1,27 @@
-use std::path::PathBuf;
+use std::io::{Cursor, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use anyhow::Result;
 use bytes::Bytes;
+use dashmap::DashMap;
+use image::codecs::jpeg::JpegEncoder;
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressFinish, ProgressStyle};
+use scraper::{ElementRef, Html, Node};
 use sha2::{Digest, Sha256};
 use tokio::fs;
-use tracing::{info, instrument};
+use tokio::sync::OnceCell;
+use tracing::{error, info, instrument, warn};
 
+use crate::cli::{GlobalNumbering, ImageFormat, Options};
+use crate::crawler::resume::ResumeState;
+use crate::epub::Doctype;
 use crate::epub::chapter::Chapter;
+use crate::logger;
+use crate::utils::{escape_xml, image_size, minify_xml, pretty_xml};
 
-static XML_CONTENT_1: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
-<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">
-<html xmlns="http://www.w3.org/1999/xhtml">
+static XML_CONTENT_1: &str = r#"
 <head>
     <title>"#;
 
@@ -24,53 +35,281 @@ static XML_CONTENT_3: &str = r#"</h1>
     <div class="chapter-content">
 "#;
 
+/// 因超出 --image-budget-bytes 而省略的图片, 写入章节时替换为占位文字
+pub const OMITTED_IMAGE_SRC: &str = "omitted-image";
+
 static XML_CONTENT_4: &str = r#"    </div>
 </body>
 </html>"#;
 
+/// --fixed-layout 时无法读取图片大小的页面使用的大小
+const DEFAULT_PAGE_SIZE: (u32, u32) = (1200, 1600);
+
+/// 保存后相对 Images/ 的路径, None表示因超出 --image-budget-bytes 被省略
+pub type ImageCell = Arc<OnceCell<Option<String>>>;
+
 #[derive(Clone)]
 pub struct Processor {
     image_dir: PathBuf,
     text_dir: PathBuf,
+    options: Arc<Options>,
+    doctype: Doctype,
+    written: Arc<AtomicU64>,     // 已写入的字节数, 用于 --image-budget-bytes
+    image_bytes: Arc<AtomicU64>, // 已保存的图片字节数, 显示在进度条中
+    images: Arc<DashMap<String, ImageCell>>, // 图片url -> 保存结果, 同一url只下载一次
+    image_files: Arc<DashMap<PathBuf, Arc<OnceCell<()>>>>, // 正在或已经保存的图片文件, 同一文件只写一次
+    progress: ProgressBar,
+    resume: Option<Arc<ResumeState>>, // --resume 的下载进度
 }
 
 impl Processor {
-    pub fn new(image_dir: PathBuf, text_dir: PathBuf) -> Self {
+    pub fn new(
+        image_dir: PathBuf,
+        text_dir: PathBuf,
+        options: Arc<Options>,
+        doctype: Doctype,
+    ) -> Self {
         Self {
             image_dir,
             text_dir,
+            doctype,
+            written: Arc::new(AtomicU64::new(0)),
+            image_bytes: Arc::new(AtomicU64::new(0)),
+            images: Arc::new(DashMap::new()),
+            image_files: Arc::new(DashMap::new()),
+            progress: Self::progress_bar(),
+            resume: None,
+            options,
         }
     }
 
-    #[instrument(skip_all)]
+    /// 进度条在设置章节总数后才显示, 所有Processor都释放后清除
+    fn progress_bar() -> ProgressBar {
+        let style = ProgressStyle::with_template("{prefix} [{bar:30}] {pos}/{len} 章 {msg}")
+            .expect("进度条模板错误")
+            .progress_chars("=> ");
+        ProgressBar::with_draw_target(None, ProgressDrawTarget::hidden())
+            .with_style(style)
+            .with_finish(ProgressFinish::AndClear)
+    }
+
+    /// 设置进度条的名称和章节总数并开始显示, 指定 --quiet 或输出不是终端时不显示
+    pub fn start_progress(&self, name: &str, chapters: usize) {
+        self.progress.set_prefix(name.to_string());
+        self.progress.set_length(chapters as u64);
+        if !self.options.quiet && std::io::stderr().is_terminal() {
+            logger::progress().add(self.progress.clone());
+        }
+    }
+
+    /// 设置了 --image-budget-bytes 时, 再写入 len 字节的插图是否会超出预算;
+    /// 章节正文也计入已写入的字节数, 但不受预算限制
+    pub fn exceeds_image_budget(&self, len: u64) -> bool {
+        self.options
+            .image_budget_bytes
+            .is_some_and(|max| self.written.load(Ordering::Relaxed) + len > max)
+    }
+
+    fn record_written(&self, len: usize) {
+        self.written.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn with_resume(mut self, resume: Option<Arc<ResumeState>>) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// --resume 时章节已经在上次运行中完成, 返回章节插图
+    pub async fn completed(&self, chapter: &Chapter) -> Option<Vec<String>> {
+        let images = self
+            .resume
+            .as_ref()?
+            .completed(chapter, &self.text_dir)
+            .await?;
+        self.progress.inc(1);
+        Some(images)
+    }
+
+    /// 记录已完成的章节, 写入进度文件失败只记录错误
+    pub async fn complete(&self, chapter: &Chapter) {
+        if let Some(resume) = &self.resume
+            && let Err(e) = resume.complete(chapter).await
+        {
+            error!("保存下载进度失败: {}: {:#}", chapter.title, e);
+        }
+    }
+
+    /// 顺序抓取时记录已完成的章节和之后开始的页面, 写入进度文件失败只记录错误
+    pub async fn complete_at(&self, chapter: &Chapter, next_url: &str) {
+        if let Some(resume) = &self.resume
+            && let Err(e) = resume.complete_at(chapter, next_url).await
+        {
+            error!("保存下载进度失败: {}: {:#}", chapter.title, e);
+        }
+    }
+
+    /// --resume 时顺序抓取上次停下的页面
+    pub async fn resume_url(&self) -> Option<String> {
+        self.resume.as_ref()?.next_url().await
+    }
+
+    /// 按 --minify 或 --pretty 处理生成的XHTML中的空白
+    fn finish(&self, xhtml: String) -> String {
+        if self.options.minify {
+            minify_xml(&xhtml)
+        } else if self.options.pretty {
+            pretty_xml(&xhtml)
+        } else {
+            xhtml
+        }
+    }
+
+    pub fn doctype(&self) -> Doctype {
+        self.doctype
+    }
+
+    /// 是否跳过所有图片
+    pub fn skip_images(&self) -> bool {
+        self.options.skip_images
+    }
+
+    /// 是否去掉番外等额外章节
+    pub fn no_extras(&self) -> bool {
+        self.options.no_extras
+    }
+
+    #[instrument(skip_all, fields(file = %chapter.filename))]
     pub async fn write_chapter(&self, chapter_content: String, chapter: &Chapter) -> Result<()> {
+        if self.options.fixed_layout && !chapter.images.is_empty() {
+            return self.write_pages(chapter).await;
+        }
+        if self.options.compat_plain {
+            return self.write_plain_chapter(chapter_content, chapter).await;
+        }
         info!("正在保存章节: {}", chapter.title);
+        let title = if self.options.global_numbering == Some(GlobalNumbering::Heading) {
+            chapter.numbered_title()
+        } else {
+            chapter.title.clone()
+        };
+        let title = escape_xml(&title, false);
+
         // 创建XHTML内容 - 在body下创建div容器
         let mut xhtml_content = String::new();
 
         // XHTML头部
+        xhtml_content.push_str(self.doctype.prolog());
         xhtml_content.push_str(XML_CONTENT_1);
-        xhtml_content.push_str(&chapter.title);
+        xhtml_content.push_str(&title);
         xhtml_content.push_str(XML_CONTENT_2);
-        xhtml_content.push_str(&chapter.title);
+        xhtml_content.push_str(&title);
         xhtml_content.push_str(XML_CONTENT_3);
-        // 添加章节内容
-        xhtml_content.push_str(&chapter_content);
+        // 添加章节内容, 网页中的HTML不一定是合法的XHTML, 需要重新序列化
+        xhtml_content.push_str(to_xhtml(&chapter_content, self.options.skip_images).trim_end());
+        xhtml_content.push('\n');
         // XHTML尾部
         xhtml_content.push_str(XML_CONTENT_4);
+        let xhtml_content = self.finish(xhtml_content);
 
         let xhtml_path = self.text_dir.join(&chapter.filename);
+        self.record_written(xhtml_content.len());
         fs::write(&xhtml_path, xhtml_content).await?;
+        self.progress.inc(1);
 
         info!("章节 XHTML 已保存到: {}", xhtml_path.display());
 
         Ok(())
     }
 
-    #[instrument(skip_all)]
+    /// --compat-plain 时只写入标题、纯文本段落和插图, head中只有<title>
+    async fn write_plain_chapter(&self, chapter_content: String, chapter: &Chapter) -> Result<()> {
+        info!("正在保存章节: {}", chapter.title);
+        let title = if self.options.global_numbering == Some(GlobalNumbering::Heading) {
+            chapter.numbered_title()
+        } else {
+            chapter.title.clone()
+        };
+        let title = escape_xml(&title, false);
+
+        let mut xhtml_content = format!(
+            r#"{}
+<head>
+<title>{}</title>
+</head>
+<body>
+<h1>{}</h1>
+"#,
+            self.doctype.prolog(),
+            title,
+            title
+        );
+        for paragraph in to_plain_paragraphs(&chapter_content, self.options.skip_images) {
+            xhtml_content.push_str(&paragraph);
+            xhtml_content.push('\n');
+        }
+        xhtml_content.push_str("</body>\n</html>");
+
+        let xhtml_path = self.text_dir.join(&chapter.filename);
+        self.record_written(xhtml_content.len());
+        fs::write(&xhtml_path, xhtml_content).await?;
+        self.progress.inc(1);
+
+        info!("章节 XHTML 已保存到: {}", xhtml_path.display());
+
+        Ok(())
+    }
+
+    /// --fixed-layout 时把章节的每张插图写成单独的一页, viewport为图片的大小
+    async fn write_pages(&self, chapter: &Chapter) -> Result<()> {
+        info!(
+            "正在保存固定版式章节: {} ({} 页)",
+            chapter.title,
+            chapter.images.len()
+        );
+        let title = escape_xml(&chapter.title, false);
+        for (page, image_name) in chapter.images.iter().enumerate() {
+            let image_bytes = fs::read(self.image_dir.join(image_name)).await?;
+            let (width, height) = image_size(&image_bytes).unwrap_or_else(|| {
+                warn!("无法读取图片大小, 使用默认页面大小: {}", image_name);
+                DEFAULT_PAGE_SIZE
+            });
+            let xhtml_content = format!(
+                r#"{}
+<head>
+    <title>{}</title>
+    <meta name="viewport" content="width={}, height={}"/>
+    <style type="text/css">html, body {{ margin: 0; padding: 0; }} img {{ display: block; }}</style>
+</head>
+<body>
+    <img src="../Images/{}" alt="" width="{}" height="{}"/>
+</body>
+</html>"#,
+                self.doctype.prolog(),
+                title,
+                width,
+                height,
+                escape_xml(image_name, true),
+                width,
+                height
+            );
+            let xhtml_content = self.finish(xhtml_content);
+            self.record_written(xhtml_content.len());
+            let xhtml_path = self.text_dir.join(chapter.page_filename(page));
+            fs::write(&xhtml_path, xhtml_content).await?;
+        }
+        self.progress.inc(1);
+
+        info!("固定版式章节已保存: {}", chapter.title);
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(file = %chapter.filename))]
     pub async fn write_html(&self, html: String, chapter: &Chapter) -> Result<()> {
         info!("正在保存章节: {}", chapter.title);
+        let html = self.finish(html);
         let html_path = self.text_dir.join(&chapter.filename);
+        self.record_written(html.len());
         fs::write(&html_path, html).await?;
 
         info!("章节 HTML 已保存到: {}", html_path.display());
@@ -78,20 +317,453 @@ impl Processor {
         Ok(())
     }
 
-    #[instrument(skip_all)]
+    /// 保存图片, 返回文件名; 指定 --normalize-images 时先转换格式
     pub async fn write_image(&self, image_bytes: Bytes, extension: String) -> Result<String> {
+        let (image_bytes, extension) = self.normalize_image(image_bytes, extension).await;
+        self.save_image(image_bytes, extension).await
+    }
+
+    /// 以内容的哈希为文件名保存图片, 相同的图片只保存一次
+    #[instrument(skip_all)]
+    async fn save_image(&self, image_bytes: Bytes, extension: String) -> Result<String> {
         info!("正在保存图片: {}", extension);
-        let mut hasher = Sha256::new();
-        hasher.update(&image_bytes);
-        let hash = hasher.finalize();
-        let filename = format!("{:x}.{}", hash, extension);
+        let filename = Self::image_filename(&image_bytes, &extension);
         let image_path = self.image_dir.join(&filename);
-        if image_path.exists() {
+        // 同时保存同一张图片的任务等待第一个任务写完, 不会重复写入或读到写了一半的文件
+        let cell = self
+            .image_files
+            .entry(image_path.clone())
+            .or_default()
+            .clone();
+        let mut written = false;
+        cell.get_or_try_init(|| async {
+            if image_path.exists() {
+                return Ok::<_, anyhow::Error>(());
+            }
+            // 先写入临时文件再改名, 中断时不会留下不完整的图片
+            let temp_path = self.image_dir.join(format!("{}.tmp", filename));
+            fs::write(&temp_path, &image_bytes).await?;
+            fs::rename(&temp_path, &image_path).await?;
+            written = true;
+            Ok(())
+        })
+        .await?;
+        if !written {
             info!("重复图片: {}", image_path.display());
             return Ok(filename.to_string());
         }
-        fs::write(&image_path, &image_bytes).await?;
+        self.record_written(image_bytes.len());
+        let total = self
+            .image_bytes
+            .fetch_add(image_bytes.len() as u64, Ordering::Relaxed)
+            + image_bytes.len() as u64;
+        self.progress
+            .set_message(format!("图片 {}", HumanBytes(total)));
         info!("图片已保存到: {}", image_path.display());
         Ok(filename.to_string())
     }
+
+    /// 指定 --normalize-images 时把图片转换为该格式, 返回转换后的内容和扩展名;
+    /// 在计算文件名之前转换, 相同的原图转换后仍然共用一个文件. 无法转换时保持原样
+    async fn normalize_image(&self, image_bytes: Bytes, extension: String) -> (Bytes, String) {
+        let Some(format) = self.options.normalize_images else {
+            return (image_bytes, extension);
+        };
+        let source = image_bytes.clone();
+        let converted = tokio::task::spawn_blocking(move || convert_image(&source, format))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|converted| converted);
+        match converted {
+            Ok(Some(converted)) => (converted, format.extension().to_string()),
+            Ok(None) => (image_bytes, format.extension().to_string()),
+            Err(e) => {
+                warn!(
+                    "图片无法转换为 {}, 保持原格式 {}: {:#}",
+                    format.extension(),
+                    extension,
+                    e
+                );
+                (image_bytes, extension)
+            }
+        }
+    }
+
+    fn image_filename(image_bytes: &[u8], extension: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(image_bytes);
+        let hash = hasher.finalize();
+        format!("{:x}.{}", hash, extension)
+    }
+
+    /// 开启 --image-subdirs 时章节插图所在的子目录, 以章节文件名命名
+    fn image_subdir(&self, chapter: &Chapter) -> Option<String> {
+        self.options.image_subdirs.then(|| {
+            Path::new(&chapter.filename)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_else(|| chapter.index.to_string())
+        })
+    }
+
+    /// 图片在缓存中的键, 开启 --image-subdirs 时每个章节分别缓存
+    fn image_key(&self, src: &str, chapter: &Chapter) -> String {
+        match self.image_subdir(chapter) {
+            Some(subdir) => format!("{}:{}", subdir, src),
+            None => src.to_string(),
+        }
+    }
+
+    /// 图片url对应的保存结果, 同一url共用一个, 内嵌的data: URI不共用
+    pub fn image_cell(&self, src: &str, chapter: &Chapter) -> ImageCell {
+        if src.starts_with("data:") {
+            return ImageCell::default();
+        }
+        self.images
+            .entry(self.image_key(src, chapter))
+            .or_default()
+            .clone()
+    }
+
+    /// 保存章节插图, 返回相对 Images/ 的路径, 超出 --image-budget-bytes 时不保存并返回None;
+    /// 开启 --image-subdirs 时保存到以章节文件名命名的子目录中
+    pub async fn write_chapter_image(
+        &self,
+        image_bytes: Bytes,
+        extension: String,
+        chapter: &Chapter,
+    ) -> Result<Option<String>> {
+        let (image_bytes, extension) = self.normalize_image(image_bytes, extension).await;
+        let subdir = self.image_subdir(chapter);
+        let processor = match &subdir {
+            Some(subdir) => Self {
+                image_dir: self.image_dir.join(subdir),
+                ..self.clone()
+            },
+            None => self.clone(),
+        };
+
+        // 已保存过的图片不占用额外的空间
+        let existing = processor
+            .image_dir
+            .join(Self::image_filename(&image_bytes, &extension))
+            .exists();
+        if !existing && self.exceeds_image_budget(image_bytes.len() as u64) {
+            warn!(
+                "图片 {} KB 超出插图预算, 在 {} 中省略",
+                image_bytes.len() / 1024,
+                chapter.title
+            );
+            return Ok(None);
+        }
+
+        fs::create_dir_all(&processor.image_dir).await?;
+        let filename = processor.save_image(image_bytes, extension).await?;
+        Ok(Some(match subdir {
+            Some(subdir) => format!("{}/{}", subdir, filename),
+            None => filename,
+        }))
+    }
+}
+
+/// 转换为JPEG时的质量
+const JPEG_QUALITY: u8 = 90;
+
+/// 把图片转换为 format, 图片已经是该格式时返回None
+fn convert_image(image_bytes: &[u8], format: ImageFormat) -> Result<Option<Bytes>> {
+    let target = match format {
+        ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+        ImageFormat::Png => image::ImageFormat::Png,
+    };
+    if image::guess_format(image_bytes).ok() == Some(target) {
+        return Ok(None);
+    }
+
+    let image = image::load_from_memory(image_bytes)?;
+    let mut converted = Vec::new();
+    match format {
+        ImageFormat::Png => image.write_to(&mut Cursor::new(&mut converted), target)?,
+        ImageFormat::Jpeg => {
+            // JPEG不支持透明, 透明的部分与白色背景混合
+            let rgba = image.to_rgba8();
+            let rgb = image::RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+                let [r, g, b, a] = rgba.get_pixel(x, y).0;
+                let blend = |c: u8| ((c as u16 * a as u16 + 255 * (255 - a as u16)) / 255) as u8;
+                image::Rgb([blend(r), blend(g), blend(b)])
+            });
+            JpegEncoder::new_with_quality(&mut converted, JPEG_QUALITY).encode_image(&rgb)?;
+        }
+    }
+    Ok(Some(Bytes::from(converted)))
+}
+
+/// HTML中没有结束标签的元素, 在XHTML中写成自闭合的形式
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// 把HTML片段重新解析并序列化为XHTML: 自闭合空元素, 补全未闭合的标签, 转义文本和属性;
+/// skip_images 为true时去掉所有<img>, 省略的图片替换为占位文字
+fn to_xhtml(content: &str, skip_images: bool) -> String {
+    let fragment = Html::parse_fragment(content);
+    let mut xhtml = String::with_capacity(content.len());
+    for child in fragment.root_element().children() {
+        write_xhtml_node(&mut xhtml, child, skip_images);
+    }
+    xhtml
+}
+
+/// 分段的块级元素, 前后的文字属于不同的段落
+const BLOCK_ELEMENTS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "tr",
+    "ul",
+];
+
+/// 把HTML片段转换为只有纯文本和插图的<p>段落: 块级元素和<br>分段, 去掉所有其他标签和属性
+fn to_plain_paragraphs(content: &str, skip_images: bool) -> Vec<String> {
+    let fragment = Html::parse_fragment(content);
+    let mut paragraphs = Vec::new();
+    let mut text = String::new();
+    for child in fragment.root_element().children() {
+        write_plain_node(&mut paragraphs, &mut text, child, skip_images);
+    }
+    push_plain_paragraph(&mut paragraphs, &mut text);
+    paragraphs
+}
+
+fn push_plain_paragraph(paragraphs: &mut Vec<String>, text: &mut String) {
+    let trimmed = text.trim();
+    if !trimmed.is_empty() {
+        paragraphs.push(format!("<p>{}</p>", trimmed));
+    }
+    text.clear();
+}
+
+fn write_plain_node(
+    paragraphs: &mut Vec<String>,
+    text: &mut String,
+    node: ego_tree::NodeRef<Node>,
+    skip_images: bool,
+) {
+    match node.value() {
+        Node::Text(t) => text.push_str(&escape_xml(t, false)),
+        Node::Element(element) => match element.name() {
+            "br" => push_plain_paragraph(paragraphs, text),
+            "img" => {
+                if skip_images {
+                    return;
+                }
+                push_plain_paragraph(paragraphs, text);
+                match element.attr("src") {
+                    Some(OMITTED_IMAGE_SRC) => paragraphs.push("<p>[图片已省略]</p>".to_string()),
+                    Some(src) => paragraphs.push(format!(
+                        r#"<p><img src="{}" alt=""/></p>"#,
+                        escape_xml(src, true)
+                    )),
+                    None => {}
+                }
+            }
+            "script" | "style" => {}
+            name => {
+                let block = BLOCK_ELEMENTS.contains(&name);
+                if block {
+                    push_plain_paragraph(paragraphs, text);
+                }
+                for child in node.children() {
+                    write_plain_node(paragraphs, text, child, skip_images);
+                }
+                if block {
+                    push_plain_paragraph(paragraphs, text);
+                }
+            }
+        },
+        _ => {}
+    }
+}
+
+fn write_xhtml_node(xhtml: &mut String, node: ego_tree::NodeRef<Node>, skip_images: bool) {
+    match node.value() {
+        Node::Text(text) => xhtml.push_str(&escape_xml(text, false)),
+        Node::Comment(comment) => {
+            xhtml.push_str("<!--");
+            xhtml.push_str(&comment.replace("--", "- -"));
+            xhtml.push_str("-->");
+        }
+        Node::Element(_) => {
+            let Some(element) = ElementRef::wrap(node) else {
+                return;
+            };
+            let name = element.value().name();
+            if skip_images && name == "img" {
+                return;
+            }
+            if name == "img" && element.value().attr("src") == Some(OMITTED_IMAGE_SRC) {
+                xhtml.push_str(r#"<span class="omitted-image">[图片已省略]</span>"#);
+                return;
+            }
+            xhtml.push('<');
+            xhtml.push_str(name);
+            // SVG和MathML中的 xml:lang、xlink:href 等属性带有命名空间前缀, 需要保留
+            let attrs = &element.value().attrs;
+            let declared = |prefix: &str| {
+                attrs.iter().any(|(attr, _)| {
+                    attr.prefix.as_deref() == Some("xmlns") && &*attr.local == prefix
+                })
+            };
+            if attrs
+                .iter()
+                .any(|(attr, _)| attr.prefix.as_deref() == Some("xlink"))
+                && !declared("xlink")
+            {
+                xhtml.push_str(r#" xmlns:xlink="http://www.w3.org/1999/xlink""#);
+            }
+            for (attr, value) in attrs.iter() {
+                xhtml.push(' ');
+                if let Some(prefix) = &attr.prefix {
+                    xhtml.push_str(prefix);
+                    xhtml.push(':');
+                }
+                xhtml.push_str(&attr.local);
+                xhtml.push_str("=\"");
+                xhtml.push_str(&escape_xml(value, true));
+                xhtml.push('"');
+            }
+            if VOID_ELEMENTS.contains(&name) {
+                xhtml.push_str("/>");
+                return;
+            }
+            xhtml.push('>');
+            for child in node.children() {
+                write_xhtml_node(xhtml, child, skip_images);
+            }
+            xhtml.push_str("</");
+            xhtml.push_str(name);
+            xhtml.push('>');
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::test_util::{TempDir, chapter};
+
+    #[test]
+    fn xhtml_closes_void_elements_and_keeps_attribute_prefixes() {
+        assert_eq!(
+            to_xhtml("第一行<br>第二行<img src=\"a.jpg\">", false),
+            r#"第一行<br/>第二行<img src="a.jpg"/>"#
+        );
+        assert_eq!(
+            to_xhtml(r#"<p>未闭合<p lang="ja">段落"#, false),
+            r#"<p>未闭合</p><p lang="ja">段落</p>"#
+        );
+        assert_eq!(
+            to_xhtml(
+                r#"<svg xml:lang="ja"><image xlink:href="a.png"></image></svg>"#,
+                false
+            ),
+            concat!(
+                r#"<svg xml:lang="ja">"#,
+                r#"<image xmlns:xlink="http://www.w3.org/1999/xlink" xlink:href="a.png"></image>"#,
+                "</svg>"
+            )
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_saves_of_same_image() {
+        let image_dir = TempDir::new("images");
+        let processor = Arc::new(Processor::new(
+            image_dir.path().to_path_buf(),
+            image_dir.path().to_path_buf(),
+            Arc::new(Options::default()),
+            Doctype::default(),
+        ));
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for i in 0..64 {
+            let processor = processor.clone();
+            // 两张不同的图片, 每张由32个任务同时保存
+            let image = Bytes::from(vec![(i % 2) as u8; 64 * 1024]);
+            tasks.spawn(async move { processor.save_image(image, "png".to_string()).await });
+        }
+        let mut filenames = std::collections::HashSet::new();
+        while let Some(result) = tasks.join_next().await {
+            filenames.insert(result.unwrap().unwrap());
+        }
+
+        let mut files = Vec::new();
+        let mut entries = fs::read_dir(image_dir.path()).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            files.push(entry.file_name().into_string().unwrap());
+        }
+        let image_bytes = processor.image_bytes.load(Ordering::Relaxed);
+
+        assert_eq!(filenames.len(), 2);
+        files.sort();
+        let mut expected: Vec<_> = filenames.into_iter().collect();
+        expected.sort();
+        // 每张图片只写入一次, 没有留下临时文件
+        assert_eq!(files, expected);
+        assert_eq!(image_bytes, 2 * 64 * 1024);
+    }
+
+    #[tokio::test]
+    async fn image_budget_omits_only_images() {
+        let dir = TempDir::new("image_budget");
+        let options = Options {
+            image_budget_bytes: Some(1024),
+            ..Options::default()
+        };
+        let processor = Processor::new(
+            dir.path().to_path_buf(),
+            dir.path().to_path_buf(),
+            Arc::new(options),
+            Doctype::default(),
+        );
+
+        // 章节正文超出预算后仍然写入
+        let first = chapter(1, "第一章");
+        processor
+            .write_chapter("正文".repeat(1024), &first)
+            .await
+            .unwrap();
+        assert!(dir.path().join(&first.filename).exists());
+        assert!(processor.exceeds_image_budget(0));
+
+        let image = Bytes::from(vec![0u8; 16]);
+        let saved = processor
+            .write_chapter_image(image, "png".to_string(), &first)
+            .await
+            .unwrap();
+        assert_eq!(saved, None);
+    }
 }
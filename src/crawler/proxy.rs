@@ -0,0 +1,120 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use http::{Request, Response};
+use reqwest::Body;
+use tower::util::BoxCloneSyncService;
+use tower::{BoxError, Service, ServiceExt as _};
+use tracing::{debug, warn};
+
+/// 请求失败的代理暂停使用的时间
+const UNHEALTHY_COOLDOWN: Duration = Duration::from_secs(60);
+
+pub type ProxyClient = BoxCloneSyncService<Request<Body>, Response<Body>, BoxError>;
+
+struct ProxyEntry {
+    name: String,
+    client: ProxyClient,
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+impl ProxyEntry {
+    /// 锁中只有一个时间, 持有锁的线程panic也不会留下不一致的状态, 直接忽略锁的中毒
+    fn unhealthy_until(&self) -> MutexGuard<'_, Option<Instant>> {
+        self.unhealthy_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// 按顺序轮流使用多个代理发送请求, 请求失败的代理在一段时间内被跳过
+///
+/// 没有配置代理时只有一个直连的客户端
+#[derive(Clone)]
+pub struct ProxyPool {
+    entries: Arc<Vec<ProxyEntry>>,
+    next: Arc<AtomicUsize>,
+}
+
+impl ProxyPool {
+    pub fn new(clients: Vec<(String, ProxyClient)>) -> Self {
+        assert!(!clients.is_empty(), "代理池中至少需要一个客户端");
+        let entries = clients
+            .into_iter()
+            .map(|(name, client)| ProxyEntry {
+                name,
+                client,
+                unhealthy_until: Mutex::new(None),
+            })
+            .collect();
+        Self {
+            entries: Arc::new(entries),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// 选择下一个可用的代理, 全部不可用时仍按顺序使用
+    fn pick(&self) -> usize {
+        let len = self.entries.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+        let now = Instant::now();
+        (0..len)
+            .map(|offset| (start + offset) % len)
+            .find(|&index| {
+                let unhealthy_until = self.entries[index].unhealthy_until();
+                unhealthy_until.is_none_or(|until| until <= now)
+            })
+            .unwrap_or_else(|| {
+                warn!(
+                    "所有代理都暂时不可用, 仍然尝试: {}",
+                    self.entries[start].name
+                );
+                start
+            })
+    }
+
+    fn mark_unhealthy(&self, index: usize) {
+        let entry = &self.entries[index];
+        if self.entries.len() > 1 {
+            warn!(
+                "代理请求失败, {:?} 内不再使用: {}",
+                UNHEALTHY_COOLDOWN, entry.name
+            );
+        }
+        *entry.unhealthy_until() = Some(Instant::now() + UNHEALTHY_COOLDOWN);
+    }
+
+    fn mark_healthy(&self, index: usize) {
+        *self.entries[index].unhealthy_until() = None;
+    }
+}
+
+impl Service<Request<Body>> for ProxyPool {
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // 每个请求在 call 中才选择代理, 由选中的客户端自己等待就绪
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let index = self.pick();
+        let pool = self.clone();
+        let client = self.entries[index].client.clone();
+        debug!("使用代理: {}", self.entries[index].name);
+        Box::pin(async move {
+            let result = client.oneshot(req).await;
+            match &result {
+                Ok(_) => pool.mark_healthy(index),
+                Err(_) => pool.mark_unhealthy(index),
+            }
+            result
+        })
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::epub::{Chapter, Epub, VolOrChap};
+
+/// 保存在EPUB临时文件夹中的进度文件, 生成EPUB后删除, 不会被打包
+const PROGRESS_FILE: &str = "progress.json";
+
+/// --resume 的下载进度: 第一次运行时解析得到的目录, 以及已经完成的章节
+#[derive(Serialize, Deserialize, Default)]
+struct Progress {
+    /// 目录骨架, 继续下载时代替重新解析的目录, 保证章节顺序和文件名不变
+    children: Option<VolOrChap>,
+    /// 已保存的封面文件名
+    cover: Option<String>,
+    /// 已保存的封底和彩页插图文件名
+    #[serde(default)]
+    back_cover: Option<String>,
+    #[serde(default)]
+    gallery: Vec<String>,
+    /// 已完成的章节文件名 -> 章节插图
+    done: HashMap<String, Vec<String>>,
+    /// 顺序抓取(next_url)时, 最后完成的章节之后的页面; 为空表示已经没有下一页
+    #[serde(default)]
+    next_url: Option<String>,
+}
+
+pub struct ResumeState {
+    path: PathBuf,
+    progress: Mutex<Progress>,
+}
+
+impl ResumeState {
+    /// 读取 epub_dir 中的进度文件, 没有或无法解析时从头开始
+    pub async fn open(epub_dir: &Path) -> Self {
+        let path = epub_dir.join(PROGRESS_FILE);
+        let progress = match fs::read(&path).await {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                warn!("进度文件无法解析, 从头开始下载: {}: {}", path.display(), e);
+                Progress::default()
+            }),
+            Err(_) => Progress::default(),
+        };
+        Self {
+            path,
+            progress: Mutex::new(progress),
+        }
+    }
+
+    /// 是否读取到了上次的进度
+    pub async fn is_resumed(&self) -> bool {
+        self.progress.lock().await.children.is_some()
+    }
+
+    /// 用上次保存的目录和封面等图片代替重新解析的结果, 返回已完成的章节数
+    pub async fn restore(&self, epub: &mut Epub) -> usize {
+        let progress = self.progress.lock().await;
+        if let Some(children) = &progress.children {
+            epub.children = children.clone();
+            epub.cover = progress.cover.clone();
+            epub.back_cover = progress.back_cover.clone();
+            epub.gallery = progress.gallery.clone();
+        }
+        progress.done.len()
+    }
+
+    /// 第一次运行时记录目录和封面等图片
+    pub async fn start(&self, epub: &Epub) -> Result<()> {
+        let mut progress = self.progress.lock().await;
+        progress.children = Some(epub.children.clone());
+        progress.cover = epub.cover.clone();
+        progress.back_cover = epub.back_cover.clone();
+        progress.gallery = epub.gallery.clone();
+        self.save(&progress).await
+    }
+
+    /// 章节已经完成且文件仍然存在时, 返回章节插图
+    pub async fn completed(&self, chapter: &Chapter, text_dir: &Path) -> Option<Vec<String>> {
+        let images = self
+            .progress
+            .lock()
+            .await
+            .done
+            .get(&chapter.filename)?
+            .clone();
+        text_dir.join(&chapter.filename).exists().then_some(images)
+    }
+
+    /// 记录已完成的章节
+    pub async fn complete(&self, chapter: &Chapter) -> Result<()> {
+        let mut progress = self.progress.lock().await;
+        progress
+            .done
+            .insert(chapter.filename.clone(), chapter.images.clone());
+        self.save(&progress).await
+    }
+
+    /// 顺序抓取时记录已完成的章节和之后开始的页面, 继续下载时从该页面接着抓取
+    pub async fn complete_at(&self, chapter: &Chapter, next_url: &str) -> Result<()> {
+        let mut progress = self.progress.lock().await;
+        progress
+            .done
+            .insert(chapter.filename.clone(), chapter.images.clone());
+        progress.next_url = Some(next_url.to_string());
+        self.save(&progress).await
+    }
+
+    /// 顺序抓取时上次最后完成的章节之后的页面
+    pub async fn next_url(&self) -> Option<String> {
+        self.progress.lock().await.next_url.clone()
+    }
+
+    /// 先写入临时文件再重命名, 写入过程中中断也不会留下不完整的进度文件
+    async fn save(&self, progress: &Progress) -> Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(progress)?).await?;
+        fs::rename(&tmp_path, &self.path).await?;
+        debug!("已保存下载进度: {} 个章节", progress.done.len());
+        Ok(())
+    }
+
+    /// 删除上次运行中断时留在 dir 中的临时文件(写了一半的图片等)
+    pub async fn remove_temp_files(dir: &Path) {
+        let Ok(mut entries) = fs::read_dir(dir).await else {
+            return;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                match fs::remove_file(&path).await {
+                    Ok(_) => info!("已删除临时文件: {}", path.display()),
+                    Err(e) => warn!("删除临时文件失败: {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+
+    /// 生成EPUB后删除进度文件; 打包时只包含EPUB的文件, 进度文件不会被打包进去
+    pub async fn remove(epub_dir: &Path) {
+        let path = epub_dir.join(PROGRESS_FILE);
+        if path.exists() {
+            match fs::remove_file(&path).await {
+                Ok(_) => info!("已删除进度文件: {}", path.display()),
+                Err(e) => warn!("删除进度文件失败: {}: {}", path.display(), e),
+            }
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use http::{Request, Response, StatusCode};
+use reqwest::Body;
+use tower::BoxError;
+use tower::retry::Policy;
+use tracing::{debug, error, warn};
+
+use crate::config::Retry;
+
+/// hyper-util解析域名失败时的错误信息, 以及其中getaddrinfo返回的错误信息
+const DNS_PREFIXES: [&str; 2] = ["dns error", "failed to lookup address information"];
+
+/// rustls握手失败时的错误信息
+const TLS_PREFIXES: [&str; 4] = [
+    "invalid peer certificate",
+    "received fatal alert",
+    "peer is incompatible",
+    "peer misbehaved",
+];
+
+/// 请求失败的原因, 决定是否重试以及最终给用户的错误信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Dns,
+    Tls,
+    Timeout,
+    Connect,
+    Body,
+    Other,
+}
+
+impl ErrorKind {
+    /// 沿错误链查找具体的失败原因
+    pub fn of(error: &(dyn Error + 'static)) -> Self {
+        let mut kind = ErrorKind::Other;
+        let mut current = Some(error);
+        while let Some(error) = current {
+            current = error.source();
+
+            if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+                if error.is_timeout() {
+                    return ErrorKind::Timeout;
+                }
+                if error.is_body() || error.is_decode() {
+                    kind = ErrorKind::Body;
+                } else if error.is_connect() {
+                    kind = ErrorKind::Connect;
+                }
+                // reqwest的错误信息里带有url, 不参与下面的文本匹配
+                continue;
+            }
+
+            if let Some(error) = error.downcast_ref::<io::Error>() {
+                match error.kind() {
+                    io::ErrorKind::TimedOut => return ErrorKind::Timeout,
+                    io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+                        if kind == ErrorKind::Other =>
+                    {
+                        kind = ErrorKind::Connect;
+                    }
+                    _ => {}
+                }
+            }
+
+            // hyper和rustls的错误没有公开的类型, 只能按错误信息的开头判断
+            let message = error.to_string();
+            if Self::is_dns_message(&message) {
+                return ErrorKind::Dns;
+            }
+            if Self::is_tls_message(&message) {
+                return ErrorKind::Tls;
+            }
+        }
+        kind
+    }
+
+    fn is_dns_message(message: &str) -> bool {
+        DNS_PREFIXES
+            .iter()
+            .any(|prefix| message.starts_with(prefix))
+    }
+
+    fn is_tls_message(message: &str) -> bool {
+        TLS_PREFIXES.iter().any(|prefix| message.starts_with(prefix))
+            // native-tls使用的OpenSSL的错误: error:0A000086:SSL routines:...
+            || (message.starts_with("error:") && message.contains(":SSL routines:"))
+    }
+
+    /// 超时、连接中断和读取响应体失败可以重试, DNS解析和证书错误重试也不会成功
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorKind::Timeout | ErrorKind::Connect | ErrorKind::Body
+        )
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ErrorKind::Dns => "DNS解析失败",
+            ErrorKind::Tls => "TLS握手失败",
+            ErrorKind::Timeout => "连接超时",
+            ErrorKind::Connect => "连接失败",
+            ErrorKind::Body => "读取响应失败",
+            ErrorKind::Other => "HTTP请求失败",
+        };
+        f.write_str(message)
+    }
+}
+
+/// 客户端最终返回的错误, 保留失败原因的分类
+#[derive(Debug)]
+pub struct RequestError {
+    pub kind: ErrorKind,
+    source: BoxError,
+}
+
+impl RequestError {
+    pub fn new(source: BoxError) -> Self {
+        Self {
+            kind: ErrorKind::of(&*source),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Error for RequestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+/// 同一个Downloader的所有请求共用的重试次数预算(retry.budget)
+pub struct RetryBudget {
+    remaining: Option<AtomicU32>, // None表示不限制
+    exhausted: AtomicBool,
+}
+
+impl RetryBudget {
+    pub fn new(budget: Option<u32>) -> Self {
+        Self {
+            remaining: budget.map(AtomicU32::new),
+            exhausted: AtomicBool::new(false),
+        }
+    }
+
+    /// 取出一次重试, 预算用完时返回false, 第一次用完时记录错误
+    pub fn withdraw(&self) -> bool {
+        let Some(remaining) = &self.remaining else {
+            return true;
+        };
+        if remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return true;
+        }
+        if !self.exhausted.swap(true, Ordering::Relaxed) {
+            error!("重试次数已达到 retry.budget 的上限, 网站似乎无法访问, 不再重试");
+        }
+        false
+    }
+
+    /// 预算是否已经用完
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+}
+
+/// 超时、连接失败、读取响应体失败以及429/5xx时重试, 每次重试前等待的时间翻倍;
+/// 所有请求合计的重试次数不超过 retry.budget
+#[derive(Clone)]
+pub struct RetryPolicy {
+    retry: Retry,
+    retries: u32,
+    budget: Arc<RetryBudget>,
+}
+
+impl RetryPolicy {
+    pub fn new(retry: Retry, budget: Arc<RetryBudget>) -> Self {
+        Self {
+            retry,
+            retries: 0,
+            budget,
+        }
+    }
+}
+
+impl Policy<Request<Body>, Response<Body>, BoxError> for RetryPolicy {
+    type Future = tokio::time::Sleep;
+
+    fn retry(
+        &mut self,
+        req: &mut Request<Body>,
+        result: &mut Result<Response<Body>, BoxError>,
+    ) -> Option<Self::Future> {
+        let reason = match result {
+            Ok(response) => {
+                let status = response.status();
+                if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+                    return None;
+                }
+                format!("HTTP错误 {}", status)
+            }
+            Err(e) => {
+                let kind = ErrorKind::of(&**e);
+                if !kind.is_retryable() {
+                    debug!("{}, 不再重试: {}: {}", kind, req.uri(), e);
+                    return None;
+                }
+                format!("{}: {}", kind, e)
+            }
+        };
+
+        if self.retries >= self.retry.max {
+            return None;
+        }
+        if !self.budget.withdraw() {
+            return None;
+        }
+
+        let delay = self.retry.delay(self.retries);
+        self.retries += 1;
+        warn!(
+            "请求失败, {} 毫秒后第 {} 次重试: {}: {}",
+            delay.as_millis(),
+            self.retries,
+            req.uri(),
+            reason
+        );
+        Some(tokio::time::sleep(delay))
+    }
+
+    /// 复制请求用于重试. Downloader发出的请求(包括POST)的请求体都已经在内存中, 都可以重试;
+    /// 流式请求体无法重放, 这样的请求失败后不会重试
+    fn clone_request(&mut self, req: &Request<Body>) -> Option<Request<Body>> {
+        let Some(body) = req.body().as_bytes() else {
+            debug!("请求体是流式的, 失败后不会重试: {}", req.uri());
+            return None;
+        };
+        let mut cloned = Request::new(Body::from(body.to_vec()));
+        *cloned.method_mut() = req.method().clone();
+        *cloned.uri_mut() = req.uri().clone();
+        *cloned.version_mut() = req.version();
+        *cloned.headers_mut() = req.headers().clone();
+        *cloned.extensions_mut() = req.extensions().clone();
+        Some(cloned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    use super::*;
+
+    /// 模拟hyper-util等没有公开类型的错误, 只有错误信息和下一层错误
+    #[derive(Debug)]
+    struct Chain(&'static str, Option<BoxError>);
+
+    impl fmt::Display for Chain {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.0)
+        }
+    }
+
+    impl Error for Chain {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match &self.1 {
+                Some(source) => Some(&**source),
+                None => None,
+            }
+        }
+    }
+
+    fn chain(message: &'static str, source: impl Into<BoxError>) -> Chain {
+        Chain(message, Some(source.into()))
+    }
+
+    fn client() -> reqwest::Client {
+        reqwest::Client::builder()
+            .no_proxy()
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap()
+    }
+
+    /// 在本地端口上接受一个连接, 在单独的线程中交给 respond 处理
+    fn serve(respond: impl FnOnce(TcpStream) + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            respond(stream);
+        });
+        url
+    }
+
+    #[test]
+    fn classify_by_message_prefix() {
+        let dns = chain(
+            "client error (Connect)",
+            chain(
+                "dns error",
+                io::Error::other("failed to lookup address information: Name or service not known"),
+            ),
+        );
+        assert_eq!(ErrorKind::of(&dns), ErrorKind::Dns);
+        let getaddrinfo = io::Error::other(
+            "failed to lookup address information: nodename nor servname provided",
+        );
+        assert_eq!(ErrorKind::of(&getaddrinfo), ErrorKind::Dns);
+
+        let rustls = chain(
+            "client error (Connect)",
+            io::Error::other("invalid peer certificate: UnknownIssuer"),
+        );
+        assert_eq!(ErrorKind::of(&rustls), ErrorKind::Tls);
+        let alert = io::Error::other("received fatal alert: HandshakeFailure");
+        assert_eq!(ErrorKind::of(&alert), ErrorKind::Tls);
+        let openssl = chain(
+            "client error (Connect)",
+            io::Error::other(
+                "error:0A000086:SSL routines:tls_post_process_server_certificate:certificate verify failed",
+            ),
+        );
+        assert_eq!(ErrorKind::of(&openssl), ErrorKind::Tls);
+
+        // 只是信息中包含tls或certificate的错误不是TLS错误
+        let other = chain(
+            "unexpected page at https://tls.example.com/certificate",
+            io::Error::other("settls"),
+        );
+        assert_eq!(ErrorKind::of(&other), ErrorKind::Other);
+
+        let reset = chain(
+            "connection error",
+            io::Error::from(io::ErrorKind::ConnectionReset),
+        );
+        assert_eq!(ErrorKind::of(&reset), ErrorKind::Connect);
+        let timed_out = chain("read error", io::Error::from(io::ErrorKind::TimedOut));
+        assert_eq!(ErrorKind::of(&timed_out), ErrorKind::Timeout);
+    }
+
+    #[tokio::test]
+    async fn classify_reqwest_errors() {
+        // 没有监听的端口: 连接被拒绝
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let e = client().get(&url).send().await.unwrap_err();
+        assert_eq!(ErrorKind::of(&e), ErrorKind::Connect);
+
+        // 接受连接但一直不响应: 超时
+        let url = serve(|stream| {
+            std::thread::sleep(Duration::from_secs(2));
+            drop(stream);
+        });
+        let e = client().get(&url).send().await.unwrap_err();
+        assert_eq!(ErrorKind::of(&e), ErrorKind::Timeout);
+
+        // 响应体没有发送完就关闭连接: 读取响应失败
+        let url = serve(|mut stream| {
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nabc");
+        });
+        let response = client().get(&url).send().await.unwrap();
+        let e = response.bytes().await.unwrap_err();
+        assert_eq!(ErrorKind::of(&e), ErrorKind::Body);
+
+        // 无法解析的url不是网络错误
+        let e = client().get("http://").send().await.unwrap_err();
+        assert_eq!(ErrorKind::of(&e), ErrorKind::Other);
+    }
+
+    #[test]
+    fn retryable_kinds() {
+        assert!(ErrorKind::Timeout.is_retryable());
+        assert!(ErrorKind::Connect.is_retryable());
+        assert!(ErrorKind::Body.is_retryable());
+        assert!(!ErrorKind::Dns.is_retryable());
+        assert!(!ErrorKind::Tls.is_retryable());
+        assert!(!ErrorKind::Other.is_retryable());
+    }
+}
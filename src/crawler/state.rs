@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::{debug, error};
+
+/// 保存下载状态的目录
+const STATE_DIR: &str = "state";
+
+/// 每本小说已经下载到的位置, 用于 --since-last 只下载新章节
+#[derive(Serialize, Deserialize)]
+struct CrawlState {
+    /// 已下载的最后一个章节在全书中的位置(从1开始, 跨卷连续计数)
+    last_chapter: usize,
+}
+
+fn state_path(novel_id: &str) -> PathBuf {
+    PathBuf::from(STATE_DIR).join(format!("{}.json", novel_id))
+}
+
+/// 读取上次下载到的章节位置, 没有记录时返回None
+pub async fn load_mark(novel_id: &str) -> Option<usize> {
+    let state = fs::read(state_path(novel_id)).await.ok()?;
+    let state: CrawlState = serde_json::from_slice(&state).ok()?;
+    debug!("上次下载到第 {} 章: {}", state.last_chapter, novel_id);
+    Some(state.last_chapter)
+}
+
+/// 记录已下载到的章节位置, 写入失败只记录错误
+pub async fn store_mark(novel_id: &str, last_chapter: usize) {
+    if let Err(e) = try_store_mark(novel_id, last_chapter).await {
+        error!("保存下载状态失败: {}: {}", novel_id, e);
+    }
+}
+
+async fn try_store_mark(novel_id: &str, last_chapter: usize) -> Result<()> {
+    fs::create_dir_all(STATE_DIR).await?;
+    fs::write(
+        state_path(novel_id),
+        serde_json::to_vec(&CrawlState { last_chapter })?,
+    )
+    .await?;
+    Ok(())
+}
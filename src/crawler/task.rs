@@ -1,14 +1,29 @@
+use std::sync::Arc;
+
 use anyhow::Result;
+use tokio::sync::Semaphore;
 use tokio::task::JoinSet;
 
 pub struct TaskManager<R: Send + 'static> {
     tasks: JoinSet<Result<R>>,
+    semaphore: Option<Arc<Semaphore>>, // 限制同时运行的任务数, None时不限制
 }
 
 impl<R: Send + 'static> TaskManager<R> {
     pub fn new() -> Self {
         Self {
             tasks: JoinSet::new(),
+            semaphore: None,
+        }
+    }
+
+    /// 同时运行的任务不超过 limit 个, 其余任务等待前面的任务完成
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            tasks: JoinSet::new(),
+            semaphore: Some(Arc::new(Semaphore::new(
+                limit.clamp(1, Semaphore::MAX_PERMITS),
+            ))),
         }
     }
 
@@ -16,7 +31,18 @@ impl<R: Send + 'static> TaskManager<R> {
     where
         F: std::future::Future<Output = Result<R>> + Send + 'static,
     {
-        self.tasks.spawn(future);
+        match &self.semaphore {
+            Some(semaphore) => {
+                let semaphore = semaphore.clone();
+                self.tasks.spawn(async move {
+                    let _permit = semaphore.acquire_owned().await?;
+                    future.await
+                });
+            }
+            None => {
+                self.tasks.spawn(future);
+            }
+        }
     }
 
     pub async fn wait(&mut self) -> Result<Vec<R>> {
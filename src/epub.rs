@@ -1,18 +1,29 @@
 pub mod chapter;
 pub mod compression;
 pub mod metadata;
+pub mod nav;
 pub mod volume;
 
 pub use chapter::Chapter;
 pub use compression::Compressor;
 pub use metadata::Metadata;
+pub use nav::{NavEntry, NavList, NavVolume};
 use tracing::instrument;
 pub use volume::Volume;
 
-use anyhow::Result;
-use std::path::PathBuf;
+use anyhow::{Result, bail};
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::mem::take;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone)]
+use crate::cli::EpubVersion;
+
+/// 书的目录结构, 下载章节时使用; 生成元数据时转换为只有导航信息的 NavList
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum VolOrChap {
     Volumes(Vec<Volume>),
     Chapters(Vec<Chapter>),
@@ -24,25 +35,500 @@ impl Default for VolOrChap {
     }
 }
 
+/// 阅读方向, 写入spine的page-progression-direction
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Ltr,
+    Rtl,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Ltr => "ltr",
+            Direction::Rtl => "rtl",
+        }
+    }
+}
+
+/// 章节等XHTML文件的文档类型; 部分EPUB3阅读器不接受XHTML 1.1的DOCTYPE, 可以改用HTML5的
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Doctype {
+    #[default]
+    Xhtml11,
+    Html5,
+}
+
+impl Doctype {
+    /// XML声明、DOCTYPE和<html>开始标签
+    pub fn prolog(&self) -> &'static str {
+        match self {
+            Doctype::Xhtml11 => {
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">
+<html xmlns="http://www.w3.org/1999/xhtml">"#
+            }
+            Doctype::Html5 => {
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml">"#
+            }
+        }
+    }
+}
+
+/// --from/--to 选中的章节, start 和 end 为按阅读顺序的位置(从1开始)
+#[derive(Debug, Clone)]
+pub struct ChapterRange {
+    pub start: usize,
+    pub end: usize,
+    filenames: HashSet<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Epub {
     pub id: String,
+    pub identifier: String, // 写入dc:identifier和dtb:uid的唯一标识
     pub title: String,
+    pub subtitle: Option<String>, // 副标题或别名
     pub lang: String,
+    pub direction: Option<Direction>, // 阅读方向, 未设置时由阅读器决定(通常为ltr)
+    pub doctype: Doctype,
+    pub version: EpubVersion, // 生成的EPUB版本
     pub author: String,
     pub illustrator: Option<String>, // 插画师
     pub summary: String,             // 简介内容
     pub cover: Option<String>,       // 封面图片本地路径
+    pub back_cover: Option<String>,  // 封底图片本地路径, 放在书的最后一页
+    pub gallery: Vec<String>,        // 彩页插图本地路径, 放在书的开头
     pub children: VolOrChap,         // 卷信息
+    pub nav: Option<NavList>,        // --stream-chapters 时已写入文件的章节, 代替 children
+    pub toc_chapters: usize,         // 网站目录中的章节数, --since-last 的下载记录
     pub tags: Vec<String>,
     pub epub_dir: PathBuf,
     pub meta_dir: PathBuf,
     pub oebps_dir: PathBuf,
     pub image_dir: PathBuf,
     pub text_dir: PathBuf,
+    pub keep_dir: bool,               // 为true时drop不删除epub_dir
+    pub group_extras: bool,           // 目录中把额外章节单独归为一组
+    pub ibooks_specified_fonts: bool, // 生成Apple Books的display-options.xml, 使用书内指定的字体
+    pub minify: bool,                 // 去掉生成的元数据文件中用于缩进的空白
+    pub pretty: bool,                 // 重新缩进生成的元数据文件
+    pub fixed_layout: bool,           // 固定版式, 有插图的章节每张图片一页
+    pub compat_plain: bool,           // 兼容模式, 只生成最简单的EPUB2结构
+    pub source: String,               // 小说页面的url
+    pub disclaimer: Option<String>,   // 书末来源声明页的文本模板
+    pub favicon: Option<String>,      // 网站图标的url, 没有封面时作为封面
 }
 
 impl Epub {
+    /// 设置EPUB版本, EPUB3的章节使用HTML5的DOCTYPE; 固定版式只支持EPUB3, 兼容模式只使用EPUB2和XHTML 1.1;
+    /// 需要在设置 fixed_layout 和 compat_plain 之后、写入章节之前调用
+    pub fn set_version(&mut self, version: EpubVersion) {
+        if self.compat_plain {
+            self.version = EpubVersion::V2;
+            self.doctype = Doctype::Xhtml11;
+            return;
+        }
+        self.version = version;
+        if self.fixed_layout && version == EpubVersion::V2 {
+            tracing::info!("固定版式需要EPUB3, 改为生成EPUB3");
+            self.version = EpubVersion::V3;
+        }
+        if self.version == EpubVersion::V3 {
+            self.doctype = Doctype::Html5;
+        }
+    }
+
+    /// 固定版式时有 images 插图的章节的页数, 没有插图的章节和非固定版式时为0(按普通章节排版)
+    pub fn fixed_pages(&self, images: &[String]) -> usize {
+        if self.fixed_layout { images.len() } else { 0 }
+    }
+
+    /// 生成元数据使用的导航列表, 没有在抓取时生成时从 children 转换
+    pub fn nav(&self) -> Cow<'_, NavList> {
+        match &self.nav {
+            Some(nav) => Cow::Borrowed(nav),
+            None => Cow::Owned(NavList::from(&self.children)),
+        }
+    }
+
+    /// 追加标签, 跳过已有的标签
+    pub fn add_tags(&mut self, tags: &[String]) {
+        for tag in tags {
+            let tag = tag.trim();
+            if !tag.is_empty() && !self.tags.iter().any(|t| t == tag) {
+                self.tags.push(tag.to_string());
+            }
+        }
+    }
+
+    /// 按阅读顺序为所有章节分配跨卷的连续编号, 从 start + 1 开始;
+    /// skip_extras 为true时额外章节没有编号, 也不占用编号
+    pub fn number_chapters(&mut self, start: usize, skip_extras: bool) {
+        let mut number = start;
+        let mut assign = |chapters: &mut Vec<Chapter>| {
+            for chapter in chapters {
+                if skip_extras && chapter.special {
+                    chapter.number = None;
+                    continue;
+                }
+                number += 1;
+                chapter.number = Some(number);
+            }
+        };
+        match &mut self.children {
+            VolOrChap::Volumes(volumes) => {
+                for volume in volumes {
+                    assign(&mut volume.chapters);
+                }
+            }
+            VolOrChap::Chapters(chapters) => assign(chapters),
+        }
+    }
+
+    /// 去掉所有额外章节, 去掉后没有章节的卷也一并去掉, 返回被去掉的章节
+    pub fn remove_extras(&mut self) -> Vec<Chapter> {
+        let mut removed = Vec::new();
+        let mut retain = |chapters: &mut Vec<Chapter>| {
+            let (extras, rest) = take(chapters).into_iter().partition(|c| c.special);
+            *chapters = rest;
+            removed.extend::<Vec<Chapter>>(extras);
+        };
+        match &mut self.children {
+            VolOrChap::Volumes(volumes) => {
+                volumes.retain_mut(|volume| {
+                    retain(&mut volume.chapters);
+                    !volume.chapters.is_empty()
+                });
+            }
+            VolOrChap::Chapters(chapters) => retain(chapters),
+        }
+        removed
+    }
+
+    /// 只有一卷时去掉卷, 章节直接作为书的章节, 不再生成卷封面页;
+    /// 卷有封面而书没有时卷封面作为书的封面, 两者都有时保留卷. 返回是否去掉了卷
+    pub fn flatten_single_volume(&mut self) -> bool {
+        let VolOrChap::Volumes(volumes) = &mut self.children else {
+            return false;
+        };
+        if volumes.len() != 1 {
+            return false;
+        }
+        if volumes[0].cover.is_some() && self.cover.is_some() {
+            tracing::info!("唯一的卷有单独的封面, 保留卷: {}", volumes[0].title);
+            return false;
+        }
+        let volume = volumes.pop().unwrap();
+        if volume.cover.is_some() {
+            self.cover = volume.cover;
+        }
+        self.children = VolOrChap::Chapters(volume.chapters);
+        true
+    }
+
+    /// 去掉正文与前面某个章节相同的章节, 返回去掉的章节; 比较时忽略标题和空白,
+    /// 插图也要相同. 去掉后没有章节的卷也一并去掉, 对应的文件会被删除
+    pub async fn dedup_by_content(&mut self) -> Result<Vec<Chapter>> {
+        let chapters: Vec<&Chapter> = match &self.children {
+            VolOrChap::Volumes(volumes) => volumes.iter().flat_map(|v| &v.chapters).collect(),
+            VolOrChap::Chapters(chapters) => chapters.iter().collect(),
+        };
+        let title_selector = scraper::Selector::parse("title, h1").unwrap();
+        let mut seen = HashSet::new();
+        let mut duplicates = HashSet::new();
+        for chapter in chapters {
+            let html = tokio::fs::read_to_string(self.text_dir.join(&chapter.filename)).await?;
+            let document = scraper::Html::parse_document(&html);
+            let titles: HashSet<_> = document.select(&title_selector).map(|e| e.id()).collect();
+            let text: Vec<&str> = document
+                .root_element()
+                .descendants()
+                .filter(|node| !node.ancestors().any(|a| titles.contains(&a.id())))
+                .filter_map(|node| node.value().as_text())
+                .flat_map(|text| text.split_whitespace())
+                .collect();
+            // 没有文字和插图的章节不作比较
+            if text.is_empty() && chapter.images.is_empty() {
+                continue;
+            }
+            let mut hasher = Sha256::new();
+            for word in text {
+                hasher.update(word.as_bytes());
+                hasher.update(b" ");
+            }
+            for image in &chapter.images {
+                hasher.update(b"\0");
+                hasher.update(image.as_bytes());
+            }
+            if !seen.insert(hasher.finalize()) {
+                tracing::info!("章节 '{}' 与前面的章节内容相同", chapter.title);
+                duplicates.insert(chapter.filename.clone());
+            }
+        }
+
+        let mut removed = Vec::new();
+        let mut removed_files = Vec::new();
+        let mut retain = |chapters: &mut Vec<Chapter>| {
+            let (dups, rest) = take(chapters)
+                .into_iter()
+                .partition(|c| duplicates.contains(&c.filename));
+            *chapters = rest;
+            removed.extend::<Vec<Chapter>>(dups);
+        };
+        match &mut self.children {
+            VolOrChap::Volumes(volumes) => {
+                volumes.retain_mut(|volume| {
+                    retain(&mut volume.chapters);
+                    if volume.chapters.is_empty() {
+                        removed_files.push(volume.cover_chapter.filename.clone());
+                    }
+                    !volume.chapters.is_empty()
+                });
+            }
+            VolOrChap::Chapters(chapters) => retain(chapters),
+        }
+
+        // 去掉的章节(固定版式时包括每一页)和卷封面不再写入manifest, 文件也一并删除
+        for chapter in &removed {
+            for page in 0..self.fixed_pages(&chapter.images).max(1) {
+                removed_files.push(chapter.page_filename(page));
+            }
+        }
+        for filename in removed_files {
+            let path = self.text_dir.join(filename);
+            if path.exists() {
+                tokio::fs::remove_file(path).await?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// 所有卷中的章节总数
+    pub fn chapter_count(&self) -> usize {
+        if let Some(nav) = &self.nav {
+            return nav.len();
+        }
+        match &self.children {
+            VolOrChap::Volumes(volumes) => volumes.iter().map(|v| v.chapters.len()).sum(),
+            VolOrChap::Chapters(chapters) => chapters.len(),
+        }
+    }
+
+    /// 按阅读顺序去掉前 count 个章节, 去掉后没有章节的卷也一并去掉, 返回被去掉的章节
+    pub fn skip_chapters(&mut self, count: usize) -> Vec<Chapter> {
+        let mut skipped = Vec::new();
+        let mut skip = |chapters: &mut Vec<Chapter>| {
+            let rest = chapters.split_off((count - skipped.len()).min(chapters.len()));
+            skipped.append(chapters);
+            *chapters = rest;
+        };
+        match &mut self.children {
+            VolOrChap::Volumes(volumes) => {
+                volumes.retain_mut(|volume| {
+                    skip(&mut volume.chapters);
+                    !volume.chapters.is_empty()
+                });
+            }
+            VolOrChap::Chapters(chapters) => skip(chapters),
+        }
+        skipped
+    }
+
+    /// 查找 --from/--to 指定的章节范围(包含两端)
+    ///
+    /// 数字表示按阅读顺序的位置(从1开始), 否则匹配标题包含该文本或url相同的第一个章节;
+    /// --to 从 --from 的位置开始查找
+    pub fn chapter_range(
+        &self,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> Result<Option<ChapterRange>> {
+        if from.is_none() && to.is_none() {
+            return Ok(None);
+        }
+
+        let chapters: Vec<&Chapter> = match &self.children {
+            VolOrChap::Volumes(volumes) => volumes.iter().flat_map(|v| &v.chapters).collect(),
+            VolOrChap::Chapters(chapters) => chapters.iter().collect(),
+        };
+        if chapters.is_empty() {
+            bail!("没有任何章节, 无法使用 --from/--to");
+        }
+
+        let find = |bound: &str, start: usize, arg: &str| -> Result<usize> {
+            let bound = bound.trim();
+            if let Ok(position) = bound.parse::<usize>() {
+                if position == 0 || position > chapters.len() {
+                    bail!("{} {} 超出章节范围 1-{}", arg, position, chapters.len());
+                }
+                if position <= start {
+                    bail!("{} {} 在 --from 之前", arg, position);
+                }
+                return Ok(position - 1);
+            }
+            chapters[start..]
+                .iter()
+                .position(|chapter| {
+                    chapter.title.contains(bound)
+                        || chapter.url == bound
+                        || (!chapter.url.is_empty() && bound.ends_with(chapter.url.as_str()))
+                })
+                .map(|offset| start + offset)
+                .ok_or_else(|| anyhow::anyhow!("没有找到与 {} '{}' 匹配的章节", arg, bound))
+        };
+
+        let start = match from {
+            Some(from) => find(from, 0, "--from")?,
+            None => 0,
+        };
+        let end = match to {
+            Some(to) => find(to, start, "--to")?,
+            None => chapters.len() - 1,
+        };
+
+        Ok(Some(ChapterRange {
+            start: start + 1,
+            end: end + 1,
+            filenames: chapters[start..=end]
+                .iter()
+                .map(|chapter| chapter.filename.clone())
+                .collect(),
+        }))
+    }
+
+    /// 只保留范围内的章节, 去掉后没有章节的卷也一并去掉
+    pub fn retain_range(&mut self, range: &ChapterRange) {
+        let retain = |chapters: &mut Vec<Chapter>| {
+            chapters.retain(|chapter| range.filenames.contains(&chapter.filename));
+        };
+        match &mut self.children {
+            VolOrChap::Volumes(volumes) => {
+                volumes.retain_mut(|volume| {
+                    retain(&mut volume.chapters);
+                    !volume.chapters.is_empty()
+                });
+            }
+            VolOrChap::Chapters(chapters) => retain(chapters),
+        }
+    }
+
+    /// 根据第一个章节的正文检测语言, 检测结果可靠时覆盖 lang, 否则保留配置中的语言
+    pub async fn detect_lang(&mut self) -> Result<()> {
+        /// 用于检测的最大字符数
+        const SAMPLE_CHARS: usize = 2000;
+
+        let first_filename = self.nav().entries().next().map(|e| e.filename.clone());
+        let Some(first_filename) = first_filename else {
+            return Ok(());
+        };
+
+        let html = tokio::fs::read_to_string(self.text_dir.join(first_filename)).await?;
+        let sample: String = scraper::Html::parse_document(&html)
+            .root_element()
+            .text()
+            .flat_map(str::chars)
+            .take(SAMPLE_CHARS)
+            .collect();
+
+        let Some(info) = whatlang::detect(&sample) else {
+            tracing::info!("无法检测语言, 使用配置的语言: {}", self.lang);
+            return Ok(());
+        };
+        let lang = match info.lang().code() {
+            "cmn" => "zh",
+            "jpn" => "ja",
+            "kor" => "ko",
+            "eng" => "en",
+            "vie" => "vi",
+            code => code,
+        };
+        if !info.is_reliable() {
+            tracing::info!(
+                "语言检测结果不可靠({}, {:.2}), 使用配置的语言: {}",
+                lang,
+                info.confidence(),
+                self.lang
+            );
+            return Ok(());
+        }
+        if lang != self.lang {
+            tracing::info!("检测到语言 {}, 代替配置的语言 {}", lang, self.lang);
+            self.lang = lang.to_string();
+        }
+        Ok(())
+    }
+
+    /// 复制按阅读顺序的第一张章节插图作为封面, 没有插图时不设置封面
+    pub async fn cover_from_first_image(&mut self) -> Result<()> {
+        let first_image = self.nav().entries().find_map(|e| e.images.first()).cloned();
+        let Some(first_image) = first_image else {
+            tracing::info!("章节中没有插图, 不设置封面");
+            return Ok(());
+        };
+
+        // 复制一份, 避免封面和章节插图在manifest中指向同一个文件
+        let extension = Path::new(&first_image)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("jpg");
+        let cover_name = format!("cover.{}", extension);
+        tokio::fs::copy(
+            self.image_dir.join(&first_image),
+            self.image_dir.join(&cover_name),
+        )
+        .await?;
+        tracing::info!("使用章节插图作为封面: {}", first_image);
+        self.cover = Some(cover_name);
+        Ok(())
+    }
+
+    /// 把网站图标放大后嵌入SVG作为封面
+    ///
+    /// 阅读器大多无法显示SVG中嵌入的ICO, 其他格式的图标转换为PNG后再嵌入; 无法转换时不设置封面
+    pub async fn cover_from_favicon(&mut self, icon: &[u8], extension: &str) -> Result<()> {
+        let (icon, media_type) = match extension {
+            "png" => (icon.to_vec(), "image/png"),
+            "gif" => (icon.to_vec(), "image/gif"),
+            "jpg" | "jpeg" => (icon.to_vec(), "image/jpeg"),
+            "svg" => (icon.to_vec(), "image/svg+xml"),
+            "webp" => (icon.to_vec(), "image/webp"),
+            _ => match Self::icon_to_png(icon) {
+                Ok(png) => (png, "image/png"),
+                Err(e) => {
+                    tracing::warn!("网站图标无法转换为PNG, 不设置封面: {:#}", e);
+                    return Ok(());
+                }
+            },
+        };
+        let svg = format!(
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="600" height="800" viewBox="0 0 600 800">
+    <rect width="600" height="800" fill="#ffffff"/>
+    <image x="150" y="250" width="300" height="300" style="image-rendering: pixelated" xlink:href="data:{};base64,{}"/>
+</svg>"##,
+            media_type,
+            BASE64_STANDARD.encode(&icon)
+        );
+        let cover_name = "cover.svg".to_string();
+        tokio::fs::write(self.image_dir.join(&cover_name), svg).await?;
+        tracing::info!("使用网站图标作为封面");
+        self.cover = Some(cover_name);
+        Ok(())
+    }
+
+    /// ICO中有多个尺寸时使用最大的一个
+    fn icon_to_png(icon: &[u8]) -> Result<Vec<u8>> {
+        let image = image::load_from_memory(icon)?;
+        let mut png = Vec::new();
+        image.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)?;
+        Ok(png)
+    }
+
     #[instrument(skip_all)]
     pub async fn generate(&self) -> Result<String> {
         tracing::info!("正在生成EPUB文件: {}", self.title);
@@ -50,20 +536,41 @@ impl Epub {
         let metadata = Metadata::new();
 
         // 生成所有元数据文件
-        metadata.generate(self).await?;
+        metadata.generate(self, &self.nav()).await?;
 
         // 压缩成EPUB文件
         let compressor = Compressor::new();
         let epub_filename = compressor.compress_epub(&self.epub_dir).await?;
 
-        tracing::info!("EPUB文件生成成功: {}", epub_filename);
+        tracing::info!(
+            "EPUB文件生成成功: {} (EPUB {})",
+            epub_filename,
+            self.version.as_str()
+        );
         Ok(epub_filename)
     }
+
+    /// 只生成元数据文件, 不打包, 保留整个文件夹
+    #[instrument(skip_all)]
+    pub async fn generate_dir(&mut self) -> Result<PathBuf> {
+        tracing::info!("正在生成EPUB文件夹: {}", self.title);
+
+        let metadata = Metadata::new();
+        metadata.generate(self, &self.nav()).await?;
+
+        self.keep_dir = true;
+        tracing::info!(
+            "EPUB文件夹生成成功: {} (EPUB {})",
+            self.epub_dir.display(),
+            self.version.as_str()
+        );
+        Ok(self.epub_dir.clone())
+    }
 }
 
 impl Drop for Epub {
     fn drop(&mut self) {
-        if self.epub_dir.exists() {
+        if !self.keep_dir && self.epub_dir.exists() {
             // 删除EPUB文件夹
             tracing::info!("正在清理临时文件夹: {}", self.epub_dir.display());
             match std::fs::remove_dir_all(&self.epub_dir) {
@@ -75,3 +582,249 @@ impl Drop for Epub {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn favicon_ico_embedded_as_png() {
+        let mut ico = Vec::new();
+        image::DynamicImage::ImageRgba8(image::RgbaImage::new(16, 16))
+            .write_to(&mut Cursor::new(&mut ico), image::ImageFormat::Ico)
+            .unwrap();
+
+        let image_dir = test_util::TempDir::new("favicon");
+        let mut epub = test_util::epub(VolOrChap::Chapters(Vec::new()));
+        epub.image_dir = image_dir.path().to_path_buf();
+
+        epub.cover_from_favicon(&ico, "ico").await.unwrap();
+        let svg = tokio::fs::read_to_string(image_dir.path().join("cover.svg"))
+            .await
+            .unwrap();
+
+        assert_eq!(epub.cover.as_deref(), Some("cover.svg"));
+        let data = svg.split("data:image/png;base64,").nth(1).unwrap();
+        let png = BASE64_STANDARD
+            .decode(&data[..data.find('"').unwrap()])
+            .unwrap();
+        assert_eq!(crate::utils::image_size(&png), Some((16, 16)));
+
+        // 无法识别的图标不设置封面
+        let mut epub = test_util::epub(VolOrChap::Chapters(Vec::new()));
+        epub.cover_from_favicon(b"<html></html>", "ico")
+            .await
+            .unwrap();
+        assert!(epub.cover.is_none());
+    }
+
+    #[test]
+    fn number_chapters_without_extras() {
+        use test_util::{chapter, volume};
+        let extra = |index, title| Chapter {
+            special: true,
+            ..chapter(index, title)
+        };
+        let mut epub = test_util::epub(VolOrChap::Volumes(vec![
+            volume(0, "第一卷", vec![chapter(1, "第一章"), extra(2, "番外")]),
+            volume(1, "第二卷", vec![chapter(3, "第二章"), chapter(4, "后记")]),
+        ]));
+        let numbers = |epub: &Epub| match &epub.children {
+            VolOrChap::Volumes(volumes) => volumes
+                .iter()
+                .flat_map(|v| &v.chapters)
+                .map(|c| c.number)
+                .collect::<Vec<_>>(),
+            VolOrChap::Chapters(_) => unreachable!(),
+        };
+
+        epub.number_chapters(0, false);
+        assert_eq!(numbers(&epub), [Some(1), Some(2), Some(3), Some(4)]);
+
+        // --no-extras: 额外章节在写入之前就没有编号, 去掉后编号不变
+        epub.number_chapters(0, true);
+        assert_eq!(numbers(&epub), [Some(1), None, Some(2), Some(3)]);
+        epub.remove_extras();
+        assert_eq!(numbers(&epub), [Some(1), Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn flatten_single_volume() {
+        use test_util::{chapter, volume};
+        let single = |cover: Option<&str>| {
+            let mut volume = volume(
+                0,
+                "第一卷",
+                vec![chapter(1, "第一章"), chapter(2, "第二章")],
+            );
+            volume.cover = cover.map(str::to_string);
+            test_util::epub(VolOrChap::Volumes(vec![volume]))
+        };
+        let titles = |epub: &Epub| match &epub.children {
+            VolOrChap::Chapters(chapters) => {
+                chapters.iter().map(|c| c.title.clone()).collect::<Vec<_>>()
+            }
+            VolOrChap::Volumes(_) => panic!("只有一卷时应该去掉卷"),
+        };
+
+        let mut epub = single(None);
+        assert!(epub.flatten_single_volume());
+        assert_eq!(titles(&epub), ["第一章", "第二章"]);
+        assert!(epub.cover.is_none());
+
+        // 卷封面作为书的封面
+        let mut epub = single(Some("volume.jpg"));
+        assert!(epub.flatten_single_volume());
+        assert_eq!(titles(&epub), ["第一章", "第二章"]);
+        assert_eq!(epub.cover.as_deref(), Some("volume.jpg"));
+
+        // 书和卷都有封面时保留卷
+        let mut epub = single(Some("volume.jpg"));
+        epub.cover = Some("cover.jpg".to_string());
+        assert!(!epub.flatten_single_volume());
+        assert!(matches!(&epub.children, VolOrChap::Volumes(volumes) if volumes.len() == 1));
+
+        // 多于一卷时不变
+        let mut epub = test_util::epub(VolOrChap::Volumes(vec![
+            volume(0, "第一卷", vec![chapter(1, "第一章")]),
+            volume(1, "第二卷", vec![chapter(2, "第二章")]),
+        ]));
+        assert!(!epub.flatten_single_volume());
+        assert!(matches!(&epub.children, VolOrChap::Volumes(volumes) if volumes.len() == 2));
+    }
+}
+
+/// 单元测试中构造书、卷、章节和网站配置, 只填写必要的字段
+#[cfg(test)]
+pub(crate) mod test_util {
+    use std::sync::Arc;
+
+    use serde::de::DeserializeOwned;
+
+    use super::*;
+    use crate::config::SiteConfig;
+
+    /// 只有标题和作者的 book 部分
+    pub const BOOK: &str = r#"
+        [book]
+        this = "div.book"
+        title = { type = "Text", selector = "h1" }
+        author = { type = "Text", selector = "span.author" }
+    "#;
+
+    /// 只包含必要字段的网站配置, rest 加入网站配置的顶层之后(网站选项、book 部分等)
+    pub fn site_config(rest: &str) -> Arc<SiteConfig> {
+        let config = SiteConfig::from_toml(&format!(
+            r#"
+            name = "test"
+            base_url = "https://example.com/novel/{{id}}"
+            lang = "zh"
+            rate_limit = {{ num = 1, secs = 1 }}
+            concurrency_limit = 5
+            {}
+            "#,
+            rest
+        ))
+        .unwrap();
+        Arc::new(config)
+    }
+
+    /// 用与读取网站配置相同的方式解析一段TOML, 用于测试配置中的单个部分
+    pub fn from_toml<T: DeserializeOwned>(toml: &str) -> T {
+        config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    /// 测试用的临时文件夹, drop时删除, 断言失败时也不会留下
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        /// 同时运行的测试使用不同的 name
+        pub fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("docln_{}_{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    pub fn chapter(index: usize, title: &str) -> Chapter {
+        Chapter {
+            index,
+            title: title.to_string(),
+            url: format!("/chapter/{}", index),
+            images: Vec::new(),
+            filename: format!("{}.xhtml", index),
+            number: None,
+            special: false,
+            published_at: None,
+        }
+    }
+
+    pub fn volume(index: usize, title: &str, chapters: Vec<Chapter>) -> Volume {
+        Volume {
+            title: title.to_string(),
+            index,
+            cover: None,
+            chapters,
+            cover_chapter: Chapter {
+                filename: format!("{}_cover.xhtml", index),
+                ..chapter(0, title)
+            },
+        }
+    }
+
+    pub fn epub(children: VolOrChap) -> Epub {
+        Epub {
+            id: "test_1".to_string(),
+            identifier: "test_1".to_string(),
+            title: "测试".to_string(),
+            subtitle: None,
+            lang: "zh".to_string(),
+            direction: None,
+            doctype: Doctype::default(),
+            version: EpubVersion::default(),
+            author: "作者".to_string(),
+            illustrator: None,
+            summary: String::new(),
+            cover: None,
+            back_cover: None,
+            gallery: Vec::new(),
+            toc_chapters: 0,
+            children,
+            nav: None,
+            tags: Vec::new(),
+            epub_dir: PathBuf::new(),
+            meta_dir: PathBuf::new(),
+            oebps_dir: PathBuf::new(),
+            image_dir: PathBuf::new(),
+            text_dir: PathBuf::new(),
+            keep_dir: false,
+            group_extras: false,
+            ibooks_specified_fonts: false,
+            minify: false,
+            pretty: false,
+            fixed_layout: false,
+            compat_plain: false,
+            source: String::new(),
+            disclaimer: None,
+            favicon: None,
+        }
+    }
+}
@@ -1,8 +1,38 @@
-#[derive(Debug, Clone)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chapter {
     pub index: usize,
     pub title: String,
     pub url: String,
     pub images: Vec<String>, // 章节内的图片列表
     pub filename: String,
+    pub number: Option<usize>, // 全书连续编号, 只用于显示
+    pub special: bool,         // 番外、后记等额外内容
+    #[serde(default)]
+    pub published_at: Option<i64>, // 发布时间的Unix时间戳(秒), 网站配置了 published_at 时提取
+}
+
+impl Chapter {
+    /// 带全书连续编号的标题, 没有编号时为原标题
+    pub fn numbered_title(&self) -> String {
+        match self.number {
+            Some(number) => format!("{}. {}", number, self.title),
+            None => self.title.clone(),
+        }
+    }
+
+    /// --fixed-layout 时第 page 页(从0开始)的文件名, 第一页就是章节文件
+    pub fn page_filename(&self, page: usize) -> String {
+        page_filename(&self.filename, page)
+    }
+}
+
+/// 章节文件 filename 第 page 页(从0开始)的文件名, 第一页就是章节文件
+pub fn page_filename(filename: &str, page: usize) -> String {
+    if page == 0 {
+        return filename.to_string();
+    }
+    let (stem, extension) = filename.rsplit_once('.').unwrap_or((filename, "xhtml"));
+    format!("{}_p{}.{}", stem, page + 1, extension)
 }
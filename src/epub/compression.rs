@@ -1,15 +1,47 @@
+use std::collections::HashMap;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
+use async_zip::base::read::mem::ZipFileReader;
+use async_zip::base::write::crc32;
 use async_zip::tokio::write::ZipFileWriter;
 use async_zip::{Compression, ZipEntryBuilder};
 use tokio::fs::{self, File};
-use tracing::{info, instrument};
+use tracing::{debug, info, instrument, warn};
 
 use crate::crawler::TaskManager;
 
 pub struct Compressor;
 
+/// 旧EPUB中可直接复用的条目, range 为已压缩的原始数据在旧EPUB中的位置
+struct PreviousEntry {
+    crc32: u32,
+    uncompressed_size: u64,
+    range: Range<usize>,
+}
+
+/// 读入内存的旧EPUB, 所有条目共用同一份数据, 不单独复制
+struct PreviousEpub {
+    reader: ZipFileReader,
+    entries: HashMap<String, PreviousEntry>,
+}
+
+impl PreviousEpub {
+    /// 内容与旧EPUB中同名条目相同时返回其中已压缩的数据
+    fn unchanged(&self, name: &str, crc32: u32, uncompressed_size: u64) -> Option<&[u8]> {
+        let entry = self.entries.get(name)?;
+        if entry.crc32 != crc32 || entry.uncompressed_size != uncompressed_size {
+            return None;
+        }
+        Some(&self.reader.data()[entry.range.clone()])
+    }
+}
+
+/// ZIP本地文件头的固定长度
+const LOCAL_HEADER_LEN: usize = 30;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+
 impl Default for Compressor {
     fn default() -> Self {
         Self::new()
@@ -29,12 +61,15 @@ impl Compressor {
 
         info!("正在压缩EPUB文件: {}", filename);
 
+        // 必须在创建新文件之前读取旧EPUB，否则会被截断
+        let previous = Self::load_previous(&epub_path).await;
+
         // 创建ZIP文件
         let file = File::create(&epub_path).await?;
         let mut writer = ZipFileWriter::with_tokio(file);
 
         Self::add_mimetype(&mut writer, epub_dir).await?;
-        Self::add_directory(&mut writer, epub_dir).await?;
+        Self::add_directory(&mut writer, epub_dir, previous.as_ref()).await?;
 
         // 完成ZIP文件
         writer.close().await?;
@@ -44,6 +79,78 @@ impl Compressor {
         Ok(filename)
     }
 
+    /// 读取同名的旧EPUB，收集其中已压缩的条目以便复用
+    async fn load_previous(epub_path: &Path) -> Option<PreviousEpub> {
+        if !epub_path.exists() {
+            return None;
+        }
+
+        match Self::read_previous(epub_path).await {
+            Ok(previous) => {
+                debug!("从旧EPUB中读取到 {} 个可复用条目", previous.entries.len());
+                Some(previous)
+            }
+            Err(e) => {
+                warn!("无法读取旧EPUB，将重新压缩全部文件: {:#}", e);
+                None
+            }
+        }
+    }
+
+    async fn read_previous(epub_path: &Path) -> Result<PreviousEpub> {
+        let data = fs::read(epub_path).await?;
+        let reader = ZipFileReader::new(data).await?;
+
+        let mut entries = HashMap::new();
+        for entry in reader.file().entries() {
+            if entry.compression() != Compression::Deflate {
+                continue;
+            }
+
+            let name = entry.filename().as_str()?.to_string();
+            let range = Self::raw_entry_range(
+                reader.data(),
+                entry.header_offset(),
+                entry.compressed_size(),
+            )
+            .with_context(|| format!("条目 {} 的本地文件头无效", name))?;
+
+            entries.insert(
+                name,
+                PreviousEntry {
+                    crc32: entry.crc32(),
+                    uncompressed_size: entry.uncompressed_size(),
+                    range,
+                },
+            );
+        }
+
+        Ok(PreviousEpub { reader, entries })
+    }
+
+    /// 根据本地文件头定位条目的已压缩数据
+    fn raw_entry_range(data: &[u8], offset: u64, compressed_size: u64) -> Result<Range<usize>> {
+        let offset = offset as usize;
+        let Some(header) = data.get(offset..offset + LOCAL_HEADER_LEN) else {
+            bail!("本地文件头越界");
+        };
+
+        let signature = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if signature != LOCAL_HEADER_SIGNATURE {
+            bail!("本地文件头签名不匹配");
+        }
+
+        let name_len = u16::from_le_bytes([header[26], header[27]]) as usize;
+        let extra_len = u16::from_le_bytes([header[28], header[29]]) as usize;
+        let start = offset + LOCAL_HEADER_LEN + name_len + extra_len;
+        let end = start + compressed_size as usize;
+        if end > data.len() {
+            bail!("压缩数据越界");
+        }
+
+        Ok(start..end)
+    }
+
     async fn add_mimetype(writer: &mut ZipFileWriter<File>, dir: &Path) -> Result<()> {
         let path = dir.join("mimetype");
         let content = fs::read(&path).await?;
@@ -57,7 +164,11 @@ impl Compressor {
         Ok(())
     }
 
-    async fn add_directory(writer: &mut ZipFileWriter<File>, root_dir: &Path) -> Result<()> {
+    async fn add_directory(
+        writer: &mut ZipFileWriter<File>,
+        root_dir: &Path,
+        previous: Option<&PreviousEpub>,
+    ) -> Result<()> {
         // 创建任务管理器
         let mut task_manager = TaskManager::new();
 
@@ -68,11 +179,30 @@ impl Compressor {
         let results = task_manager.wait().await?;
 
         // 将结果写入ZIP文件（按顺序保证稳定性）
+        let mut reused = 0;
         for (zip_path, content) in results {
+            let crc = crc32(&content);
+
+            // 内容未变化的文件直接复用旧EPUB中的压缩数据
+            if let Some(raw) = previous
+                .and_then(|previous| previous.unchanged(&zip_path, crc, content.len() as u64))
+            {
+                let entry = ZipEntryBuilder::new(zip_path.into(), Compression::Deflate)
+                    .crc32(crc)
+                    .uncompressed_size(content.len() as u64);
+                writer.write_entry_whole_precompressed(entry, raw).await?;
+                reused += 1;
+                continue;
+            }
+
             let entry = ZipEntryBuilder::new(zip_path.into(), Compression::Deflate);
             writer.write_entry_whole(entry, &content).await?;
         }
 
+        if reused > 0 {
+            info!("复用了旧EPUB中 {} 个未变化的文件", reused);
+        }
+
         Ok(())
     }
 
@@ -93,8 +223,8 @@ impl Compressor {
                 let path = entry.path();
                 let name = entry.file_name().to_string_lossy().to_string();
 
-                // 跳过已处理的特殊文件
-                if name == "mimetype" && current_base_path.is_empty() {
+                // 跳过已处理的mimetype; 根目录下的其他文件(例如 --resume 的进度文件)不属于EPUB
+                if current_base_path.is_empty() && !entry.file_type().await?.is_dir() {
                     continue;
                 }
 
@@ -105,6 +235,12 @@ impl Compressor {
                     format!("{}/{}", current_base_path, name)
                 };
 
+                // 写入中断时留下的临时文件(例如 --resume 前没有写完的图片)
+                if name.ends_with(".tmp") {
+                    warn!("跳过临时文件: {}", zip_path);
+                    continue;
+                }
+
                 if path.is_dir() {
                     // 记录子目录稍后处理
                     sub_dirs.push((path, zip_path));
@@ -126,3 +262,93 @@ impl Compressor {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use flate2::read::DeflateDecoder;
+    use flate2::write::DeflateEncoder;
+
+    use super::*;
+    use crate::epub::test_util::TempDir;
+
+    /// 只使用未压缩块的deflate数据, 与 Compressor 压缩的结果不同, 可以区分条目是否被复用
+    fn stored_deflate(content: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::none());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// 读取EPUB中每个条目已压缩的原始数据
+    async fn raw_entries(epub_path: &Path) -> HashMap<String, Vec<u8>> {
+        let previous = Compressor::read_previous(epub_path).await.unwrap();
+        previous
+            .entries
+            .iter()
+            .map(|(name, entry)| {
+                let raw = previous.reader.data()[entry.range.clone()].to_vec();
+                (name.clone(), raw)
+            })
+            .collect()
+    }
+
+    fn inflate(raw: &[u8]) -> String {
+        let mut content = String::new();
+        DeflateDecoder::new(raw)
+            .read_to_string(&mut content)
+            .unwrap();
+        content
+    }
+
+    #[tokio::test]
+    async fn reuses_unchanged_entries() {
+        let dir = TempDir::new("compress");
+        let epub_dir = dir.path().join("book");
+        let text_dir = epub_dir.join("OEBPS").join("Text");
+        fs::create_dir_all(&text_dir).await.unwrap();
+        fs::write(epub_dir.join("mimetype"), "application/epub+zip")
+            .await
+            .unwrap();
+        fs::write(text_dir.join("1.xhtml"), "<p>第一章</p>")
+            .await
+            .unwrap();
+        fs::write(text_dir.join("2.xhtml"), "<p>第二章</p>")
+            .await
+            .unwrap();
+
+        // 旧EPUB中的条目使用与 Compressor 不同的压缩方式, 第二章的内容与现在不同
+        let epub_path = dir.path().join("book.epub");
+        let mut writer = ZipFileWriter::with_tokio(File::create(&epub_path).await.unwrap());
+        for (name, content) in [
+            ("OEBPS/Text/1.xhtml", "<p>第一章</p>"),
+            ("OEBPS/Text/2.xhtml", "<p>旧的第二章</p>"),
+        ] {
+            let entry = ZipEntryBuilder::new(name.into(), Compression::Deflate)
+                .crc32(crc32(content.as_bytes()))
+                .uncompressed_size(content.len() as u64);
+            writer
+                .write_entry_whole_precompressed(entry, &stored_deflate(content.as_bytes()))
+                .await
+                .unwrap();
+        }
+        writer.close().await.unwrap();
+
+        let compressor = Compressor::new();
+        compressor.compress_epub(&epub_dir).await.unwrap();
+        // 再次生成时第二章也没有变化, 两个条目都复用上一次的数据
+        let first = raw_entries(&epub_path).await;
+        compressor.compress_epub(&epub_dir).await.unwrap();
+        let second = raw_entries(&epub_path).await;
+
+        let unchanged = stored_deflate("<p>第一章</p>".as_bytes());
+        assert_eq!(first["OEBPS/Text/1.xhtml"], unchanged);
+        assert_eq!(second["OEBPS/Text/1.xhtml"], unchanged);
+
+        // 内容变化的条目重新压缩
+        let changed = &first["OEBPS/Text/2.xhtml"];
+        assert_ne!(changed, &stored_deflate("<p>旧的第二章</p>".as_bytes()));
+        assert_eq!(inflate(changed), "<p>第二章</p>");
+        assert_eq!(&second["OEBPS/Text/2.xhtml"], changed);
+    }
+}
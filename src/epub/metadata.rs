@@ -1,11 +1,26 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
 use anyhow::Result;
 use tokio::fs;
 use tracing::{info, instrument};
 
-use crate::epub::{VolOrChap, chapter::Chapter};
+use crate::cli::EpubVersion;
+use crate::epub::{NavEntry, NavList};
+use crate::utils::{escape_xml, minify_xml, pretty_xml};
 
 use super::Epub;
 
+/// 来源声明页的文件名, 位于 Text/ 下
+const DISCLAIMER_FILENAME: &str = "disclaimer.xhtml";
+
+/// 封底页和彩页的文件名, 位于 Text/ 下
+const BACK_COVER_FILENAME: &str = "back-cover.xhtml";
+const GALLERY_FILENAME: &str = "gallery.xhtml";
+
+/// EPUB3导航文档的文件名, 位于 OEBPS/ 下
+const NAV_FILENAME: &str = "nav.xhtml";
+
 pub struct Metadata;
 
 impl Default for Metadata {
@@ -39,31 +54,183 @@ impl Metadata {
         <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
     </rootfiles>
 </container>"#;
-        fs::write(epub.meta_dir.join("container.xml"), container_content).await?;
+        fs::write(
+            epub.meta_dir.join("container.xml"),
+            Self::finish(epub, container_content),
+        )
+        .await?;
         info!("container.xml文件生成完成");
         Ok(())
     }
 
+    /// 生成Apple Books的display-options文件: --ibooks-specified-fonts 时使用书内指定的字体,
+    /// --fixed-layout 时按固定版式显示
+    #[instrument(skip_all)]
+    pub async fn ibooks_display_options(&self, epub: &Epub) -> Result<()> {
+        info!("正在生成com.apple.ibooks.display-options.xml文件");
+        let mut options_content = String::from(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<display_options>
+    <platform name="*">"#,
+        );
+        if epub.ibooks_specified_fonts {
+            options_content.push_str(
+                r#"
+        <option name="specified-fonts">true</option>"#,
+            );
+        }
+        if epub.fixed_layout {
+            options_content.push_str(
+                r#"
+        <option name="fixed-layout">true</option>
+        <option name="open-to-spread">false</option>"#,
+            );
+        }
+        options_content.push_str(
+            r#"
+    </platform>
+</display_options>"#,
+        );
+        fs::write(
+            epub.meta_dir.join("com.apple.ibooks.display-options.xml"),
+            Self::finish(epub, &options_content),
+        )
+        .await?;
+        info!("com.apple.ibooks.display-options.xml文件生成完成");
+        Ok(())
+    }
+
+    /// 生成书末的来源声明页, 替换文本中的 {title}/{source}/{date}
+    #[instrument(skip_all)]
+    pub async fn disclaimer(&self, epub: &Epub, template: &str) -> Result<()> {
+        info!("正在生成声明页");
+        let date = chrono::Local::now().format("%Y-%m-%d").to_string();
+        let text = template
+            .replace("{title}", &epub.title)
+            .replace("{source}", &epub.source)
+            .replace("{date}", &date);
+
+        let mut disclaimer_content = String::from(epub.doctype.prolog());
+        disclaimer_content.push_str(
+            r#"
+<head>
+    <title>声明</title>
+    <meta http-equiv="Content-Type" content="text/html; charset=UTF-8"/>
+</head>
+<body>
+    <div class="disclaimer">"#,
+        );
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            disclaimer_content.push_str(&format!(
+                r#"
+        <p>{}</p>"#,
+                escape_xml(line, false)
+            ));
+        }
+        disclaimer_content.push_str(
+            r#"
+    </div>
+</body>
+</html>"#,
+        );
+        fs::write(
+            epub.text_dir.join(DISCLAIMER_FILENAME),
+            Self::finish(epub, &disclaimer_content),
+        )
+        .await?;
+        info!("声明页生成完成");
+        Ok(())
+    }
+
+    /// 生成书末的封底页
+    #[instrument(skip_all)]
+    pub async fn back_cover(&self, epub: &Epub, back_cover_name: &str) -> Result<()> {
+        info!("正在生成封底页");
+        let mut back_cover_content = String::from(epub.doctype.prolog());
+        back_cover_content.push_str(&format!(
+            r#"
+<head>
+    <title>封底</title>
+    <meta http-equiv="Content-Type" content="text/html; charset=UTF-8"/>
+</head>
+<body>
+    <div class="cover">
+        <img src="../Images/{}" alt="封底" class="back-cover-img"/>
+    </div>
+</body>
+</html>"#,
+            back_cover_name
+        ));
+        fs::write(
+            epub.text_dir.join(BACK_COVER_FILENAME),
+            Self::finish(epub, &back_cover_content),
+        )
+        .await?;
+        info!("封底页生成完成");
+        Ok(())
+    }
+
+    /// 生成书首的彩页, 按提取的顺序排列所有彩页插图
+    #[instrument(skip_all)]
+    pub async fn gallery(&self, epub: &Epub) -> Result<()> {
+        info!("正在生成彩页");
+        let mut gallery_content = String::from(epub.doctype.prolog());
+        gallery_content.push_str(
+            r#"
+<head>
+    <title>彩页</title>
+    <meta http-equiv="Content-Type" content="text/html; charset=UTF-8"/>
+</head>
+<body>
+    <div class="gallery">"#,
+        );
+        for image_name in &epub.gallery {
+            let _ = write!(
+                gallery_content,
+                r#"
+        <p><img src="../Images/{}" alt="彩页" class="gallery-img"/></p>"#,
+                image_name
+            );
+        }
+        gallery_content.push_str(
+            r#"
+    </div>
+</body>
+</html>"#,
+        );
+        fs::write(
+            epub.text_dir.join(GALLERY_FILENAME),
+            Self::finish(epub, &gallery_content),
+        )
+        .await?;
+        info!("彩页生成完成");
+        Ok(())
+    }
+
     /// 生成content.opf文件
     #[instrument(skip_all)]
-    pub async fn content_opf(&self, epub: &Epub) -> Result<()> {
+    pub async fn content_opf(&self, epub: &Epub, nav: &NavList) -> Result<()> {
         info!("正在生成content.opf文件");
         let mut content_opf = String::new();
-        Self::opf_header(&mut content_opf);
+        Self::opf_header(&mut content_opf, epub);
         Self::opf_metadata(&mut content_opf, epub);
-        Self::opf_manifest(&mut content_opf, epub);
-        Self::opf_spine(&mut content_opf, epub);
+        Self::opf_manifest(&mut content_opf, epub, nav);
+        Self::opf_spine(&mut content_opf, epub, nav);
         Self::opf_guide(&mut content_opf, epub);
         Self::opf_footer(&mut content_opf);
 
-        fs::write(epub.oebps_dir.join("content.opf"), content_opf).await?;
+        fs::write(
+            epub.oebps_dir.join("content.opf"),
+            Self::finish(epub, &content_opf),
+        )
+        .await?;
         info!("content.opf文件生成完成");
         Ok(())
     }
 
     /// 生成toc.ncx文件
     #[instrument(skip_all)]
-    pub async fn toc_ncx(&self, epub: &Epub) -> Result<()> {
+    pub async fn toc_ncx(&self, epub: &Epub, nav: &NavList) -> Result<()> {
         info!("正在生成toc.ncx文件");
         let mut toc_ncx = String::new();
 
@@ -73,29 +240,30 @@ impl Metadata {
     <head>
         <meta name="dtb:uid" content=""#,
         );
-        toc_ncx.push_str(&format!("{}", epub.id));
-        toc_ncx.push_str(
+        toc_ncx.push_str(&escape_xml(&epub.identifier, true));
+        toc_ncx.push_str(&format!(
             r#""/>
-        <meta name="dtb:depth" content="1"/>
+        <meta name="dtb:depth" content="{}"/>
         <meta name="dtb:totalPageCount" content="0"/>
         <meta name="dtb:maxPageNumber" content="0"/>
     </head>
     <docTitle>
         <text>"#,
-        );
-        toc_ncx.push_str(&epub.title);
+            Self::toc_depth(epub, nav)
+        ));
+        toc_ncx.push_str(&escape_xml(&epub.title, false));
         toc_ncx.push_str(
             r#"</text>
     </docTitle>
     <navMap>"#,
         );
 
-        match &epub.children {
-            VolOrChap::Volumes(volumes) => {
+        let mut nav_point_counter = 1;
+        match nav {
+            NavList::Volumes(volumes) => {
                 // 添加章节导航 - 层级结构
-                let mut nav_point_counter = 1;
                 for volume in volumes {
-                    if volume.chapters.is_empty() {
+                    if volume.entries.is_empty() {
                         continue;
                     }
 
@@ -109,13 +277,17 @@ impl Metadata {
             <content src="Text/{}"/>"#,
                         nav_point_counter,
                         nav_point_counter,
-                        volume.cover_chapter.title,
-                        volume.cover_chapter.filename
+                        escape_xml(&volume.title, false),
+                        volume.cover_filename
                     ));
                     nav_point_counter += 1;
 
                     // 章节作为卷的子导航点
-                    Self::toc_ncx_chapters(&mut toc_ncx, &volume.chapters, &mut nav_point_counter);
+                    let chapters = volume
+                        .entries
+                        .iter()
+                        .filter(|c| !(epub.group_extras && c.special));
+                    Self::toc_ncx_chapters(&mut toc_ncx, chapters, &mut nav_point_counter);
 
                     toc_ncx.push_str(
                         r#"
@@ -123,31 +295,68 @@ impl Metadata {
                     );
                 }
             }
-            VolOrChap::Chapters(chapters) => {
+            NavList::Chapters(chapters) => {
                 // 添加章节导航 - 扁平结构
-                let mut nav_point_counter = 1;
+                let chapters = chapters
+                    .iter()
+                    .filter(|c| !(epub.group_extras && c.special));
                 Self::toc_ncx_chapters(&mut toc_ncx, chapters, &mut nav_point_counter);
             }
         }
 
+        // 额外章节单独作为最后一个一级导航点
+        let extras: Vec<&NavEntry> = if epub.group_extras {
+            nav.entries().filter(|c| c.special).collect()
+        } else {
+            Vec::new()
+        };
+        if let Some(first) = extras.first() {
+            toc_ncx.push_str(&format!(
+                r#"
+        <navPoint id="navPointExtras" playOrder="{}">
+            <navLabel>
+                <text>番外</text>
+            </navLabel>
+            <content src="Text/{}"/>"#,
+                nav_point_counter, first.filename
+            ));
+            nav_point_counter += 1;
+            Self::toc_ncx_chapters(&mut toc_ncx, extras, &mut nav_point_counter);
+            toc_ncx.push_str(
+                r#"
+        </navPoint>"#,
+            );
+        }
+
         toc_ncx.push_str(
             r#"
     </navMap>
 </ncx>"#,
         );
 
-        fs::write(epub.oebps_dir.join("toc.ncx"), toc_ncx).await?;
+        fs::write(epub.oebps_dir.join("toc.ncx"), Self::finish(epub, &toc_ncx)).await?;
         info!("toc.ncx文件生成完成");
         Ok(())
     }
 
-    fn toc_ncx_chapters(
+    /// navMap的实际层数: 有子导航点(卷下的章节或番外分组)时为2, 否则为1
+    fn toc_depth(epub: &Epub, nav: &NavList) -> usize {
+        // 卷下有非番外的章节
+        let nested = matches!(nav, NavList::Volumes(_))
+            && nav.entries().any(|c| !(epub.group_extras && c.special));
+        let extras = epub.group_extras && nav.entries().any(|c| c.special);
+        if nested || extras { 2 } else { 1 }
+    }
+
+    fn toc_ncx_chapters<'a>(
         toc_ncx: &mut String,
-        chapters: &Vec<Chapter>,
+        chapters: impl IntoIterator<Item = &'a NavEntry>,
         nav_point_counter: &mut usize,
     ) {
+        // 直接写入缓冲区, 不为每个章节分配临时String; 写入String不会失败
         for chapter in chapters {
-            toc_ncx.push_str(&format!(
+            let _ = write!(
+                toc_ncx,
                 r#"
             <navPoint id="navPoint{}" playOrder="{}">
                 <navLabel>
@@ -155,21 +364,157 @@ impl Metadata {
                 </navLabel>
                 <content src="Text/{}"/>
             </navPoint>"#,
-                nav_point_counter, nav_point_counter, chapter.title, chapter.filename
-            ));
+                nav_point_counter,
+                nav_point_counter,
+                escape_xml(&chapter.title, false),
+                chapter.filename
+            );
             *nav_point_counter += 1;
         }
     }
 
-    /// 生成所有元数据文件
+    /// 生成EPUB3的导航文档nav.xhtml, 结构与toc.ncx相同
     #[instrument(skip_all)]
-    pub async fn generate(&self, epub: &Epub) -> Result<()> {
+    pub async fn nav_xhtml(&self, epub: &Epub, nav_list: &NavList) -> Result<()> {
+        info!("正在生成nav.xhtml文件");
+        let mut nav = String::from(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head>
+    <title>"#,
+        );
+        nav.push_str(&escape_xml(&epub.title, false));
+        nav.push_str(
+            r#"</title>
+    <meta charset="UTF-8"/>
+</head>
+<body>
+    <nav epub:type="toc" id="toc">
+        <h1>目录</h1>
+        <ol>"#,
+        );
+
+        match nav_list {
+            NavList::Volumes(volumes) => {
+                for volume in volumes {
+                    let chapters: Vec<&NavEntry> = volume
+                        .entries
+                        .iter()
+                        .filter(|c| !(epub.group_extras && c.special))
+                        .collect();
+                    // 章节都归入番外的卷不显示, nav中不允许空的 <ol>
+                    if chapters.is_empty() {
+                        continue;
+                    }
+                    let title = escape_xml(&volume.title, false);
+                    // 没有封面的卷没有封面页, 只显示标题
+                    if volume.cover.is_some() {
+                        nav.push_str(&format!(
+                            r#"
+            <li><a href="Text/{}">{}</a>
+                <ol>"#,
+                            volume.cover_filename, title
+                        ));
+                    } else {
+                        nav.push_str(&format!(
+                            r#"
+            <li><span>{}</span>
+                <ol>"#,
+                            title
+                        ));
+                    }
+                    Self::nav_chapters(&mut nav, chapters, "                    ");
+                    nav.push_str(
+                        r#"
+                </ol>
+            </li>"#,
+                    );
+                }
+            }
+            NavList::Chapters(chapters) => {
+                let chapters = chapters
+                    .iter()
+                    .filter(|c| !(epub.group_extras && c.special));
+                Self::nav_chapters(&mut nav, chapters, "            ");
+            }
+        }
+
+        // 额外章节单独作为最后一组
+        let extras: Vec<&NavEntry> = if epub.group_extras {
+            nav_list.entries().filter(|c| c.special).collect()
+        } else {
+            Vec::new()
+        };
+        if !extras.is_empty() {
+            nav.push_str(
+                r#"
+            <li><span>番外</span>
+                <ol>"#,
+            );
+            Self::nav_chapters(&mut nav, extras, "                    ");
+            nav.push_str(
+                r#"
+                </ol>
+            </li>"#,
+            );
+        }
+
+        nav.push_str(
+            r#"
+        </ol>
+    </nav>
+</body>
+</html>"#,
+        );
+
+        fs::write(epub.oebps_dir.join(NAV_FILENAME), Self::finish(epub, &nav)).await?;
+        info!("nav.xhtml文件生成完成");
+        Ok(())
+    }
+
+    fn nav_chapters<'a>(
+        nav: &mut String,
+        chapters: impl IntoIterator<Item = &'a NavEntry>,
+        indent: &str,
+    ) {
+        // 与 toc_ncx_chapters 一样直接写入缓冲区
+        for chapter in chapters {
+            let _ = write!(
+                nav,
+                r#"
+{}<li><a href="Text/{}">{}</a></li>"#,
+                indent,
+                chapter.filename,
+                escape_xml(&chapter.title, false)
+            );
+        }
+    }
+
+    /// 生成所有元数据文件, 章节只从导航列表 nav 读取
+    #[instrument(skip_all)]
+    pub async fn generate(&self, epub: &Epub, nav: &NavList) -> Result<()> {
         info!("正在生成EPUB元数据文件");
         // 生成所有元数据文件
         self.mimetype(epub).await?;
         self.container_xml(epub).await?;
-        self.content_opf(epub).await?;
-        self.toc_ncx(epub).await?;
+        if epub.ibooks_specified_fonts || epub.fixed_layout {
+            self.ibooks_display_options(epub).await?;
+        }
+        if let Some(template) = &epub.disclaimer {
+            self.disclaimer(epub, template).await?;
+        }
+        if let Some(back_cover_name) = &epub.back_cover {
+            self.back_cover(epub, back_cover_name).await?;
+        }
+        if !epub.gallery.is_empty() {
+            self.gallery(epub).await?;
+        }
+        self.content_opf(epub, nav).await?;
+        self.toc_ncx(epub, nav).await?;
+        if epub.version == EpubVersion::V3 {
+            self.nav_xhtml(epub, nav).await?;
+        }
 
         info!("EPUB元数据文件已生成");
         Ok(())
@@ -177,11 +522,12 @@ impl Metadata {
 }
 
 impl Metadata {
-    fn opf_header(content_opf: &mut String) {
-        content_opf.push_str(
+    fn opf_header(content_opf: &mut String, epub: &Epub) {
+        content_opf.push_str(&format!(
             r#"<?xml version="1.0" encoding="UTF-8"?>
-<package version="2.0" xmlns="http://www.idpf.org/2007/opf" unique-identifier="BookId">"#,
-        );
+<package version="{}" xmlns="http://www.idpf.org/2007/opf" unique-identifier="BookId">"#,
+            epub.version.as_str()
+        ));
     }
 
     #[instrument(skip_all)]
@@ -192,29 +538,67 @@ impl Metadata {
     <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
         <dc:identifier id="BookId">"#,
         );
-        content_opf.push_str(&epub.id.to_string());
+        content_opf.push_str(&escape_xml(&epub.identifier, false));
         content_opf.push_str(
             r#"</dc:identifier>
         <dc:title>"#,
         );
-        content_opf.push_str(&epub.title);
+        content_opf.push_str(&escape_xml(&epub.title, false));
+        content_opf.push_str(r#"</dc:title>"#);
+
+        // 添加副标题, 兼容模式下旧阅读器可能把第二个dc:title当作书名
+        if let Some(subtitle) = &epub.subtitle
+            && !epub.compat_plain
+        {
+            match epub.version {
+                EpubVersion::V2 => content_opf.push_str(
+                    r#"
+        <dc:title opf:title-type="subtitle">"#,
+                ),
+                EpubVersion::V3 => content_opf.push_str(
+                    r#"
+        <dc:title id="subtitle">"#,
+                ),
+            }
+            content_opf.push_str(&escape_xml(subtitle, false));
+            content_opf.push_str(r#"</dc:title>"#);
+            Self::opf_refines(content_opf, epub, "subtitle", "title-type", "subtitle");
+        }
+
         content_opf.push_str(&format!(
-            r#"</dc:title>
-        <dc:language>{}</dc:language>
-        <dc:creator opf:role="aut">"#,
-            epub.lang
+            r#"
+        <dc:language>{}</dc:language>"#,
+            escape_xml(&epub.lang, false)
         ));
-        content_opf.push_str(&epub.author);
+        match epub.version {
+            EpubVersion::V2 => content_opf.push_str(
+                r#"
+        <dc:creator opf:role="aut">"#,
+            ),
+            EpubVersion::V3 => content_opf.push_str(
+                r#"
+        <dc:creator id="author">"#,
+            ),
+        }
+        content_opf.push_str(&escape_xml(&epub.author, false));
         content_opf.push_str(r#"</dc:creator>"#);
+        Self::opf_refines(content_opf, epub, "author", "role", "aut");
 
         // 添加插画师信息
         if let Some(illustrator) = &epub.illustrator {
-            content_opf.push_str(
-                r#"
+            match epub.version {
+                EpubVersion::V2 => content_opf.push_str(
+                    r#"
         <dc:contributor opf:role="ill">"#,
-            );
-            content_opf.push_str(illustrator);
+                ),
+                EpubVersion::V3 => content_opf.push_str(
+                    r#"
+        <dc:contributor id="illustrator">"#,
+                ),
+            }
+            content_opf.push_str(&escape_xml(illustrator, false));
             content_opf.push_str(r#"</dc:contributor>"#);
+            Self::opf_refines(content_opf, epub, "illustrator", "role", "ill");
         }
 
         // 添加标签
@@ -223,7 +607,7 @@ impl Metadata {
                 r#"
         <dc:subject>"#,
             );
-            content_opf.push_str(tag);
+            content_opf.push_str(&escape_xml(tag, false));
             content_opf.push_str(r#"</dc:subject>"#);
         }
 
@@ -233,7 +617,7 @@ impl Metadata {
                 r#"
         <dc:description>"#,
             );
-            content_opf.push_str(&epub.summary);
+            content_opf.push_str(&escape_xml(&epub.summary, false));
             content_opf.push_str(r#"</dc:description>"#);
         }
 
@@ -243,39 +627,136 @@ impl Metadata {
         <dc:date>"#,
         );
         content_opf.push_str(&chrono::Local::now().format("%Y-%m-%d").to_string());
+        content_opf.push_str(r#"</dc:date>"#);
+        if epub.compat_plain {
+            // 旧阅读器通过 <meta name="cover"> 查找封面
+            if epub.cover.is_some() {
+                content_opf.push_str(
+                    r#"
+        <meta name="cover" content="cover-image"/>"#,
+                );
+            }
+        } else {
+            content_opf.push_str(
+                r#"
+        <meta name="generator" content="novel-fetch"/>"#,
+            );
+        }
+        if epub.version == EpubVersion::V3 {
+            content_opf.push_str(&format!(
+                r#"
+        <meta property="dcterms:modified">{}</meta>"#,
+                chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ")
+            ));
+        }
+        if epub.fixed_layout {
+            content_opf.push_str(
+                r#"
+        <meta property="rendition:layout">pre-paginated</meta>
+        <meta property="rendition:orientation">auto</meta>
+        <meta property="rendition:spread">auto</meta>"#,
+            );
+        }
         content_opf.push_str(
-            r#"</dc:date>
-        <meta name="generator" content="novel-fetch"/>
+            r#"
     </metadata>"#,
         );
         info!("opf的metadata部分生成完成");
     }
 
+    /// EPUB3中用 refines 代替 opf:role 等属性, EPUB2不需要
+    fn opf_refines(content_opf: &mut String, epub: &Epub, id: &str, property: &str, value: &str) {
+        if epub.version != EpubVersion::V3 {
+            return;
+        }
+        let scheme = match property {
+            "role" => r#" scheme="marc:relators""#,
+            _ => "",
+        };
+        content_opf.push_str(&format!(
+            r##"
+        <meta refines="#{}" property="{}"{}>{}</meta>"##,
+            id, property, scheme, value
+        ));
+    }
+
     #[instrument(skip_all)]
-    fn opf_manifest(content_opf: &mut String, epub: &Epub) {
+    fn opf_manifest(content_opf: &mut String, epub: &Epub, nav: &NavList) {
         info!("正在生成opf的manifest部分");
         // manifest内容
         content_opf.push_str(
             r#"
-            <manifest>
+    <manifest>
         <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>"#,
         );
+        if epub.version == EpubVersion::V3 {
+            content_opf.push_str(&format!(
+                r#"
+        <item id="nav" href="{}" media-type="application/xhtml+xml" properties="nav"/>"#,
+                NAV_FILENAME
+            ));
+        }
 
         if let Some(cover_name) = &epub.cover {
+            let properties = match epub.version {
+                EpubVersion::V2 => "",
+                EpubVersion::V3 => r#" properties="cover-image""#,
+            };
             content_opf.push_str(&format!(
                 r#"
-        <item id="cover-image" href="Images/{}" media-type="{}"/>"#,
+        <item id="cover-image" href="Images/{}" media-type="{}"{}/>"#,
                 cover_name,
-                Self::get_media_type(cover_name)
+                Self::get_media_type(cover_name),
+                properties
+            ));
+        }
+
+        if let Some(back_cover_name) = &epub.back_cover {
+            content_opf.push_str(&format!(
+                r#"
+        <item id="back-cover-image" href="Images/{}" media-type="{}"/>
+        <item id="back-cover" href="Text/{}" media-type="application/xhtml+xml"/>"#,
+                back_cover_name,
+                Self::get_media_type(back_cover_name),
+                BACK_COVER_FILENAME
             ));
         }
+        if !epub.gallery.is_empty() {
+            for (index, image_name) in epub.gallery.iter().enumerate() {
+                let _ = write!(
+                    content_opf,
+                    r#"
+        <item id="gallery-img{}" href="Images/{}" media-type="{}"/>"#,
+                    index + 1,
+                    image_name,
+                    Self::get_media_type(image_name)
+                );
+            }
+            let _ = write!(
+                content_opf,
+                r#"
+        <item id="gallery" href="Text/{}" media-type="application/xhtml+xml"/>"#,
+                GALLERY_FILENAME
+            );
+        }
+
+        // 同一张图片(按url缓存的网站横幅、分隔线等)可能被多个章节引用, manifest中只写一次
+        let mut images: HashSet<&str> = epub
+            .cover
+            .iter()
+            .chain(&epub.back_cover)
+            .chain(&epub.gallery)
+            .map(String::as_str)
+            .collect();
 
         // 添加章节文件
-        match &epub.children {
-            VolOrChap::Volumes(volumes) => {
+        match nav {
+            NavList::Volumes(volumes) => {
                 for volume in volumes {
                     // 添加卷封面图片
-                    if let Some(cover_name) = &volume.cover {
+                    if let Some(cover_name) = &volume.cover
+                        && images.insert(cover_name)
+                    {
                         content_opf.push_str(&format!(
                             r#"
         <item id="vol{}-cover-img" href="Images/{}" media-type="{}"/>"#,
@@ -289,82 +770,164 @@ impl Metadata {
                         content_opf.push_str(&format!(
                             r#"
         <item id="vol{}-cover" href="Text/{}" media-type="application/xhtml+xml"/>"#,
-                            volume.index, volume.cover_chapter.filename
+                            volume.index, volume.cover_filename
                         ));
                     }
 
-                    Self::opf_manifest_chapters(content_opf, &volume.chapters, Some(volume.index));
+                    Self::opf_manifest_chapters(
+                        content_opf,
+                        epub,
+                        &volume.entries,
+                        Some(volume.index),
+                        &mut images,
+                    );
                 }
             }
-            VolOrChap::Chapters(chapters) => {
-                Self::opf_manifest_chapters(content_opf, chapters, None);
+            NavList::Chapters(chapters) => {
+                Self::opf_manifest_chapters(content_opf, epub, chapters, None, &mut images);
             }
         }
-        content_opf.push_str(r#"    </manifest>"#);
+        if epub.disclaimer.is_some() {
+            content_opf.push_str(&format!(
+                r#"
+        <item id="disclaimer" href="Text/{}" media-type="application/xhtml+xml"/>"#,
+                DISCLAIMER_FILENAME
+            ));
+        }
+        content_opf.push_str(
+            r#"
+    </manifest>"#,
+        );
         info!("opf的manifest部分生成完成");
     }
 
-    fn opf_manifest_chapters(
+    /// images 为已经写入manifest的图片, 章节插图只写入其中没有的
+    fn opf_manifest_chapters<'a>(
         content_opf: &mut String,
-        chapters: &Vec<Chapter>,
+        epub: &Epub,
+        chapters: &'a [NavEntry],
         volume_index: Option<usize>,
+        images: &mut HashSet<&'a str>,
     ) {
         for chapter in chapters {
             for image_name in &chapter.images {
-                content_opf.push_str(&format!(
+                if !images.insert(image_name) {
+                    continue;
+                }
+                let _ = write!(
+                    content_opf,
                     r#"
         <item id="img-{}" href="Images/{}" media-type="{}"/>"#,
-                    image_name,
+                    image_name.replace('/', "-"),
                     image_name,
                     Self::get_media_type(image_name)
-                ));
+                );
             }
-            if let Some(vol_idx) = volume_index {
-                content_opf.push_str(&format!(
-                    r#"
-        <item id="chap{}-{}" href="Text/{}" media-type="application/xhtml+xml"/>"#,
-                    vol_idx, chapter.index, chapter.filename
-                ));
-            } else {
-                content_opf.push_str(&format!(
+            // 固定版式的章节每页一个文件
+            for page in 0..epub.fixed_pages(&chapter.images).max(1) {
+                let _ = write!(
+                    content_opf,
                     r#"
-        <item id="chap{}" href="Text/{}" media-type="application/xhtml+xml"/>"#,
-                    chapter.index, chapter.filename
-                ));
+        <item id="{}" href="Text/{}" media-type="application/xhtml+xml"/>"#,
+                    Self::chapter_item_id(chapter, volume_index, page),
+                    chapter.page_filename(page)
+                );
             }
         }
     }
 
+    /// 章节在manifest中的id, 固定版式第一页之后的页加上页码
+    fn chapter_item_id(chapter: &NavEntry, volume_index: Option<usize>, page: usize) -> String {
+        let id = match volume_index {
+            Some(vol_idx) => format!("chap{}-{}", vol_idx, chapter.index),
+            None => format!("chap{}", chapter.index),
+        };
+        match page {
+            0 => id,
+            page => format!("{}-p{}", id, page + 1),
+        }
+    }
+
+    /// 固定版式的书中按普通方式排版的页面(卷封面、没有插图的章节等)
+    fn reflowable(epub: &Epub) -> &'static str {
+        if epub.fixed_layout {
+            r#" properties="rendition:layout-reflowable""#
+        } else {
+            ""
+        }
+    }
+
     #[instrument(skip_all)]
-    fn opf_spine(content_opf: &mut String, epub: &Epub) {
+    fn opf_spine(content_opf: &mut String, epub: &Epub, nav: &NavList) {
         info!("正在生成opf的spine部分");
         // spine内容
-        content_opf.push_str(
-            r#"
+        // page-progression-direction 是EPUB3的属性, 兼容模式下不使用
+        match epub.direction.filter(|_| !epub.compat_plain) {
+            Some(direction) => content_opf.push_str(&format!(
+                r#"
+    <spine toc="ncx" page-progression-direction="{}">"#,
+                direction.as_str()
+            )),
+            None => content_opf.push_str(
+                r#"
     <spine toc="ncx">"#,
-        );
+            ),
+        }
+
+        // 彩页放在正文之前
+        if !epub.gallery.is_empty() {
+            content_opf.push_str(&format!(
+                r#"
+        <itemref idref="gallery"{}/>"#,
+                Self::reflowable(epub)
+            ));
+        }
 
         // 添加章节到spine - 按卷的顺序添加
-        match &epub.children {
-            VolOrChap::Volumes(volumes) => {
+        match nav {
+            NavList::Volumes(volumes) => {
                 for volume in volumes {
                     // 没有封面的卷跳过
                     if volume.cover.is_some() {
                         content_opf.push_str(&format!(
                             r#"
-        <itemref idref="vol{}-cover"/>"#,
-                            volume.index
+        <itemref idref="vol{}-cover"{}/>"#,
+                            volume.index,
+                            Self::reflowable(epub)
                         ));
                     }
 
-                    Self::opf_spine_chapters(content_opf, &volume.chapters, Some(volume.index));
+                    Self::opf_spine_chapters(
+                        content_opf,
+                        epub,
+                        &volume.entries,
+                        Some(volume.index),
+                    );
                 }
             }
-            VolOrChap::Chapters(chapters) => {
-                Self::opf_spine_chapters(content_opf, chapters, None);
+            NavList::Chapters(chapters) => {
+                Self::opf_spine_chapters(content_opf, epub, chapters, None);
             }
         }
 
+        // 声明页放在最后
+        if epub.disclaimer.is_some() {
+            content_opf.push_str(&format!(
+                r#"
+        <itemref idref="disclaimer"{}/>"#,
+                Self::reflowable(epub)
+            ));
+        }
+
+        // 封底是最后一页
+        if epub.back_cover.is_some() {
+            content_opf.push_str(&format!(
+                r#"
+        <itemref idref="back-cover"{}/>"#,
+                Self::reflowable(epub)
+            ));
+        }
+
         content_opf.push_str(
             r#"
     </spine>"#,
@@ -374,22 +937,28 @@ impl Metadata {
 
     pub fn opf_spine_chapters(
         content_opf: &mut String,
-        chapters: &Vec<Chapter>,
+        epub: &Epub,
+        chapters: &[NavEntry],
         volume_index: Option<usize>,
     ) {
         for chapter in chapters {
-            if let Some(vol_idx) = volume_index {
-                content_opf.push_str(&format!(
+            let pages = epub.fixed_pages(&chapter.images);
+            if pages == 0 {
+                let _ = write!(
+                    content_opf,
                     r#"
-        <itemref idref="chap{}-{}"/>"#,
-                    vol_idx, chapter.index
-                ));
-            } else {
-                content_opf.push_str(&format!(
+        <itemref idref="{}"{}/>"#,
+                    Self::chapter_item_id(chapter, volume_index, 0),
+                    Self::reflowable(epub)
+                );
+            }
+            for page in 0..pages {
+                let _ = write!(
+                    content_opf,
                     r#"
-        <itemref idref="chap{}"/>"#,
-                    chapter.index
-                ));
+        <itemref idref="{}"/>"#,
+                    Self::chapter_item_id(chapter, volume_index, page)
+                );
             }
         }
     }
@@ -397,21 +966,61 @@ impl Metadata {
     #[instrument(skip_all)]
     fn opf_guide(content_opf: &mut String, epub: &Epub) {
         info!("正在生成opf的guide部分");
-        let Some(cover_name) = &epub.cover else {
+        let mut references = String::new();
+        if let Some(cover_name) = &epub.cover {
+            let _ = write!(
+                references,
+                r#"
+        <reference type="cover" title="Cover" href="Images/{}"/>"#,
+                cover_name
+            );
+        }
+        // guide 只定义了封面, 其他页面使用 "other." 开头的自定义类型
+        if !epub.gallery.is_empty() {
+            let _ = write!(
+                references,
+                r#"
+        <reference type="other.gallery" title="Gallery" href="Text/{}"/>"#,
+                GALLERY_FILENAME
+            );
+        }
+        if epub.back_cover.is_some() {
+            let _ = write!(
+                references,
+                r#"
+        <reference type="other.backcover" title="Back Cover" href="Text/{}"/>"#,
+                BACK_COVER_FILENAME
+            );
+        }
+        if references.is_empty() {
             return;
-        };
-        content_opf.push_str(&format!(
+        }
+        let _ = write!(
+            content_opf,
             r#"
-    <guide>
-        <reference type="cover" title="Cover" href="Images/{}"/>
+    <guide>{}
     </guide>"#,
-            cover_name
-        ));
+            references
+        );
         info!("opf的guide部分生成完成");
     }
 
     fn opf_footer(content_opf: &mut String) {
-        content_opf.push_str(r#"</package>"#);
+        content_opf.push_str(
+            r#"
+</package>"#,
+        );
+    }
+
+    /// 指定 --minify 时去掉用于缩进的空白, 指定 --pretty 时重新缩进
+    fn finish(epub: &Epub, content: &str) -> String {
+        if epub.minify {
+            minify_xml(content)
+        } else if epub.pretty {
+            pretty_xml(content)
+        } else {
+            content.to_string()
+        }
     }
 
     fn get_media_type(filename: &str) -> &str {
@@ -419,8 +1028,184 @@ impl Metadata {
             "image/png"
         } else if filename.ends_with(".jpg") || filename.ends_with(".jpeg") {
             "image/jpeg"
+        } else if filename.ends_with(".gif") {
+            "image/gif"
+        } else if filename.ends_with(".webp") {
+            "image/webp"
+        } else if filename.ends_with(".svg") {
+            "image/svg+xml"
         } else {
             "application/octet-stream"
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::{Chapter, Direction, VolOrChap, test_util};
+
+    fn spine(epub: &Epub) -> String {
+        let mut content_opf = String::new();
+        Metadata::opf_spine(&mut content_opf, epub, &epub.nav());
+        content_opf
+    }
+
+    #[test]
+    fn spine_direction_only_when_configured() {
+        let mut epub = test_util::epub(VolOrChap::Chapters(vec![test_util::chapter(1, "第1章")]));
+        let default_spine = spine(&epub);
+        assert!(default_spine.contains(r#"<spine toc="ncx">"#));
+        assert!(!default_spine.contains("page-progression-direction"));
+
+        epub.direction = Some(Direction::Rtl);
+        assert!(spine(&epub).contains(r#"<spine toc="ncx" page-progression-direction="rtl">"#));
+
+        // 兼容模式只生成EPUB2的结构, 不写入EPUB3的属性
+        epub.compat_plain = true;
+        assert!(!spine(&epub).contains("page-progression-direction"));
+    }
+
+    #[tokio::test]
+    async fn nav_skips_volumes_with_only_extras() {
+        let oebps_dir = test_util::TempDir::new("nav");
+        let extra = |index, title| Chapter {
+            special: true,
+            ..test_util::chapter(index, title)
+        };
+        let mut epub = test_util::epub(VolOrChap::Volumes(vec![
+            test_util::volume(0, "第一卷", vec![test_util::chapter(1, "第一章")]),
+            test_util::volume(1, "特典", vec![extra(2, "特典一"), extra(3, "特典二")]),
+        ]));
+        epub.group_extras = true;
+        epub.oebps_dir = oebps_dir.path().to_path_buf();
+
+        Metadata::new().nav_xhtml(&epub, &epub.nav()).await.unwrap();
+        let nav = tokio::fs::read_to_string(oebps_dir.path().join(NAV_FILENAME))
+            .await
+            .unwrap();
+
+        assert!(nav.contains("<li><span>第一卷</span>"));
+        assert!(!nav.contains("<li><span>特典</span>"));
+        assert!(nav.contains(r#"<li><a href="Text/2.xhtml">"#));
+        let empty_list = regex::Regex::new(r"<ol>\s*</ol>").unwrap();
+        assert!(!empty_list.is_match(&nav), "{}", nav);
+    }
+
+    /// 500章的书生成manifest、spine和toc.ncx章节条目的平均耗时, 运行:
+    /// cargo test --release chapter_entries_500 -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn chapter_entries_500() {
+        const ROUNDS: u32 = 200;
+        let chapters: Vec<Chapter> = (1..=500)
+            .map(|i| Chapter {
+                images: vec![format!("{}.jpg", i)],
+                ..test_util::chapter(i, &format!("第{}章", i))
+            })
+            .collect();
+        let entries: Vec<NavEntry> = chapters.iter().map(NavEntry::from).collect();
+        let epub = test_util::epub(VolOrChap::Chapters(chapters));
+
+        let start = std::time::Instant::now();
+        for _ in 0..ROUNDS {
+            let mut content_opf = String::new();
+            Metadata::opf_manifest_chapters(
+                &mut content_opf,
+                &epub,
+                &entries,
+                None,
+                &mut HashSet::new(),
+            );
+            Metadata::opf_spine_chapters(&mut content_opf, &epub, &entries, None);
+            let mut toc_ncx = String::new();
+            Metadata::toc_ncx_chapters(&mut toc_ncx, &entries, &mut 1);
+            std::hint::black_box((content_opf, toc_ncx));
+        }
+        println!("500章: 平均每次 {:?}", start.elapsed() / ROUNDS);
+    }
+
+    #[test]
+    fn shared_image_written_once() {
+        let shared = |index, title| Chapter {
+            images: vec!["banner.png".to_string()],
+            ..test_util::chapter(index, title)
+        };
+        let epub = test_util::epub(VolOrChap::Volumes(vec![
+            test_util::volume(0, "第一卷", vec![shared(1, "第一章")]),
+            test_util::volume(1, "第二卷", vec![shared(2, "第二章")]),
+        ]));
+
+        let mut content_opf = String::new();
+        Metadata::opf_manifest(&mut content_opf, &epub, &epub.nav());
+        assert_eq!(
+            content_opf.matches(r#"href="Images/banner.png""#).count(),
+            1
+        );
+        assert!(content_opf.contains(r#"<item id="chap0-1" href="Text/1.xhtml""#));
+        assert!(content_opf.contains(r#"<item id="chap1-2" href="Text/2.xhtml""#));
+    }
+
+    /// --stream-chapters 时 children 为空, 章节只来自导航列表
+    #[test]
+    fn chapters_from_nav_list() {
+        let mut epub = test_util::epub(VolOrChap::Chapters(Vec::new()));
+        let chapter = Chapter {
+            number: Some(3),
+            images: vec!["1.jpg".to_string()],
+            ..test_util::chapter(1, "第一章")
+        };
+        epub.nav = Some(NavList::Chapters(vec![NavEntry::from(chapter)]));
+        let nav = epub.nav();
+
+        let mut content_opf = String::new();
+        Metadata::opf_manifest(&mut content_opf, &epub, &nav);
+        Metadata::opf_spine(&mut content_opf, &epub, &nav);
+        assert!(content_opf.contains(r#"<item id="chap1" href="Text/1.xhtml""#));
+        assert!(content_opf.contains(r#"href="Images/1.jpg""#));
+        assert!(content_opf.contains(r#"<itemref idref="chap1"/>"#));
+
+        let mut toc_ncx = String::new();
+        Metadata::toc_ncx_chapters(&mut toc_ncx, nav.entries(), &mut 1);
+        assert!(toc_ncx.contains("<text>3. 第一章</text>"));
+        assert!(toc_ncx.contains(r#"<content src="Text/1.xhtml"/>"#));
+    }
+
+    #[tokio::test]
+    async fn titles_escaped_in_opf_and_ncx() {
+        let oebps_dir = test_util::TempDir::new("ncx_escape");
+        let mut epub = test_util::epub(VolOrChap::Volumes(vec![test_util::volume(
+            0,
+            "上 & 下",
+            vec![test_util::chapter(1, "<序章>")],
+        )]));
+        epub.title = "A & B".to_string();
+        epub.author = "甲 & 乙".to_string();
+        epub.oebps_dir = oebps_dir.path().to_path_buf();
+
+        let mut content_opf = String::new();
+        Metadata::opf_metadata(&mut content_opf, &epub);
+        assert!(content_opf.contains("<dc:title>A &amp; B</dc:title>"));
+        assert!(content_opf.contains("甲 &amp; 乙</dc:creator>"));
+
+        Metadata::new().toc_ncx(&epub, &epub.nav()).await.unwrap();
+        let toc_ncx = tokio::fs::read_to_string(oebps_dir.path().join("toc.ncx"))
+            .await
+            .unwrap();
+        assert!(toc_ncx.contains("<text>A &amp; B</text>"));
+        assert!(toc_ncx.contains("<text>上 &amp; 下</text>"));
+        assert!(toc_ncx.contains("<text>&lt;序章&gt;</text>"));
+    }
+
+    #[test]
+    fn subtitle_escaped_in_opf() {
+        let mut epub = test_util::epub(VolOrChap::Chapters(Vec::new()));
+        epub.subtitle = Some("前篇 & 后篇 <外传>".to_string());
+        for version in [EpubVersion::V2, EpubVersion::V3] {
+            epub.version = version;
+            let mut content_opf = String::new();
+            Metadata::opf_metadata(&mut content_opf, &epub);
+            assert!(content_opf.contains("前篇 &amp; 后篇 &lt;外传&gt;</dc:title>"));
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use crate::epub::chapter::{self, Chapter};
+use crate::epub::{VolOrChap, Volume};
+
+/// 写入文件后的章节在目录中的信息, 不包含url等下载用的字段
+#[derive(Debug, Clone)]
+pub struct NavEntry {
+    pub index: usize,
+    pub title: String, // 目录中显示的标题, 已加上全书连续编号
+    pub filename: String,
+    pub images: Vec<String>, // 章节内的图片, 写入manifest, 固定版式时每张图片一页
+    pub special: bool,
+}
+
+impl NavEntry {
+    /// --fixed-layout 时第 page 页(从0开始)的文件名, 与 Chapter::page_filename 相同
+    pub fn page_filename(&self, page: usize) -> String {
+        chapter::page_filename(&self.filename, page)
+    }
+}
+
+impl From<Chapter> for NavEntry {
+    fn from(chapter: Chapter) -> Self {
+        Self {
+            title: chapter.numbered_title(),
+            index: chapter.index,
+            filename: chapter.filename,
+            images: chapter.images,
+            special: chapter.special,
+        }
+    }
+}
+
+impl From<&Chapter> for NavEntry {
+    fn from(chapter: &Chapter) -> Self {
+        Self {
+            title: chapter.numbered_title(),
+            index: chapter.index,
+            filename: chapter.filename.clone(),
+            images: chapter.images.clone(),
+            special: chapter.special,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NavVolume {
+    pub index: usize,
+    pub title: String,
+    pub cover: Option<String>,  // 卷封面图片, 有封面时才有卷封面页
+    pub cover_filename: String, // 卷封面页的文件名
+    pub entries: Vec<NavEntry>,
+}
+
+impl NavVolume {
+    /// 卷的章节已经单独转换为 entries, 不读取 volume.chapters
+    pub fn new(volume: &Volume, entries: Vec<NavEntry>) -> Self {
+        Self {
+            index: volume.index,
+            title: volume.title.clone(),
+            cover: volume.cover.clone(),
+            cover_filename: volume.cover_chapter.filename.clone(),
+            entries,
+        }
+    }
+}
+
+/// 生成元数据(manifest、spine、toc.ncx、nav.xhtml)只读取的导航列表;
+/// --stream-chapters 时顺序抓取的章节写入文件后直接加入, 不保留目录中的章节
+#[derive(Debug, Clone)]
+pub enum NavList {
+    Volumes(Vec<NavVolume>),
+    Chapters(Vec<NavEntry>),
+}
+
+impl NavList {
+    /// 按阅读顺序的所有章节
+    pub fn entries(&self) -> Box<dyn Iterator<Item = &NavEntry> + '_> {
+        match self {
+            NavList::Volumes(volumes) => Box::new(volumes.iter().flat_map(|v| &v.entries)),
+            NavList::Chapters(entries) => Box::new(entries.iter()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            NavList::Volumes(volumes) => volumes.iter().map(|v| v.entries.len()).sum(),
+            NavList::Chapters(entries) => entries.len(),
+        }
+    }
+
+    /// 与 Epub::remove_extras 相同, 去掉后没有章节的卷也一并去掉
+    pub fn remove_extras(&mut self) -> Vec<NavEntry> {
+        let mut removed = Vec::new();
+        let mut retain = |entries: &mut Vec<NavEntry>| {
+            let (extras, rest) = std::mem::take(entries).into_iter().partition(|e| e.special);
+            *entries = rest;
+            removed.extend::<Vec<NavEntry>>(extras);
+        };
+        match self {
+            NavList::Volumes(volumes) => {
+                volumes.retain_mut(|volume| {
+                    retain(&mut volume.entries);
+                    !volume.entries.is_empty()
+                });
+            }
+            NavList::Chapters(entries) => retain(entries),
+        }
+        removed
+    }
+}
+
+impl From<&VolOrChap> for NavList {
+    fn from(children: &VolOrChap) -> Self {
+        let entries = |chapters: &Vec<Chapter>| chapters.iter().map(NavEntry::from).collect();
+        match children {
+            VolOrChap::Volumes(volumes) => NavList::Volumes(
+                volumes
+                    .iter()
+                    .map(|volume| NavVolume::new(volume, entries(&volume.chapters)))
+                    .collect(),
+            ),
+            VolOrChap::Chapters(chapters) => NavList::Chapters(entries(chapters)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::test_util::{chapter, volume};
+
+    #[test]
+    fn remove_extras_drops_empty_volumes() {
+        let numbered = Chapter {
+            number: Some(1),
+            ..chapter(1, "第一章")
+        };
+        let extra = Chapter {
+            special: true,
+            ..chapter(2, "后记")
+        };
+        let mut nav = NavList::from(&VolOrChap::Volumes(vec![
+            volume(0, "第一卷", vec![numbered]),
+            volume(1, "特典", vec![extra]),
+        ]));
+
+        let removed = nav.remove_extras();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].filename, "2.xhtml");
+        let NavList::Volumes(volumes) = &nav else {
+            panic!("{:?}", nav);
+        };
+        assert_eq!(volumes.len(), 1);
+        assert_eq!(volumes[0].cover_filename, "0_cover.xhtml");
+        // 标题已经加上全书连续编号
+        let titles: Vec<&str> = nav.entries().map(|e| e.title.as_str()).collect();
+        assert_eq!(titles, ["1. 第一章"]);
+    }
+}
@@ -1,8 +1,12 @@
+use serde::{Deserialize, Serialize};
+
+use crate::epub::Doctype;
 use crate::epub::chapter::Chapter;
+use crate::utils::escape_xml;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Volume {
-    // pub title: String,
+    pub title: String,
     pub index: usize,
     pub cover: Option<String>,
     pub chapters: Vec<Chapter>,
@@ -10,18 +14,18 @@ pub struct Volume {
 }
 
 impl Volume {
-    pub fn cover_html(&self) -> String {
+    pub fn cover_html(&self, doctype: Doctype) -> String {
+        let title = escape_xml(&self.title, false);
         let mut xhtml_content = String::new();
 
+        xhtml_content.push_str(doctype.prolog());
         xhtml_content.push_str(
-            r#"<?xml version="1.0" encoding="UTF-8"?>
-<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">
-<html xmlns="http://www.w3.org/1999/xhtml">
+            r#"
 <head>
     <title>"#,
         );
 
-        xhtml_content.push_str(&self.cover_chapter.title);
+        xhtml_content.push_str(&title);
         xhtml_content.push_str(
             r#"</title>
     <meta http-equiv="Content-Type" content="text/html; charset=UTF-8"/>
@@ -31,7 +35,7 @@ impl Volume {
         <h1>"#,
         );
 
-        xhtml_content.push_str(&self.cover_chapter.title);
+        xhtml_content.push_str(&title);
         xhtml_content.push_str(
             r#"</h1>
 "#,
@@ -1,20 +1,28 @@
 pub mod attr;
 pub mod combine;
+pub mod constant;
 pub mod html;
 pub mod list;
 pub mod next;
+pub mod template;
 pub mod text;
 pub mod url;
 pub mod current;
 
+use std::sync::LazyLock;
+
+use clap::ValueEnum;
 use regex::Regex;
 use scraper::{ElementRef, Selector, element_ref::Select};
 use serde::{Deserialize, Deserializer};
+use tracing::warn;
 
 pub use attr::Attr;
 pub use combine::Combine;
+pub use constant::Constant;
 pub use list::List;
 pub use next::Next;
+pub use template::Template;
 pub use text::Text;
 pub use url::Url;
 
@@ -28,6 +36,50 @@ pub enum Value {
     Multiple(Vec<String>),
 }
 
+impl Value {
+    /// 去掉首尾空白, 多值结果中去掉后为空的项会被丢弃
+    pub fn trim(self) -> Self {
+        match self {
+            Value::Empty => Value::Empty,
+            Value::Single(s) => Value::Single(s.trim().to_string()),
+            Value::Multiple(vs) => Value::Multiple(
+                vs.iter()
+                    .map(|v| v.trim())
+                    .filter(|v| !v.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// 单个提取器默认最多处理的元素数量, 正常页面远远达不到, 只用于防止异常页面占用过多内存
+const DEFAULT_MAX_ITEMS: usize = 10_000;
+
+fn default_max_items() -> usize {
+    DEFAULT_MAX_ITEMS
+}
+
+/// 把提取结果加入 results, 总数超过 max_items 时截断并记录警告; 发生截断时返回false
+fn push_limited(results: &mut Vec<String>, value: Value, max_items: usize, name: &str) -> bool {
+    let values = match value {
+        Value::Single(v) => vec![v],
+        Value::Multiple(vs) => vs,
+        Value::Empty => return true,
+    };
+    let room = max_items.saturating_sub(results.len());
+    if values.len() <= room {
+        results.extend(values);
+        return true;
+    }
+    results.extend(values.into_iter().take(room));
+    warn!(
+        "{} 提取到的元素超过上限 {}, 之后的元素被忽略",
+        name, max_items
+    );
+    false
+}
+
 #[typetag::deserialize(tag = "type")]
 pub trait Extractor: Send + Sync {
     fn extract(&self, element: ElementRef) -> Value;
@@ -39,24 +91,115 @@ pub trait Extractor: Send + Sync {
 
 #[derive(Deserialize)]
 pub struct ContentExtractor {
-    #[serde(deserialize_with = "deserialize_selector")]
-    pub this: Selector,
+    /// 可以是单个选择器或选择器列表, 按顺序尝试, 第一个匹配的生效
+    #[serde(deserialize_with = "deserialize_selectors")]
+    pub this: Vec<Selector>,
+    /// 正文分成多个并列的块时, 按顺序合并所有匹配 this 的块, 默认只使用第一个
+    #[serde(default)]
+    pub merge_blocks: bool,
     pub paragraphs: Box<dyn Extractor>,
     pub next_url: Option<Box<dyn Extractor>>,
-    #[serde(default = "default_title_pattern")]
-    pub title_pattern: String,
+    /// "上一页"链接, 配置后顺序抓取前检查目录的第一章之前是否还有页面
+    pub prev_url: Option<Box<dyn Extractor>>,
+    /// 判断页面是否属于某个章节的正则, {title} 会被替换为章节标题;
+    /// 未配置时页面标题等于章节标题或以章节标题开头即视为属于该章节
+    pub title_pattern: Option<String>,
     pub title: Option<Box<dyn Extractor>>,
+    /// 去掉与章节标题相同的第一段, 并合并紧邻的重复段落
+    #[serde(default)]
+    pub dedup_paragraphs: bool,
+    /// 目录链接指向中间页时, 从中间页提取真正的阅读页链接
+    pub reader_url: Option<Box<dyn Extractor>>,
+    /// 章节内容匹配其中任意一个正则时视为错误页(例如"系统繁忙"), 等待后重新获取
+    #[serde(default, deserialize_with = "deserialize_regexes")]
+    pub retry_if_content_matches: Vec<Regex>,
+    /// 章节页需要用POST请求获取时配置, 默认使用GET
+    pub post: Option<PostRequest>,
+}
+
+/// 用POST请求获取章节页, url和body中的 {url} 和 {id} 会被替换为章节的url和id
+///
+/// 章节id为章节url路径的最后一段去掉扩展名, 例如 /read/123.html 的id为 123
+#[derive(Deserialize)]
+pub struct PostRequest {
+    /// 请求的url, 默认为章节的url
+    pub url: Option<String>,
+    pub body: String,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
 }
 
-fn default_title_pattern() -> String {
-    r#"^体育祭开幕(（\d+/\d+）)?$"#.to_string()
+fn default_content_type() -> String {
+    "application/x-www-form-urlencoded".to_string()
+}
+
+impl PostRequest {
+    pub fn chapter_id(chapter_url: &reqwest::Url) -> String {
+        let segment = chapter_url
+            .path_segments()
+            .and_then(|mut segments| segments.rfind(|s| !s.is_empty()))
+            .unwrap_or_default();
+        match segment.rsplit_once('.') {
+            Some((id, _)) if !id.is_empty() => id.to_string(),
+            _ => segment.to_string(),
+        }
+    }
+
+    fn render(template: &str, chapter_url: &reqwest::Url) -> String {
+        template
+            .replace("{url}", chapter_url.as_str())
+            .replace("{id}", &Self::chapter_id(chapter_url))
+    }
+
+    /// 返回请求的url和请求体
+    pub fn build(&self, chapter_url: &reqwest::Url) -> anyhow::Result<(reqwest::Url, String)> {
+        let url = match &self.url {
+            Some(url) => chapter_url.join(&Self::render(url, chapter_url))?,
+            None => chapter_url.clone(),
+        };
+        Ok((url, Self::render(&self.body, chapter_url)))
+    }
 }
 
 impl ContentExtractor {
+    pub fn this<'a>(&self, element: ElementRef<'a>) -> Option<ElementRef<'a>> {
+        self.this
+            .iter()
+            .find_map(|selector| element.select(selector).next())
+    }
+
     pub fn extract_paragraphs<'a>(&self, this: ElementRef<'a>) -> Value {
         self.paragraphs.extract(this)
     }
 
+    /// 提取页面的正文, merge_blocks 为true时合并所有匹配的块, 每块之间换行
+    pub fn extract_content<'a>(&self, root: ElementRef<'a>, this: ElementRef<'a>) -> Value {
+        if !self.merge_blocks {
+            return self.extract_paragraphs(this);
+        }
+
+        let Some(blocks) = self
+            .this
+            .iter()
+            .map(|selector| root.select(selector).collect::<Vec<_>>())
+            .find(|blocks| !blocks.is_empty())
+        else {
+            return Value::Empty;
+        };
+        let paragraphs: Vec<String> = blocks
+            .into_iter()
+            .filter_map(|block| match self.extract_paragraphs(block) {
+                Value::Single(text) => Some(text),
+                _ => None,
+            })
+            .collect();
+        if paragraphs.is_empty() {
+            Value::Empty
+        } else {
+            Value::Single(paragraphs.join("\n"))
+        }
+    }
+
     pub fn extract_next_url<'a>(&self, this: ElementRef<'a>) -> Value {
         match &self.next_url {
             Some(extractor) => extractor.extract(this),
@@ -64,12 +207,48 @@ impl ContentExtractor {
         }
     }
 
+    pub fn extract_prev_url<'a>(&self, this: ElementRef<'a>) -> Value {
+        match &self.prev_url {
+            Some(extractor) => extractor.extract(this),
+            None => Value::Empty,
+        }
+    }
+
     pub fn matches_title(&self, title: &str, target: &str) -> bool {
-        let pattern = self.title_pattern.replace("{title}", title);
+        let title = normalize_title(title);
+        let target = normalize_title(target);
+
+        match &self.title_pattern {
+            Some(pattern) => {
+                // 标题中可能有 ( + 等正则元字符, 需要转义
+                let pattern = pattern.replace("{title}", &regex::escape(&title));
+                match Regex::new(&pattern) {
+                    Ok(regex) => regex.is_match(&target),
+                    Err(e) => {
+                        warn!(
+                            "title_pattern 编译失败, 改为比较标题是否相同: {}: {}",
+                            pattern, e
+                        );
+                        target == title
+                    }
+                }
+            }
+            None => target.starts_with(&title),
+        }
+    }
+
+    /// 返回章节内容匹配到的错误正则
+    pub fn soft_error(&self, content: &str) -> Option<&Regex> {
+        self.retry_if_content_matches
+            .iter()
+            .find(|pattern| pattern.is_match(content))
+    }
 
-        Regex::new(&pattern)
-            .expect("正则表达式编译失败")
-            .is_match(target)
+    pub fn extract_reader_url<'a>(&self, root: ElementRef<'a>) -> Value {
+        match &self.reader_url {
+            Some(extractor) => extractor.extract(root),
+            None => Value::Empty,
+        }
     }
 
     pub fn extract_title<'a>(&self, this: ElementRef<'a>) -> Value {
@@ -80,6 +259,11 @@ impl ContentExtractor {
     }
 }
 
+/// 去掉首尾空白并把连续的空白合并为一个空格
+fn normalize_title(title: &str) -> String {
+    title.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
 // #[derive(Deserialize)]
 // pub struct ChapterExtractor {
 //     #[serde(deserialize_with = "deserialize_selector")]
@@ -96,9 +280,124 @@ pub struct ChapterExtractor {
     pub title: Box<dyn Extractor>,
     pub content_url: Box<dyn Extractor>,
     pub content: ContentExtractor,
+    /// content_url 提取到多个链接时优先使用匹配该正则的, 没有匹配或未配置时使用第一个
+    #[serde(default, deserialize_with = "deserialize_nullable_regex")]
+    pub content_url_prefer: Option<Regex>,
+    /// 按标题中的数字排序章节, 用于页面中章节顺序不可靠的网站
+    #[serde(default)]
+    pub order_by_title_number: bool,
+    /// 提取标题中数字的正则, 使用第一个捕获组(没有时使用整个匹配), 默认取第一段数字
+    #[serde(default, deserialize_with = "deserialize_nullable_regex")]
+    pub title_number_pattern: Option<Regex>,
+    /// 标记番外、后记等额外章节: 章节元素本身或其子元素匹配该选择器
+    #[serde(default, deserialize_with = "deserialize_nullable_selector")]
+    pub special_selector: Option<Selector>,
+    /// 标记番外、后记等额外章节: 章节标题匹配该正则
+    #[serde(default, deserialize_with = "deserialize_nullable_regex")]
+    pub special_text: Option<Regex>,
+    /// 章节的发布时间, sort_by = "published_at" 时用于排序
+    pub published_at: Option<Box<dyn Extractor>>,
+    /// 解析发布时间的chrono格式, 例如 "%Y-%m-%d %H:%M"; 默认尝试RFC 3339、Unix时间戳和常见的日期格式
+    pub published_at_format: Option<String>,
+    /// 章节的排列顺序: "index" 页面中的顺序(默认), "published_at" 按发布时间
+    #[serde(default)]
+    pub sort_by: ChapterSort,
+}
+
+/// 章节的排列顺序, 决定章节的编号和在EPUB中的位置
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ChapterSort {
+    /// 页面中的顺序
+    #[default]
+    Index,
+    /// 按发布时间从早到晚排序, 没有提取到发布时间的章节保持原来的位置;
+    /// 分卷时卷也按其中最早的章节排序, 卷的 order 或 --sort-volumes 不是 page 时以卷的顺序为准
+    PublishedAt,
+}
+
+/// 未配置 published_at_format 时依次尝试的日期时间格式
+const PUBLISHED_AT_FORMATS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y/%m/%d %H:%M:%S",
+    "%Y/%m/%d %H:%M",
+];
+
+/// 未配置 published_at_format 时依次尝试的日期格式
+const PUBLISHED_DATE_FORMATS: &[&str] = &["%Y-%m-%d", "%Y/%m/%d", "%Y.%m.%d", "%Y年%m月%d日"];
+
+/// 把发布时间解析为Unix时间戳(秒), 没有时区的时间按UTC处理, 只用于比较先后
+fn parse_published_at(text: &str, format: Option<&str>) -> Option<i64> {
+    use chrono::{DateTime, NaiveDate, NaiveDateTime};
+
+    let text = text.trim();
+    if let Some(format) = format {
+        return NaiveDateTime::parse_from_str(text, format)
+            .map(|time| time.and_utc().timestamp())
+            .or_else(|_| {
+                NaiveDate::parse_from_str(text, format)
+                    .map(|date| date.and_time(Default::default()).and_utc().timestamp())
+            })
+            .ok();
+    }
+
+    if !text.is_empty() && text.chars().all(|c| c.is_ascii_digit()) {
+        let timestamp: i64 = text.parse().ok()?;
+        // 13位的时间戳为毫秒
+        return Some(if text.len() >= 13 {
+            timestamp / 1000
+        } else {
+            timestamp
+        });
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Some(time.timestamp());
+    }
+    PUBLISHED_AT_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .map(|time| time.and_utc().timestamp())
+        .or_else(|| {
+            PUBLISHED_DATE_FORMATS
+                .iter()
+                .find_map(|format| NaiveDate::parse_from_str(text, format).ok())
+                .map(|date| date.and_time(Default::default()).and_utc().timestamp())
+        })
+}
+
+static DEFAULT_TITLE_NUMBER_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\d+").expect("无法创建默认的章节编号正则"));
+
+/// 用 pattern(未配置时取第一段数字)提取标题中的数字
+fn title_number(pattern: Option<&Regex>, title: &str) -> Option<u64> {
+    let pattern = pattern.unwrap_or(&DEFAULT_TITLE_NUMBER_PATTERN);
+    let captures = pattern.captures(title)?;
+    captures
+        .get(1)
+        .or_else(|| captures.get(0))?
+        .as_str()
+        .parse()
+        .ok()
 }
 
 impl ChapterExtractor {
+    pub fn title_number(&self, title: &str) -> Option<u64> {
+        title_number(self.title_number_pattern.as_ref(), title)
+    }
+
+    pub fn is_special(&self, this: ElementRef, title: &str) -> bool {
+        let by_selector = self.special_selector.as_ref().is_some_and(|selector| {
+            selector.matches(&this) || this.select(selector).next().is_some()
+        });
+        let by_text = self
+            .special_text
+            .as_ref()
+            .is_some_and(|pattern| pattern.is_match(title));
+        by_selector || by_text
+    }
+
     pub fn extract_title(&self, this: ElementRef) -> Value {
         self.title.extract(this)
     }
@@ -107,6 +406,29 @@ impl ChapterExtractor {
         self.content_url.extract(this)
     }
 
+    /// 提取并解析章节的发布时间, 未配置、没有提取到或无法解析时返回None
+    pub fn published_at(&self, this: ElementRef) -> Option<i64> {
+        let text = match self.published_at.as_ref()?.extract(this).trim() {
+            Value::Single(text) => text,
+            Value::Multiple(texts) => texts.into_iter().next()?,
+            Value::Empty => return None,
+        };
+        let published_at = parse_published_at(&text, self.published_at_format.as_deref());
+        if published_at.is_none() {
+            warn!("无法解析章节的发布时间: {}", text);
+        }
+        published_at
+    }
+
+    /// 从多个候选的内容链接中选择一个
+    pub fn pick_content_url(&self, urls: &[String]) -> Option<String> {
+        self.content_url_prefer
+            .as_ref()
+            .and_then(|prefer| urls.iter().find(|url| prefer.is_match(url)))
+            .or_else(|| urls.first())
+            .cloned()
+    }
+
     // pub fn extract_paragraphs(&self, this: ElementRef) -> Value {
     //     self.paragraphs.extract(this)
     // }
@@ -119,9 +441,36 @@ pub struct VolumeExtractor {
     pub title: Box<dyn Extractor>,
     pub cover_url: Option<Box<dyn Extractor>>,
     pub chapters: ChapterExtractor,
+    /// 卷的排列顺序, 页面中的卷从新到旧排列或顺序不可靠时配置
+    #[serde(default)]
+    pub order: VolumeOrder,
+    /// order = "number" 时提取标题中数字的正则, 规则与章节的 title_number_pattern 相同
+    #[serde(default, deserialize_with = "deserialize_nullable_regex")]
+    pub title_number_pattern: Option<Regex>,
+    /// 合并被拆成多个部分的卷, 例如 '^(第.+卷)' 把"第一卷 上"和"第一卷 下"合并为"第一卷":
+    /// 标题的第一个捕获组(没有时为整个匹配)相同的卷合并, 章节按顺序连续编号
+    #[serde(default, deserialize_with = "deserialize_nullable_regex")]
+    pub merge_pattern: Option<Regex>,
+}
+
+/// 卷的排列顺序, 决定卷的编号和在EPUB中的位置
+#[derive(Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeOrder {
+    /// 页面中的顺序
+    #[default]
+    Page,
+    /// 与页面中的顺序相反
+    Reverse,
+    /// 按标题中的数字排序, 标题中没有数字的卷保持原来的位置
+    Number,
 }
 
 impl VolumeExtractor {
+    pub fn title_number(&self, title: &str) -> Option<u64> {
+        title_number(self.title_number_pattern.as_ref(), title)
+    }
+
     pub fn extract_title(&self, this: ElementRef) -> Value {
         self.title.extract(this)
     }
@@ -143,16 +492,55 @@ pub struct BookExtractor {
     #[serde(deserialize_with = "deserialize_selector")]
     pub this: Selector,
     pub title: Box<dyn Extractor>,
+    /// 副标题或别名, 例如长标题对应的常用简称
+    pub subtitle: Option<Box<dyn Extractor>>,
     pub author: Box<dyn Extractor>,
     pub illustrator: Option<Box<dyn Extractor>>,
     pub tags: Option<Box<dyn Extractor>>,
     pub summary: Option<Box<dyn Extractor>>,
     pub cover_url: Option<Box<dyn Extractor>>,
+    /// 封底图片, 放在书的最后一页
+    pub back_cover_url: Option<Box<dyn Extractor>>,
+    /// 彩页插图(例如跨页插图), 可以提取到多个, 集中放在书的开头
+    pub gallery: Option<Box<dyn Extractor>>,
     pub volumes: Option<VolumeExtractor>,
     pub chapters: Option<ChapterExtractor>,
+    /// 完整的章节列表在单独的页面时配置, 卷和章节从该页面解析;
+    /// {url} 会被替换为小说页面的url, {id} 为小说url路径的最后一段去掉扩展名, 可以是相对链接
+    pub chapter_list_url: Option<String>,
+    /// 从所有卷和章节标题中去掉匹配该正则的部分, 例如 '^正文\s*' 去掉每个章节标题前相同的前缀;
+    /// 在排序、合并卷等处理之后应用, 去掉后为空的标题保持不变
+    #[serde(default, deserialize_with = "deserialize_nullable_regex")]
+    pub strip_title: Option<Regex>,
+}
+
+/// 去掉标题中匹配 pattern 的部分, 去掉后为空时返回原标题
+pub fn strip_title(pattern: Option<&Regex>, title: &str) -> String {
+    let Some(pattern) = pattern else {
+        return title.to_string();
+    };
+    let stripped = pattern.replace_all(title, "");
+    let stripped = stripped.trim();
+    if stripped.is_empty() {
+        title.to_string()
+    } else {
+        stripped.to_string()
+    }
 }
 
 impl BookExtractor {
+    /// 按 chapter_list_url 模板生成章节列表页的url, 未配置时返回None
+    pub fn build_chapter_list_url(
+        &self,
+        novel_url: &reqwest::Url,
+    ) -> Option<anyhow::Result<reqwest::Url>> {
+        let template = self.chapter_list_url.as_ref()?;
+        let url = template
+            .replace("{url}", novel_url.as_str().trim_end_matches('/'))
+            .replace("{id}", &PostRequest::chapter_id(novel_url));
+        Some(novel_url.join(&url).map_err(Into::into))
+    }
+
     pub fn this<'a>(&self, element: ElementRef<'a>) -> Option<ElementRef<'a>> {
         element.select(&self.this).next()
     }
@@ -161,6 +549,13 @@ impl BookExtractor {
         self.title.extract(this)
     }
 
+    pub fn extract_subtitle(&self, this: ElementRef) -> Value {
+        match &self.subtitle {
+            Some(subtitle_extractor) => subtitle_extractor.extract(this),
+            None => Value::Empty,
+        }
+    }
+
     pub fn extract_author(&self, this: ElementRef) -> Value {
         self.author.extract(this)
     }
@@ -192,6 +587,57 @@ impl BookExtractor {
             None => Value::Empty,
         }
     }
+
+    pub fn extract_back_cover_url(&self, this: ElementRef) -> Value {
+        match &self.back_cover_url {
+            Some(back_cover_extractor) => back_cover_extractor.extract(this),
+            None => Value::Empty,
+        }
+    }
+
+    pub fn extract_gallery(&self, this: ElementRef) -> Value {
+        match &self.gallery {
+            Some(gallery_extractor) => gallery_extractor.extract(this),
+            None => Value::Empty,
+        }
+    }
+}
+
+/// 解析CSS选择器, 失败时指出从jQuery/BeautifulSoup/Scrapy照搬过来的不支持的写法
+fn parse_selector(s: &str) -> Result<Selector, String> {
+    Selector::parse(s).map_err(|e| match unsupported_selector_hint(s) {
+        Some(hint) => format!("无效的选择器 '{}': {}", s, hint),
+        None => format!("无效的选择器 '{}': {}", s, e),
+    })
+}
+
+fn unsupported_selector_hint(s: &str) -> Option<&'static str> {
+    let s = s.trim().to_lowercase();
+    // 伪类后面紧跟字母或'-'时是另一个伪类, 例如 :first-child
+    let has_pseudo = |name: &str| {
+        s.match_indices(name).any(|(index, _)| {
+            !s[index + name.len()..].starts_with(|c: char| c == '-' || c.is_alphanumeric())
+        })
+    };
+
+    let jquery_functions = [":eq(", ":gt(", ":lt("];
+    let jquery_pseudos = [":first", ":last", ":even", ":odd"];
+    let jquery_position = jquery_functions.iter().any(|name| s.contains(name))
+        || jquery_pseudos.into_iter().any(has_pseudo);
+
+    if s.starts_with('/') || s.starts_with("(/") {
+        Some("scraper只支持CSS选择器, 不支持XPath")
+    } else if s.contains(":contains(") {
+        Some("scraper不支持:contains, 请用condition字段按文本筛选")
+    } else if s.contains("::text") || s.contains("::attr(") {
+        Some("scraper不支持::text/::attr(), 请用Text/Attr提取器")
+    } else if jquery_position {
+        Some("scraper不支持jQuery的:eq()/:first/:last等, 请用:nth-child()/:first-child等标准伪类")
+    } else if has_pseudo(":visible") || has_pseudo(":hidden") {
+        Some("抓取时没有样式信息, 不支持:visible/:hidden")
+    } else {
+        None
+    }
 }
 
 fn deserialize_selector<'de, D>(deserializer: D) -> Result<Selector, D::Error>
@@ -200,7 +646,32 @@ where
 {
     let s = String::deserialize(deserializer)?;
 
-    Selector::parse(&s).map_err(|e| serde::de::Error::custom(format!("Invalid selector: {}", e)))
+    parse_selector(&s).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_selectors<'de, D>(deserializer: D) -> Result<Vec<Selector>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let strs = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    };
+
+    if strs.is_empty() {
+        return Err(serde::de::Error::custom("选择器列表不能为空"));
+    }
+
+    strs.iter()
+        .map(|s| parse_selector(s).map_err(serde::de::Error::custom))
+        .collect()
 }
 
 fn deserialize_nullable_selector<'de, D>(deserializer: D) -> Result<Option<Selector>, D::Error>
@@ -211,9 +682,84 @@ where
 
     match option_str {
         Some(s) if s.trim().is_empty() => Ok(None), // 空字符串也视为 None
-        Some(s) => Selector::parse(&s)
+        Some(s) => parse_selector(&s)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn deserialize_nullable_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let option_str: Option<String> = Option::deserialize(deserializer)?;
+
+    match option_str {
+        Some(s) if s.is_empty() => Ok(None),
+        Some(s) => Regex::new(&s)
             .map(Some)
-            .map_err(|e| serde::de::Error::custom(format!("Invalid selector '{}': {}", s, e))),
+            .map_err(|e| serde::de::Error::custom(format!("Invalid regex '{}': {}", s, e))),
         None => Ok(None),
     }
 }
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+
+    Regex::new(&s).map_err(|e| serde::de::Error::custom(format!("Invalid regex '{}': {}", s, e)))
+}
+
+fn deserialize_regexes<'de, D>(deserializer: D) -> Result<Vec<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let strs: Vec<String> = Vec::deserialize(deserializer)?;
+
+    strs.iter()
+        .map(|s| {
+            Regex::new(s)
+                .map_err(|e| serde::de::Error::custom(format!("Invalid regex '{}': {}", s, e)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn post_request_body() {
+        let post = PostRequest {
+            url: Some("/api/chapter?book={id}".to_string()),
+            body: "id={id}&url={url}".to_string(),
+            content_type: default_content_type(),
+        };
+        let chapter_url = reqwest::Url::parse("https://example.com/read/123.html").unwrap();
+
+        let (url, body) = post.build(&chapter_url).unwrap();
+        assert_eq!(url.as_str(), "https://example.com/api/chapter?book=123");
+        assert_eq!(body, "id=123&url=https://example.com/read/123.html");
+
+        // 未配置url时POST到章节页本身
+        let post = PostRequest { url: None, ..post };
+        let (url, _) = post.build(&chapter_url).unwrap();
+        assert_eq!(url, chapter_url);
+    }
+
+    #[test]
+    fn strip_chapter_number_prefix() {
+        let pattern = Regex::new(r"^第\d+章\s*").unwrap();
+        let strip = |title| strip_title(Some(&pattern), title);
+
+        assert_eq!(strip("第12章 重逢"), "重逢");
+        assert_eq!(strip("第3章　雨夜"), "雨夜");
+        assert_eq!(strip("序章 开端"), "序章 开端");
+        // 去掉后为空的标题保持不变
+        assert_eq!(strip("第3章"), "第3章");
+        assert_eq!(strip_title(None, "第12章 重逢"), "第12章 重逢");
+    }
+}
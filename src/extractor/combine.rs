@@ -1,47 +1,180 @@
 use scraper::ElementRef;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 
-use super::{Extractor, List, Value};
+use super::{Extractor, List, Value, default_max_items, push_limited};
+use crate::utils::escape_xml;
+
+/// 分隔符和项目插入结果的方式
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SeparatorMode {
+    /// 分隔符和项目都原样拼接, 由使用结果的地方决定是文本还是HTML
+    #[default]
+    Raw,
+    /// 分隔符是文本, 其中的 < & 等会被转义
+    Text,
+    /// 分隔符是HTML(例如 "<br/>"), 项目是文本并被转义, 用于把Text提取的多段文字合并为HTML
+    Html,
+}
 
 #[derive(Deserialize)]
 pub struct Combine {
+    /// 支持转义序列 \n、\t 和 \\, 在TOML的单引号字符串中也能写换行
+    #[serde(deserialize_with = "deserialize_escaped")]
     separator: String,
+    /// 分隔符按文本还是HTML处理, 默认原样拼接
+    #[serde(default)]
+    separator_mode: SeparatorMode,
     items: List,
+    /// 去掉空的和只有空白的项目, 避免拼接出重复的分隔符; 项目本身的内容保持不变
+    #[serde(default)]
+    compact: bool,
+    /// 最多合并的项目数量, 超过时截断并警告
+    #[serde(default = "default_max_items")]
+    max_items: usize,
 }
 
-#[typetag::deserialize]
-impl Extractor for Combine {
-    fn extract(&self, element: ElementRef) -> Value {
+impl Combine {
+    fn combine(&self, value: Value) -> Value {
         let mut combined = Vec::new();
 
-        let value = self.items.extract(element);
-        match value {
-            Value::Single(v) => combined.push(v),
-            Value::Multiple(vs) => combined.extend(vs),
-            Value::Empty => (),
+        push_limited(&mut combined, value, self.max_items, "Combine");
+
+        if self.compact {
+            combined.retain(|item| !item.trim().is_empty());
         }
 
         if combined.is_empty() {
-            Value::Empty
-        } else {
-            Value::Single(combined.join(&self.separator))
+            return Value::Empty;
+        }
+
+        let separator = match self.separator_mode {
+            SeparatorMode::Raw | SeparatorMode::Html => self.separator.clone(),
+            SeparatorMode::Text => escape_xml(&self.separator, false),
+        };
+        if self.separator_mode == SeparatorMode::Html {
+            for item in &mut combined {
+                *item = escape_xml(item, false);
+            }
         }
+
+        Value::Single(combined.join(&separator))
+    }
+}
+
+#[typetag::deserialize]
+impl Extractor for Combine {
+    fn extract(&self, element: ElementRef) -> Value {
+        self.combine(self.items.extract(element))
     }
 
     fn extract_all(&self, element: ElementRef) -> Value {
-        let mut combined = Vec::new();
+        self.combine(self.items.extract_all(element))
+    }
+}
 
-        let value = self.items.extract_all(element);
-        match value {
-            Value::Single(v) => combined.push(v),
-            Value::Multiple(vs) => combined.extend(vs),
-            Value::Empty => (),
+/// 把 \n、\t、\\ 转换为对应的字符, 其他反斜杠保持原样
+fn deserialize_escaped<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
         }
-
-        if combined.is_empty() {
-            Value::Empty
-        } else {
-            Value::Single(combined.join(&self.separator))
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
         }
     }
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use scraper::Html;
+
+    use super::*;
+    use crate::epub::test_util::from_toml;
+
+    #[test]
+    fn compact_drops_empty_items() {
+        let html = Html::parse_fragment(
+            "<div><p>第一段\n\n第二段</p><p></p><p> \n </p><p>第三段</p></div>",
+        );
+        let extract = |compact| {
+            from_toml::<Combine>(&format!(
+                r#"
+                separator = "\n"
+                compact = {}
+                items = {{ selector = "div", item = {{ type = "Text", selector = "p" }} }}
+                "#,
+                compact
+            ))
+            .extract(html.root_element())
+        };
+
+        // 项目中的空行不是多余的分隔符, 保持不变
+        assert_eq!(
+            extract(true),
+            Value::Single("第一段\n\n第二段\n第三段".to_string())
+        );
+        assert_eq!(
+            extract(false),
+            Value::Single("第一段\n\n第二段\n\n \n \n第三段".to_string())
+        );
+
+        let empty = Html::parse_fragment("<div><p></p><p> </p></div>");
+        let compact: Combine = from_toml(
+            r#"
+            separator = "\n"
+            compact = true
+            items = { selector = "div", item = { type = "Text", selector = "p" } }
+            "#,
+        );
+        assert_eq!(compact.extract(empty.root_element()), Value::Empty);
+    }
+
+    #[test]
+    fn html_mode_escapes_items_not_separator() {
+        let html = Html::parse_fragment("<div><p>a < b</p><p>Tom & Jerry</p></div>");
+        let combine: Combine = from_toml(
+            r#"
+            separator = '<br/>'
+            separator_mode = "html"
+            items = { selector = "div", item = { type = "Text", selector = "p" } }
+            "#,
+        );
+        assert_eq!(
+            combine.extract(html.root_element()),
+            Value::Single("a &lt; b<br/>Tom &amp; Jerry".to_string())
+        );
+    }
+
+    #[test]
+    fn escape_sequences_in_single_quoted_separator() {
+        // 单引号字符串中 \n 不会被TOML转义, 由 deserialize_escaped 转换为换行
+        let html = Html::parse_fragment("<div><p>第一段</p><p>第二段</p></div>");
+        let combine: Combine = from_toml(
+            r#"
+            separator = '\n'
+            items = { selector = "div", item = { type = "Text", selector = "p" } }
+            "#,
+        );
+        assert_eq!(combine.separator, "\n");
+        assert_eq!(
+            combine.extract(html.root_element()),
+            Value::Single("第一段\n第二段".to_string())
+        );
+    }
 }
@@ -0,0 +1,21 @@
+use scraper::ElementRef;
+use serde::Deserialize;
+
+use super::{Extractor, Value};
+
+/// 不读取页面, 总是返回配置的固定值
+#[derive(Debug, Deserialize)]
+pub struct Constant {
+    value: String,
+}
+
+#[typetag::deserialize]
+impl Extractor for Constant {
+    fn extract(&self, _element: ElementRef) -> Value {
+        Value::Single(self.value.clone())
+    }
+
+    fn extract_all(&self, _element: ElementRef) -> Value {
+        Value::Multiple(vec![self.value.clone()])
+    }
+}
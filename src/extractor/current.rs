@@ -1,7 +1,7 @@
 use scraper::{ElementRef, Selector};
 use serde::Deserialize;
 
-use super::{Extractor, Value, deserialize_selector};
+use super::{Extractor, Value, default_max_items, deserialize_selector, push_limited};
 
 #[derive(Deserialize)]
 pub struct Current {
@@ -9,6 +9,9 @@ pub struct Current {
     base: Selector,
     condition: Option<String>,
     current: Box<dyn Extractor>,
+    /// 最多保留的元素数量, 超过时截断并警告
+    #[serde(default = "default_max_items")]
+    max_items: usize,
 }
 
 #[typetag::deserialize]
@@ -36,10 +39,9 @@ impl Extractor for Current {
                 }
             }
 
-            match self.current.extract(base_elem) {
-                Value::Single(v) => results.push(v),
-                Value::Multiple(vs) => results.extend(vs),
-                Value::Empty => (),
+            let value = self.current.extract(base_elem);
+            if !push_limited(&mut results, value, self.max_items, "Current") {
+                break;
             }
         }
 
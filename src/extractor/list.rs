@@ -1,13 +1,16 @@
 use scraper::{ElementRef, Selector};
 use serde::Deserialize;
 
-use super::{Extractor, Value, deserialize_selector};
+use super::{Extractor, Value, default_max_items, deserialize_selector, push_limited};
 
 #[derive(Deserialize)]
 pub struct List {
     #[serde(deserialize_with = "deserialize_selector")]
     selector: Selector,
     item: Box<dyn Extractor>,
+    /// 最多保留的元素数量, 超过时截断并警告
+    #[serde(default = "default_max_items")]
+    max_items: usize,
 }
 
 #[typetag::deserialize]
@@ -20,12 +23,7 @@ impl Extractor for List {
         };
 
         let value = self.item.extract_all(container);
-
-        match value {
-            Value::Single(v) => results.push(v),
-            Value::Multiple(vs) => results.extend(vs),
-            Value::Empty => (),
-        }
+        push_limited(&mut results, value, self.max_items, "List");
 
         if results.is_empty() {
             Value::Empty
@@ -39,10 +37,8 @@ impl Extractor for List {
 
         for container in element.select(&self.selector) {
             let value = self.item.extract_all(container);
-            match value {
-                Value::Single(v) => results.push(v),
-                Value::Multiple(vs) => results.extend(vs),
-                Value::Empty => (),
+            if !push_limited(&mut results, value, self.max_items, "List") {
+                break;
             }
         }
 
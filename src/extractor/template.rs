@@ -0,0 +1,113 @@
+use regex::Regex;
+use scraper::ElementRef;
+use serde::Deserialize;
+
+use super::{Extractor, Value, deserialize_regex};
+
+/// 用正则从内部提取器的结果中取出id, 填入模板生成新的值;
+/// 例如链接是 `<a href="javascript:void(0)" onclick="read(99)">` 时,
+/// 从 onclick 中取出章节id, 再按 template = "/read/{id}" 生成真正的链接
+#[derive(Deserialize)]
+pub struct Template {
+    inner: Box<dyn Extractor>,
+    /// 使用名为 id 的捕获组, 没有时使用第一个捕获组, 都没有时使用整个匹配
+    #[serde(deserialize_with = "deserialize_regex")]
+    pattern: Regex,
+    /// 其中的 {id} 被替换为取出的id
+    template: String,
+}
+
+impl Template {
+    /// 不匹配正则的值被丢弃
+    fn fill(&self, value: &str) -> Option<String> {
+        let captures = self.pattern.captures(value)?;
+        let id = captures
+            .name("id")
+            .or_else(|| captures.get(1))
+            .or_else(|| captures.get(0))?;
+        Some(self.template.replace("{id}", id.as_str()))
+    }
+}
+
+#[typetag::deserialize]
+impl Extractor for Template {
+    fn extract(&self, element: ElementRef) -> Value {
+        match self.inner.extract(element) {
+            Value::Single(value) => self.fill(&value).map_or(Value::Empty, Value::Single),
+            Value::Multiple(values) => {
+                let filled: Vec<String> = values.iter().filter_map(|v| self.fill(v)).collect();
+                match filled.len() {
+                    0 => Value::Empty,
+                    1 => Value::Single(filled.into_iter().next().unwrap()),
+                    _ => Value::Multiple(filled),
+                }
+            }
+            Value::Empty => Value::Empty,
+        }
+    }
+
+    fn extract_all(&self, element: ElementRef) -> Value {
+        let filled: Vec<String> = match self.inner.extract_all(element) {
+            Value::Single(value) => self.fill(&value).into_iter().collect(),
+            Value::Multiple(values) => values.iter().filter_map(|v| self.fill(v)).collect(),
+            Value::Empty => Vec::new(),
+        };
+        if filled.is_empty() {
+            Value::Empty
+        } else {
+            Value::Multiple(filled)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use scraper::Html;
+
+    use super::*;
+    use crate::epub::test_util::from_toml;
+
+    #[test]
+    fn fill_id_from_onclick() {
+        let html = Html::parse_fragment(
+            r#"<ul>
+            <li><a href="javascript:void(0)" onclick="read(99)">第一章</a></li>
+            <li><a href="javascript:void(0)" onclick="read(100)">第二章</a></li>
+            <li><a href="javascript:void(0)">没有id</a></li>
+            </ul>"#,
+        );
+        let read: Template = from_toml(
+            r#"
+            pattern = 'read\((\d+)\)'
+            template = "/read/{id}"
+            inner = { type = "Attr", selector = "a", name = "onclick" }
+            "#,
+        );
+
+        assert_eq!(
+            read.extract(html.root_element()),
+            Value::Single("/read/99".to_string())
+        );
+        // 不匹配正则的值被丢弃
+        assert_eq!(
+            read.extract_all(html.root_element()),
+            Value::Multiple(vec!["/read/99".to_string(), "/read/100".to_string()])
+        );
+    }
+
+    #[test]
+    fn named_capture_group_preferred() {
+        let html = Html::parse_fragment(r#"<a onclick="open(3, 42)">第一章</a>"#);
+        let read: Template = from_toml(
+            r#"
+            pattern = 'open\((\d+), (?<id>\d+)\)'
+            template = "/read/{id}"
+            inner = { type = "Attr", selector = "a", name = "onclick" }
+            "#,
+        );
+        assert_eq!(
+            read.extract(html.root_element()),
+            Value::Single("/read/42".to_string())
+        );
+    }
+}
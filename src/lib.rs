@@ -1,10 +1,13 @@
+pub mod cli;
 pub mod config;
 pub mod crawler;
 pub mod epub;
 pub mod extractor;
 pub mod logger;
+#[cfg(feature = "tui")]
+pub mod tui;
 pub mod utils;
 
-pub use crawler::DoclnCrawler;
+pub use crawler::{DoclnCrawler, DoclnCrawlerBuilder};
 pub use epub::{Chapter, Epub, Volume};
 pub use utils::get_user_input;
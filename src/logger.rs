@@ -1,12 +1,53 @@
+use std::io::{self, Write};
+use std::sync::LazyLock;
+
+use indicatif::MultiProgress;
 use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
-pub fn init() {
+/// 所有进度条都加入这里, 输出日志时先隐藏进度条, 避免日志和进度条混在一起
+static PROGRESS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+pub fn progress() -> &'static MultiProgress {
+    &PROGRESS
+}
+
+/// quiet 为true时默认只输出警告和错误, 仍可以通过 RUST_LOG 覆盖
+pub fn init(quiet: bool) {
     let tracing_subscriber = tracing_subscriber::registry();
-    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let default_level = if quiet { "warn" } else { "info" };
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level));
     let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(ProgressWriter)
         .with_thread_ids(true)
         .with_target(false);
     tracing_subscriber.with(filter).with(fmt).init();
 }
+
+/// 写入stderr, 写入期间暂停进度条的绘制
+struct ProgressWriter;
+
+impl Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        PROGRESS.suspend(|| io::stderr().write(buf))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        PROGRESS.suspend(|| io::stderr().write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for ProgressWriter {
+    type Writer = ProgressWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        ProgressWriter
+    }
+}
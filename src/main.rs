@@ -1,26 +1,95 @@
+use std::sync::Arc;
+
 use anyhow::Result;
+use clap::Parser;
+use tracing::error;
 
+use docln_fetch::cli::{Cli, Command};
 use docln_fetch::config::get_site_config;
+#[cfg(feature = "tui")]
+use docln_fetch::tui;
 use docln_fetch::{DoclnCrawler, get_user_input, logger};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    logger::init();
+    let cli = Cli::parse();
+    logger::init(cli.options.quiet);
 
-    loop {
-        println!("\n=== docln-fetch ===");
-        let site = get_user_input("请输入要爬取的网站")?;
+    if let Some(Command::TestSite { site, id }) = &cli.command {
+        let crawler = DoclnCrawler::builder()
+            .site(site)
+            .id(id)
+            .options(cli.options.clone())
+            .build()?;
+        if !crawler.test_site().await? {
+            anyhow::bail!("网站配置 {} 检查未通过", site);
+        }
+        return Ok(());
+    }
+
+    let options = Arc::new(cli.options.clone());
+    // 爬取失败的小说, 单本失败不影响后续小说, 最后统一报告
+    let mut failures = Vec::new();
 
-        let (id, url) = get_site_config(&site)?.build_url();
+    loop {
+        let site = match &cli.site {
+            Some(site) => site.clone(),
+            #[cfg(feature = "tui")]
+            None if cli.tui => tui::select_site()?,
+            None => {
+                println!("\n=== docln-fetch ===");
+                get_user_input("请输入要爬取的网站")?
+            }
+        };
 
-        let crawler = DoclnCrawler::new(url, &site);
+        let site_config = match get_site_config(&site) {
+            Ok(site_config) => site_config,
+            Err(e) if cli.is_interactive() => {
+                println!("无法获取网站配置 {}: {}", site, e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let (id, url) = match &cli.id {
+            Some(id) => (Some(id.clone()), site_config.build_url_with_id(id)),
+            #[cfg(feature = "tui")]
+            None if cli.tui => tui::input_novel(&site_config)?,
+            None => site_config.build_url()?,
+        };
 
         let Some(id) = id else {
             println!("没有找到小说id, 请重试");
             continue;
         };
 
-        crawler.crawl(id, site).await?;
+        let novel = format!("{}_{}", site, id);
+        let result = async {
+            let crawler = DoclnCrawler::new(url, id, &site, options.clone())?;
+            if options.list_chapters_json {
+                let json = crawler.list_chapters_json().await?;
+                println!("{}", json);
+                Ok(())
+            } else {
+                crawler.crawl().await
+            }
+        }
+        .await;
+        if let Err(e) = result {
+            error!("爬取 {} 失败: {:#}", novel, e);
+            failures.push((novel, e));
+        }
+
+        if !cli.is_interactive() {
+            break;
+        }
+
+        #[cfg(feature = "tui")]
+        if cli.tui {
+            if !tui::confirm_continue()? {
+                break;
+            }
+            continue;
+        }
 
         let continue_choice = get_user_input("是否继续爬取其他小说? (y/n): ")?;
 
@@ -29,5 +98,12 @@ async fn main() -> Result<()> {
         }
     }
 
-    Ok(())
+    if failures.is_empty() {
+        return Ok(());
+    }
+    eprintln!("\n以下 {} 本小说爬取失败:", failures.len());
+    for (novel, e) in &failures {
+        eprintln!("  {}: {:#}", novel, e);
+    }
+    anyhow::bail!("{} 本小说爬取失败", failures.len())
 }
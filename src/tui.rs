@@ -0,0 +1,36 @@
+use anyhow::Result;
+use dialoguer::theme::ColorfulTheme;
+use dialoguer::{Confirm, Input, Select};
+
+use crate::config::{SiteConfig, site_names};
+
+/// 从 config/ 目录中的网站配置里选择一个
+pub fn select_site() -> Result<String> {
+    let names = site_names()?;
+    if names.is_empty() {
+        anyhow::bail!("config/ 目录中没有网站配置");
+    }
+    let index = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("选择要爬取的网站")
+        .items(&names)
+        .default(0)
+        .interact()?;
+    Ok(names[index].to_string())
+}
+
+/// 逐个输入网站 base_url 中的参数, 返回小说id和url; 输入完整的小说url时从中取出参数
+pub fn input_novel(site_config: &SiteConfig) -> Result<(Option<String>, String)> {
+    site_config.build_url_with(|param| {
+        Ok(Input::<String>::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("输入 {} (也可以粘贴小说页面的url)", param))
+            .interact_text()?)
+    })
+}
+
+/// 一本小说完成后询问是否继续爬取
+pub fn confirm_continue() -> Result<bool> {
+    Ok(Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("是否继续爬取其他小说?")
+        .default(true)
+        .interact()?)
+}
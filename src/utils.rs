@@ -11,3 +11,285 @@ pub fn get_user_input(prompt: &str) -> Result<String> {
     debug!("用户输入: {}", input);
     Ok(input.trim().to_owned())
 }
+
+/// 转义XML文本中的特殊字符, attr 为true时同时转义双引号;
+/// 只输出XML预定义的实体, 在任何DOCTYPE下都是合法的
+pub fn escape_xml(text: &str, attr: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' if attr => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// 整理从页面中提取的链接: 去掉路径中的 "." 和 ".." 段, 查询参数和锚点保持不变;
+/// 带协议的绝对链接和 "//" 开头的链接原样返回, 相对链接开头无法消去的 ".." 保留,
+/// 交给下载时基于页面url解析
+pub fn normalize_url(url: &str) -> String {
+    let url = url.trim();
+    if url.starts_with("//") || url::Url::parse(url).is_ok() {
+        return url.to_owned();
+    }
+    let (path, rest) = url.split_at(url.find(['?', '#']).unwrap_or(url.len()));
+    if path.is_empty() {
+        return url.to_owned();
+    }
+
+    let absolute = path.starts_with('/');
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/').skip(usize::from(absolute)) {
+        trailing_slash = false;
+        match segment {
+            "." => trailing_slash = true,
+            ".." => {
+                match segments.last() {
+                    Some(&last) if last != ".." => {
+                        segments.pop();
+                    }
+                    _ if !absolute => segments.push(".."),
+                    _ => (),
+                }
+                trailing_slash = true;
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut normalized = segments.join("/");
+    if trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+    if absolute {
+        normalized.insert(0, '/');
+    } else if normalized.is_empty() {
+        normalized.push_str("./");
+    }
+    normalized + rest
+}
+
+/// 两侧的空白可能影响显示的行内元素
+const INLINE_ELEMENTS: &[&str] = &[
+    "a", "abbr", "b", "bdi", "bdo", "br", "cite", "code", "del", "dfn", "em", "font", "i", "img",
+    "ins", "kbd", "mark", "q", "rp", "rt", "ruby", "s", "samp", "small", "span", "strong", "sub",
+    "sup", "time", "u", "var", "wbr",
+];
+
+/// 去掉标签之间用于缩进的空白(只包含空白且有换行的文本), 相邻的是行内元素时保留为一个空格;
+/// 其他文本和 <pre> 中的内容保持不变
+pub fn minify_xml(xml: &str) -> String {
+    let tag_name = |tag: &str| -> String {
+        tag.trim_start_matches(['<', '/'])
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == ':' || *c == '-')
+            .collect::<String>()
+            .to_ascii_lowercase()
+    };
+    let is_inline = |name: &str| INLINE_ELEMENTS.contains(&name);
+
+    let mut minified = String::with_capacity(xml.len());
+    let mut rest = xml;
+    let mut prev_tag = String::new();
+    let mut pre_depth = 0usize;
+    while let Some(start) = rest.find('<') {
+        let text = &rest[..start];
+        let tag_end = rest[start..]
+            .find('>')
+            .map_or(rest.len(), |end| start + end + 1);
+        let tag = &rest[start..tag_end];
+        let name = tag_name(tag);
+
+        let indentation = text.contains('\n') && text.trim().is_empty();
+        if pre_depth > 0 || !indentation {
+            minified.push_str(text);
+        } else if is_inline(&prev_tag) || is_inline(&name) {
+            minified.push(' ');
+        }
+        minified.push_str(tag);
+
+        if name == "pre" {
+            if tag.starts_with("</") {
+                pre_depth = pre_depth.saturating_sub(1);
+            } else if !tag.ends_with("/>") {
+                pre_depth += 1;
+            }
+        }
+        prev_tag = name;
+        rest = &rest[tag_end..];
+    }
+    minified.push_str(rest);
+    minified
+}
+
+/// 重新缩进XML: 每个块级元素的标签各占一行, 按层级缩进4个空格;
+/// 行内元素和文本保持在所在的行中, <pre> 中的内容保持不变
+pub fn pretty_xml(xml: &str) -> String {
+    const INDENT: &str = "    ";
+    let xml = minify_xml(xml);
+    let tag_name = |tag: &str| -> String {
+        tag.trim_start_matches(['<', '/'])
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == ':' || *c == '-')
+            .collect::<String>()
+            .to_ascii_lowercase()
+    };
+    let is_inline = |name: &str| INLINE_ELEMENTS.contains(&name);
+
+    let mut pretty = String::with_capacity(xml.len() + xml.len() / 4);
+    let mut rest = xml.as_str();
+    let mut depth = 0usize;
+    let mut pre_depth = 0usize;
+    // 上一个输出的是文本或行内元素, 或者刚输出了开始标签时, 结束标签不换行
+    let mut inline_content = false;
+    let mut just_opened = false;
+    let new_line = |pretty: &mut String, depth: usize| {
+        if !pretty.is_empty() {
+            pretty.push('\n');
+        }
+        pretty.push_str(&INDENT.repeat(depth));
+    };
+    while let Some(start) = rest.find('<') {
+        let text = &rest[..start];
+        let tag_end = rest[start..]
+            .find('>')
+            .map_or(rest.len(), |end| start + end + 1);
+        let tag = &rest[start..tag_end];
+        let name = tag_name(tag);
+        rest = &rest[tag_end..];
+
+        if pre_depth > 0 || !text.trim().is_empty() {
+            pretty.push_str(text);
+            inline_content = true;
+        } else if !text.is_empty() && inline_content && is_inline(&name) {
+            pretty.push_str(text);
+        }
+
+        let closing = tag.starts_with("</");
+        if name == "pre" && closing {
+            pre_depth = pre_depth.saturating_sub(1);
+        }
+        if pre_depth > 0 || is_inline(&name) {
+            pretty.push_str(tag);
+            inline_content = true;
+        } else if closing {
+            depth = depth.saturating_sub(1);
+            if !inline_content && !just_opened {
+                new_line(&mut pretty, depth);
+            }
+            pretty.push_str(tag);
+            inline_content = false;
+        } else {
+            new_line(&mut pretty, depth);
+            pretty.push_str(tag);
+            // 自闭合的标签、XML声明、DOCTYPE和注释没有结束标签
+            let has_end = !tag.ends_with("/>") && !tag.starts_with("<?") && !tag.starts_with("<!");
+            if has_end {
+                depth += 1;
+            }
+            if name == "pre" && has_end {
+                pre_depth += 1;
+            }
+            inline_content = false;
+            just_opened = has_end;
+            continue;
+        }
+        just_opened = false;
+    }
+    pretty.push_str(rest);
+    pretty
+}
+
+/// 从文件头读取图片的宽和高, 支持PNG、JPEG、GIF和WebP; 无法识别时返回None
+pub fn image_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    let u16_be = |i: usize| Some(u16::from_be_bytes(bytes.get(i..i + 2)?.try_into().ok()?) as u32);
+    let u16_le = |i: usize| Some(u16::from_le_bytes(bytes.get(i..i + 2)?.try_into().ok()?) as u32);
+    let u24_le = |i: usize| {
+        let b = bytes.get(i..i + 3)?;
+        Some(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16)
+    };
+
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let width = u32::from_be_bytes(bytes.get(16..20)?.try_into().ok()?);
+        let height = u32::from_be_bytes(bytes.get(20..24)?.try_into().ok()?);
+        return Some((width, height));
+    }
+    if bytes.starts_with(b"GIF8") {
+        return Some((u16_le(6)?, u16_le(8)?));
+    }
+    if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        return match bytes.get(12..16)? {
+            b"VP8 " => Some((u16_le(26)? & 0x3fff, u16_le(28)? & 0x3fff)),
+            b"VP8L" => {
+                let bits = u32::from_le_bytes(bytes.get(21..25)?.try_into().ok()?);
+                Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+            }
+            b"VP8X" => Some((u24_le(24)? + 1, u24_le(27)? + 1)),
+            _ => None,
+        };
+    }
+    if bytes.starts_with(&[0xff, 0xd8]) {
+        // 依次跳过各个段, 直到SOF段
+        let mut i = 2;
+        while *bytes.get(i)? == 0xff {
+            let marker = *bytes.get(i + 1)?;
+            match marker {
+                0xff => i += 1,
+                0x01 | 0xd0..=0xd7 => i += 2,
+                0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => {
+                    return Some((u16_be(i + 7)?, u16_be(i + 5)?));
+                }
+                _ => i += 2 + u16_be(i + 2)? as usize,
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_dot_segments() {
+        assert_eq!(normalize_url("./images/01.jpg"), "images/01.jpg");
+        assert_eq!(normalize_url("a/b/../c.jpg"), "a/c.jpg");
+        assert_eq!(normalize_url("/a/./b/../../c/"), "/c/");
+        assert_eq!(normalize_url("/../a.jpg"), "/a.jpg");
+        assert_eq!(normalize_url("a/.."), "./");
+    }
+
+    #[test]
+    fn normalize_keeps_leading_parent_segments() {
+        assert_eq!(normalize_url("../../img/a.jpg"), "../../img/a.jpg");
+        assert_eq!(normalize_url("../a/../b.jpg"), "../b.jpg");
+    }
+
+    #[test]
+    fn normalize_keeps_absolute_and_protocol_relative_urls() {
+        assert_eq!(
+            normalize_url("https://example.com/a/../b.jpg"),
+            "https://example.com/a/../b.jpg"
+        );
+        assert_eq!(
+            normalize_url("//cdn.example.com/./a.jpg"),
+            "//cdn.example.com/./a.jpg"
+        );
+        assert_eq!(normalize_url("  /a/b.jpg\n"), "/a/b.jpg");
+    }
+
+    #[test]
+    fn normalize_keeps_query_and_fragment() {
+        assert_eq!(
+            normalize_url("./read/../chapter?id=../1#./top"),
+            "chapter?id=../1#./top"
+        );
+        assert_eq!(normalize_url("?page=2"), "?page=2");
+        assert_eq!(normalize_url("#top"), "#top");
+    }
+}